@@ -1,31 +1,55 @@
 use std::{
     collections::HashMap,
-    path::Path,
-    sync::{Arc, Mutex},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}},
 };
 use async_trait::async_trait;
-use teloxide::{net::Download, prelude::*, types::Document};
-use tokio::fs;
+use chrono::NaiveDateTime;
+use teloxide::{net::Download, prelude::*, types::{Document, InlineKeyboardButton, InputFile, InlineKeyboardMarkup, MessageId, ParseMode, User}, utils::html};
+use futures_util::StreamExt;
+use tokio::{fs, io::AsyncWriteExt};
 
 use crate::{
-    categorizer::{self, Categorizer, RepoBasedCategorizer},
+    categorizer::{self, Categorized, CategorySource, Categorizer, ContentCategorizer, RepoBasedCategorizer},
+    classifier::NaiveBayesCategorizer,
     commit_messages::{self, CommitMessageGenerator, WhatTheCommitMessageGenerator},
-    config::{EnvironmentRepositoryFactory, Repository, RepositoryFactory, JsonRepositoryFactory},
-    message_cache::{MessageCache, SyncedInMemoryMessageCache},
-    path_matcher::{self, Matcher},
+    encrypt,
+    lfs,
+    metadata,
+    config::{Author, AuthorMapping, Repository, RepositoryFactory, JsonRepositoryFactory},
+    message_cache::{PendingUpload, PendingUploads},
+    path_matcher::{self, Matcher, MatchError, RuleRegistry},
+    pipeline::{AttachmentKind, Phase, Pipeline, PipelineFile, StageContext, StageRegistry},
+    policy::{self, FilePolicy, PolicyViolation},
     publisher::{self, GitPublisher, Publisher},
     authenticate::Authenticator,
 };
 
 
-pub trait Archivist {
-    fn trigger_upload_document(
-        &self,
-        chat: ChatId,
-        document: &Document,
-        caption: Option<&String>,
-    ) -> ResponseResult<()>;
-}
+/**
+ * Prefix of the callback data of the removal confirmation
+ */
+pub const REMOVE_CALLBACK: &str = "rm:";
+
+/**
+ * Text of the removal confirmation, followed by the path of the file
+ */
+pub const REMOVE_CONFIRMATION: &str = "Delete file ";
+
+/**
+ * Prefix of the callback data of the category picker
+ */
+pub const CATEGORY_CALLBACK: &str = "cat:";
+
+/**
+ * Text of the category picker
+ */
+pub const CATEGORY_QUESTION: &str = "No category matches, where should the file be stored?";
+
+/**
+ * Line of the category picker that shows the caption of the document
+ */
+pub const CAPTION_PREFIX: &str = "Caption: ";
 
 pub struct ArchivistImpl<
     T: RepositoryFactory,
@@ -37,28 +61,34 @@ pub struct ArchivistImpl<
     pub repos: T,
     pub publisher: P,
     pub categorizer: C,
-    pub matcher: path_matcher::Matcher<
-        path_matcher::AddRule<path_matcher::LatestRule<path_matcher::DefaultRule>>,
-    >,
+    pub rules: RuleRegistry,
+    pub stages: StageRegistry,
     pub message_generator: M,
     pub authenticator: Authenticator,
+    pub authors: AuthorMapping,
+    pub uploads: PendingUploads,
 }
 
 pub type BilloArchivist = ArchivistImpl<
     JsonRepositoryFactory,
     GitPublisher,
-    RepoBasedCategorizer,
+    ContentCategorizer<NaiveBayesCategorizer<RepoBasedCategorizer>>,
     WhatTheCommitMessageGenerator,
 >;
 
 impl BilloArchivist {
-    pub fn new(bot: Bot) -> BilloArchivist {
+    pub fn new(bot: Bot, uploads: PendingUploads) -> BilloArchivist {
         // let secret = std::env::var("SECRET").unwrap_or("".to_string());
         // let path = std::env::var("GIT_REPO").unwrap_or(".".to_string());
         let path = std::env::var("GIT_REPO_CONFIG").unwrap_or(".".to_string());
         let name = std::env::var("GIT_NAME").unwrap_or("archiver".to_string());
         let email = std::env::var("GIT_EMAIL").unwrap_or("archiver@mail.com".to_string());
         let ssh_key = std::env::var("SSH_KEY").unwrap_or("".to_string());
+        let authors_path = std::env::var("GIT_AUTHORS").unwrap_or("authors.json".to_string());
+        let state_dir = std::env::var("STATE_DIR").unwrap_or("state".to_string());
+        let threshold = std::env::var("CLASSIFIER_THRESHOLD").ok()
+            .and_then(|threshold| threshold.parse().ok())
+            .unwrap_or(0.6);
 
         log::info!("Starting with...");
         // log::info!("SECRET:    {}", secret);
@@ -67,6 +97,9 @@ impl BilloArchivist {
         log::info!("GIT_NAME:  {}", name);
         log::info!("GIT_EMAIL: {}", email);
         log::info!("SSH_KEY:   {}", ssh_key);
+        log::info!("GIT_AUTHORS: {}", authors_path);
+        log::info!("STATE_DIR: {}", state_dir);
+        log::info!("CLASSIFIER_THRESHOLD: {}", threshold);
 
 
         let repos = JsonRepositoryFactory::new(&path, &name, &email);
@@ -78,10 +111,28 @@ impl BilloArchivist {
             bot,
             repos,
             publisher,
-            matcher: Matcher::new(),
-            categorizer: RepoBasedCategorizer::new(),
+            rules: RuleRegistry::with_defaults(),
+            stages: StageRegistry::with_defaults(),
+            categorizer: ContentCategorizer::new(NaiveBayesCategorizer::new(RepoBasedCategorizer::new(), &state_dir, threshold)),
             message_generator: WhatTheCommitMessageGenerator::new(),
             authenticator: Authenticator::new(),
+            authors: AuthorMapping::load(&authors_path).unwrap_or_else(|error| {
+                log::error!("Unable to parse author mapping {}: {}", authors_path, error);
+                AuthorMapping::default()
+            }),
+            uploads,
+        }
+    }
+
+    /**
+     * Trains the classifiers of all configured repositories from scratch
+     */
+    pub fn train_classifiers(&self) {
+        for repo in self.repos.get_repositories() {
+            let classifier = self.categorizer.fallback();
+            if let Err(error) = classifier.train(categorizer::CategorizationContext::new(repo, 0)) {
+                log::error!("[repo: {}] Training failed: {}", repo.path(), error);
+            }
         }
     }
 }
@@ -93,16 +144,13 @@ impl<
         C: Categorizer,
         M: CommitMessageGenerator,
     >  ArchivistImpl<T, P, C, M>{
-        pub async fn upload_document(
-            &self,
-            chat: ChatId,
-            document: &Document,
-            caption: Option<&String>,
-        ) -> ResponseResult<()> {
-            
+        /**
+         * Returns the repository the chat is authenticated for, informs the chat otherwise
+         */
+        async fn get_repository(&self, chat: ChatId) -> ResponseResult<Option<&Repository>> {
             let passed_secret = self.authenticator.get_auth(&self.bot, &chat).await?;
             if passed_secret.is_none() {
-                return Ok(());
+                return Ok(None);
             }
             let repo = self.repos.get_repository(&passed_secret.unwrap());
     
@@ -112,16 +160,15 @@ impl<
                     .await?;
     
                 log::info!("[chat: {}] Incorrect authentication token", chat);
-    
-                return Ok(());
             }
-    
-            // Write file to disk
-            let file_meta = document.file.clone();
-            let file = self.bot.get_file(file_meta.id).await?;
-    
-            // Pull changes upfront
-            let pull_result = self.publisher.update_files(repo.unwrap());
+            Ok(repo)
+        }
+
+        /**
+         * Pulls the latest changes, returns false and informs the chat if that failed
+         */
+        async fn update_repository(&self, chat: ChatId, repo: &Repository) -> ResponseResult<bool> {
+            let pull_result = self.publisher.update_files(repo);
             if pull_result.is_err() {
                 let error = pull_result.err().unwrap();
                 self.bot
@@ -132,54 +179,468 @@ impl<
                     chat,
                     &error                    
                 );
+                return Ok(false);
+            }
+            Ok(true)
+        }
+
+        /**
+         * Builds the matcher for the rules of the repository, returns None and informs the chat if that failed
+         */
+        async fn get_matcher(&self, chat: ChatId, repo: &Repository) -> ResponseResult<Option<Matcher>> {
+            match self.rules.matcher(&repo.settings().rules) {
+                Ok(matcher) => Ok(Some(matcher)),
+                Err(error) => {
+                    log::error!("[chat: {}] Invalid path rules: {}", chat, &error);
+                    self.bot
+                        .send_message(chat, format!("Invalid path rule configuration: {}", &error))
+                        .await?;
+                    Ok(None)
+                }
+            }
+        }
+
+        /**
+         * Resolves the path matcher for a file with the date and name, returns None and informs the chat if that failed
+         */
+        async fn resolve_path(&self, chat: ChatId, repo: &Repository, path_matcher: String, date: Option<NaiveDateTime>, name: &str) -> ResponseResult<Option<String>> {
+            let extension = Path::new(name).extension().and_then(|extension| extension.to_str());
+            let matcher = match self.get_matcher(chat, repo).await? {
+                Some(matcher) => matcher.with_date(date).with_extension(extension),
+                None => return Ok(None),
+            };
+            match matcher.resolve(repo, path_matcher) {
+                Ok(target) => Ok(Some(target)),
+                Err(error) => {
+                    self.report_match_error(chat, &error).await?;
+                    Ok(None)
+                }
+            }
+        }
+
+        /**
+         * Validates a path given by the user, returns None and informs the chat if it is not allowed
+         */
+        async fn validate_path(&self, chat: ChatId, repo: &Repository, path: &str) -> ResponseResult<Option<String>> {
+            match path_matcher::validate_path(repo, path) {
+                Ok(path) => Ok(Some(path)),
+                Err(error) => {
+                    self.report_match_error(chat, &error).await?;
+                    Ok(None)
+                }
+            }
+        }
+
+        async fn report_match_error(&self, chat: ChatId, error: &MatchError) -> ResponseResult<()> {
+            log::error!("[chat: {}] Could not resolve {}", chat, error);
+            self.bot
+                .send_message(
+                    chat,
+                    format!("Could not resolve path {}: {}", error.highlighted(), html::escape(&error.error.to_string())),
+                )
+                .parse_mode(ParseMode::Html)
+                .await?;
+            Ok(())
+        }
+
+        fn get_author(&self, repo: &Repository, sender: Option<&User>) -> Author {
+            match sender {
+                Some(user) => self.authors.get_author(user.id.0, &user.full_name()),
+                None => repo.author(),
+            }
+        }
+
+        pub async fn upload_document(
+            &self,
+            chat: ChatId,
+            message_id: MessageId,
+            document: &Document,
+            caption: Option<&String>,
+            sender: Option<&User>,
+        ) -> ResponseResult<()> {
+            
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
                 return Ok(());
             }
     
+            // Pull changes upfront
+            if !self.update_repository(chat, repo.unwrap()).await? {
+                return Ok(());
+            }
+    
+            let pipeline = match self.get_pipeline(chat, repo.unwrap()).await? {
+                Some(pipeline) => pipeline,
+                None => return Ok(()),
+            };
+            let staged = match self.stage_document(chat, repo.unwrap(), &pipeline, document, caption.map(|caption| caption.as_str())).await? {
+                Some(staged) => staged,
+                None => return Ok(()),
+            };
+            let matching_template = self.categorizer.categorize(
+                caption.map(|caption| caption.as_str()),
+                categorizer::CategorizationContext::new(repo.unwrap(), chat.0).with_file(&staged.file).with_name(&staged.name),
+            );
+            let categories = self.categorizer.categories(categorizer::CategorizationContext::new(repo.unwrap(), chat.0));
+            let unmatched = matches!(matching_template.source, CategorySource::Verbatim | CategorySource::Default);
+            if unmatched && !categories.is_empty() {
+                // Let the user pick the category instead of guessing, the staged file waits for the pick
+                self.ask_category(chat, message_id, caption, &categories, staged).await?;
+                return Ok(());
+            }
+
+            self.store_document(chat, repo.unwrap(), &pipeline, staged, matching_template, sender).await
+        }
+
+        /**
+         * Builds the pipeline for the stages of the repository, returns None and informs the chat if that failed
+         */
+        async fn get_pipeline(&self, chat: ChatId, repo: &Repository) -> ResponseResult<Option<Arc<Pipeline>>> {
+            match self.stages.pipeline(&repo.settings().stages) {
+                Ok(pipeline) => Ok(Some(Arc::new(pipeline))),
+                Err(error) => {
+                    log::error!("[chat: {}] Invalid pipeline stages: {}", chat, &error);
+                    self.bot
+                        .send_message(chat, format!("Invalid pipeline configuration: {}", &error))
+                        .await?;
+                    Ok(None)
+                }
+            }
+        }
+
+        /**
+         * Runs the stages of the phase, returns false and informs the chat if the file was rejected
+         */
+        async fn run_pipeline(
+            &self,
+            chat: ChatId,
+            repo: &Repository,
+            pipeline: &Arc<Pipeline>,
+            phase: Phase,
+            staged: &mut PipelineFile,
+        ) -> ResponseResult<bool> {
+            // Stages call external tools and block, the file is moved to a blocking thread and back
+            let (pipeline, stage_repo, mut file) = (pipeline.clone(), repo.clone(), std::mem::take(staged));
+            let task = tokio::task::spawn_blocking(move || {
+                let stored = pipeline.run(phase, &mut file, &StageContext::new(&stage_repo, chat.0));
+                (file, stored)
+            });
+            let (file, stored) = task.await.map_err(|error| std::io::Error::other(error.to_string()))?;
+            *staged = file;
+            if stored {
+                return Ok(true);
+            }
+            log::info!("[chat: {}] Rejected file {}", chat, staged.name);
+            self.bot
+                .send_message(chat, format!("File {} was not stored\n{}", staged.name, staged.report().join("\n")))
+                .await?;
+            Ok(false)
+        }
+
+        /**
+         * Downloads the document to a temporary location and runs the stages of the downloaded phase,
+         * so it can be inspected before it is stored. The download is aborted and None returned
+         * if the file violates the policy of the repository.
+         */
+        async fn stage_document(
+            &self,
+            chat: ChatId,
+            repo: &Repository,
+            pipeline: &Arc<Pipeline>,
+            document: &Document,
+            caption: Option<&str>,
+        ) -> ResponseResult<Option<PipelineFile>> {
+            let policy = &repo.settings().policy;
+            let file_meta = document.file.clone();
+            if let Err(violation) = policy.check_size(file_meta.size as u64) {
+                self.reject_document(chat, &violation).await?;
+                return Ok(None);
+            }
+            let file = self.bot.get_file(file_meta.id).await?;
+
+            let extension = document.file_name.as_deref()
+                .and_then(|name| Path::new(name).extension())
+                .and_then(|extension| extension.to_str())
+                .map(|extension| format!(".{}", extension))
+                .unwrap_or_default();
+            let staged = staging_path(chat, &format!("{}{}", file_meta.unique_id, extension)).await?;
+            let name = document.file_name.clone().unwrap_or(format!("{}{}", file_meta.unique_id, extension));
+            let mut staged = PipelineFile::new(&staged, &name, caption);
+
+            let violation = self.download_checked(&file.path, &staged.file, policy, document.file_name.as_deref()).await?;
+            if let Some(violation) = violation {
+                staged.remove();
+                self.reject_document(chat, &violation).await?;
+                return Ok(None);
+            }
+            log::info!("[chat: {}] Downloaded file {:?} to {:?}", chat, file.path, staged.file);
+            if !self.run_pipeline(chat, repo, pipeline, Phase::Downloaded, &mut staged).await? {
+                staged.remove();
+                return Ok(None);
+            }
+            Ok(Some(staged))
+        }
+
+        /**
+         * Streams the file to `staged` and stops as soon as its type, detected from the first
+         * bytes, or its size violates the policy
+         */
+        async fn download_checked(
+            &self,
+            path: &str,
+            staged: &Path,
+            policy: &FilePolicy,
+            file_name: Option<&str>,
+        ) -> ResponseResult<Option<PolicyViolation>> {
+            let mut dst = fs::File::create(staged).await?;
+            let mut stream = self.bot.download_file_stream(path);
+            let mut header: Vec<u8> = Vec::new();
+            let mut checked_type = false;
+            let mut size: u64 = 0;
+            while let Some(chunk) = stream.next().await {
+                let chunk = chunk?;
+                size += chunk.len() as u64;
+                if let Err(violation) = policy.check_size(size) {
+                    return Ok(Some(violation));
+                }
+                if !checked_type {
+                    header.extend_from_slice(&chunk[..chunk.len().min(policy::HEADER_SIZE - header.len())]);
+                    if header.len() == policy::HEADER_SIZE {
+                        checked_type = true;
+                        if let Err(violation) = policy.check_type(&policy::detect(&header, file_name)) {
+                            return Ok(Some(violation));
+                        }
+                    }
+                }
+                dst.write_all(&chunk).await?;
+            }
+            dst.flush().await?;
+            if !checked_type {
+                if let Err(violation) = policy.check_type(&policy::detect(&header, file_name)) {
+                    return Ok(Some(violation));
+                }
+            }
+            Ok(None)
+        }
+
+        async fn reject_document(&self, chat: ChatId, violation: &PolicyViolation) -> ResponseResult<()> {
+            log::info!("[chat: {}] Rejected file: {}", chat, violation);
+            self.bot.send_message(chat, format!("File rejected: {}", violation)).await?;
+            Ok(())
+        }
+
+        async fn ask_category(
+            &self,
+            chat: ChatId,
+            message_id: MessageId,
+            caption: Option<&String>,
+            categories: &[Categorized],
+            staged: PipelineFile,
+        ) -> ResponseResult<()> {
+            let mut buttons: Vec<Vec<InlineKeyboardButton>> = categories.iter()
+                .enumerate()
+                .map(|(i, category)| {
+                    let label = match &category.source {
+                        CategorySource::Category(tags) => tags.join(" "),
+                        _ => category.path_matcher.clone(),
+                    };
+                    vec![InlineKeyboardButton::callback(label, format!("{}{}", CATEGORY_CALLBACK, i))]
+                })
+                .collect();
+            let mut last_row = Vec::new();
+            if caption.is_some() {
+                last_row.push(InlineKeyboardButton::callback("Use as path", format!("{}path", CATEGORY_CALLBACK)));
+            }
+            last_row.push(InlineKeyboardButton::callback("Cancel", format!("{}cancel", CATEGORY_CALLBACK)));
+            buttons.push(last_row);
+
+            let mut question = CATEGORY_QUESTION.to_string();
+            if let Some(caption) = caption {
+                question = format!("{}\n{}{}", question, CAPTION_PREFIX, caption);
+            }
+            log::info!("[chat: {}] Asking for category of message {}", chat, message_id);
+            let picker = self.bot
+                .send_message(chat, question)
+                .reply_to_message_id(message_id)
+                .reply_markup(InlineKeyboardMarkup::new(buttons))
+                .await;
+            let picker = match picker {
+                Ok(picker) => picker,
+                Err(error) => {
+                    staged.remove();
+                    return Err(error);
+                }
+            };
+            // The whole caption and the offered categories are kept, the picker only shows them
+            let upload = PendingUpload { caption: caption.cloned(), categories: categories.to_vec(), staged };
+            self.uploads.put(chat, picker.id, upload);
+            Ok(())
+        }
+
+        /**
+         * Uploads a document to the category the user picked in the picker message, `choice` is
+         * the index of the offered category, `path` to use the caption as path or `cancel`
+         */
+        pub async fn upload_picked_document(
+            &self,
+            chat: ChatId,
+            picker: MessageId,
+            choice: &str,
+            sender: Option<&User>,
+        ) -> ResponseResult<()> {
+            let pending = match self.uploads.pop(chat, picker) {
+                Some(pending) => pending,
+                None => {
+                    self.bot.send_message(chat, "Upload expired, please upload again").await?;
+                    return Ok(());
+                }
+            };
+            if choice == "cancel" {
+                pending.staged.remove();
+                self.bot.send_message(chat, "Upload cancelled").await?;
+                return Ok(());
+            }
+            let caption = pending.caption.as_deref();
+
+            let repo = match self.get_repository(chat).await? {
+                Some(repo) => repo,
+                None => {
+                    pending.staged.remove();
+                    return Ok(());
+                }
+            };
+
+            if !self.update_repository(chat, repo).await? {
+                pending.staged.remove();
+                return Ok(());
+            }
+
+            let categorized = match (choice, caption) {
+                ("path", Some(caption)) => Some(Categorized::new(caption.to_string(), CategorySource::Verbatim)),
+                _ => choice.parse::<usize>().ok().and_then(|index| pending.categories.get(index).cloned()),
+            };
+            match categorized {
+                Some(categorized) => {
+                    let pipeline = match self.get_pipeline(chat, repo).await? {
+                        Some(pipeline) => pipeline,
+                        None => {
+                            pending.staged.remove();
+                            return Ok(());
+                        }
+                    };
+                    self.store_document(chat, repo, &pipeline, pending.staged, categorized, sender).await
+                }
+                None => {
+                    pending.staged.remove();
+                    self.bot.send_message(chat, "Unknown category, please upload again").await?;
+                    Ok(())
+                }
+            }
+        }
+
+        /**
+         * Moves the staged file to the location of the categorization and commits it
+         */
+        async fn store_document(
+            &self,
+            chat: ChatId,
+            repo: &Repository,
+            pipeline: &Arc<Pipeline>,
+            mut staged: PipelineFile,
+            matching_template: Categorized,
+            sender: Option<&User>,
+        ) -> ResponseResult<()> {
+            let result = self.store_staged(chat, repo, pipeline, &mut staged, matching_template, sender).await;
+            staged.remove();
+            result
+        }
+
+        async fn store_staged(
+            &self,
+            chat: ChatId,
+            repo: &Repository,
+            pipeline: &Arc<Pipeline>,
+            staged: &mut PipelineFile,
+            matching_template: Categorized,
+            sender: Option<&User>,
+        ) -> ResponseResult<()> {
             log::info!(
                 "[chat: {}] Pushing file {:?} to repo at {}",
                 chat,
-                file,
-                repo.unwrap().path()
+                staged.file,
+                repo.path()
             );
-            let dest = Path::new(repo.unwrap().path());
-            let matching_template;
-            if caption.is_some() {
-                matching_template = self.categorizer.categorize(
-                    Some(caption.unwrap().as_str()),
-                    categorizer::CategorizationContext::new(repo.unwrap(), chat.0),
-                );
-            } else {
-                matching_template = self.categorizer.categorize(
-                    None,
-                    categorizer::CategorizationContext::new(repo.unwrap(), chat.0),
-                );
+            let dest = Path::new(repo.path());
+            let target = match self.resolve_path(chat, repo, matching_template.path_matcher.clone(), staged.captured, &staged.name).await? {
+                Some(target) => target,
+                None => return Ok(()),
+            };
+            staged.target = PathBuf::from(&target);
+            staged.category = Some(matching_template.clone());
+            if !self.run_pipeline(chat, repo, pipeline, Phase::Resolved, staged).await? {
+                return Ok(());
             }
-            let target = self.matcher.resolve(&repo.unwrap(), matching_template);
-            let rel_path = Path::new(&target);
-            let path = dest.join(rel_path.clone());
-            if path.parent().is_some() && !path.parent().unwrap().exists() {
-                fs::create_dir_all(path.parent().unwrap()).await?;
-                log::info!(
-                    "[chat: {}] Created directory {:?}",
-                    chat,
-                    path.parent().unwrap()
-                );
+
+            let target = staged.target.to_string_lossy().to_string();
+            let mut files = vec![(staged.file.clone(), staged.target.clone())];
+            files.extend(staged.attachments.iter().map(|attachment| (attachment.source.clone(), attachment.target.clone())));
+            // Stages may have changed the targets after they were resolved
+            for (_, rel_path) in &files {
+                if self.validate_path(chat, repo, &rel_path.to_string_lossy()).await?.is_none() {
+                    return Ok(());
+                }
+            }
+            // Uploads before anything is written to the working tree, a rejected file leaves no trace there
+            let (lfs_repo, lfs_files) = (repo.clone(), files.clone());
+            let uploaded = tokio::task::spawn_blocking(move || lfs::upload_tracked(&lfs_repo, &lfs_files))
+                .await
+                .map_err(|error| git2::Error::from_str(&error.to_string()))
+                .and_then(|uploaded| uploaded);
+            if let Err(error) = uploaded {
+                log::error!("[chat: {}] LFS upload failed: {}", chat, error);
+                self.bot.send_message(chat, format!("Error during upload: {}", error)).await?;
+                return Ok(());
+            }
+            for (source, rel_path) in &files {
+                let path = dest.join(rel_path);
+                if path.parent().is_some() && !path.parent().unwrap().exists() {
+                    fs::create_dir_all(path.parent().unwrap()).await?;
+                    log::info!(
+                        "[chat: {}] Created directory {:?}",
+                        chat,
+                        path.parent().unwrap()
+                    );
+                }
+                fs::copy(source, &path).await?;
+                log::info!("[chat: {}] Created file at {:?}", chat, rel_path);
+            }
+            let added_files: Vec<&Path> = files.iter().map(|(_, rel_path)| rel_path.as_path()).collect();
+
+            let mut stored = match &matching_template.source {
+                source @ (CategorySource::Content(_) | CategorySource::Suggested(_)) =>
+                    format!("File stored at {}\nCategory: {}", target, source),
+                _ => format!("File stored at {}", target),
+            };
+            for original in staged.attachments.iter().filter(|attachment| attachment.kind == AttachmentKind::Original) {
+                stored = format!("{}\nOriginal stored at {}", stored, original.target.display());
+            }
+            for outcome in staged.report() {
+                stored = format!("{}\n{}", stored, outcome);
             }
-            let mut dst = fs::File::create(path).await?;
-            log::info!("[chat: {}] Created file at {:?}", chat, target);
-    
-            let downloaded = self.bot.download_file(&file.path, &mut dst).await?;
-            log::info!("[chat: {}] Downloaded file {:?}", chat, downloaded);
             self.bot
-                .send_message(chat, format!("File stored at {}", target.to_string()))
+                .send_message(chat, stored)
                 .await?;
     
-            let commit_msg = self.message_generator.generate().await;
+            let author = self.get_author(repo, sender);
+            let commit_msg = commit_messages::with_chat_trailer(&self.message_generator.generate().await, chat.0);
             let commit = self
                 .publisher
-                .publish_file(repo.unwrap(), rel_path, &commit_msg);
+                .publish_files(repo, &added_files, &author, &commit_msg);
             log::info!("[chat: {}] Committed file {:?}", chat, commit);
             if commit.is_ok() {
+                if !staged.encrypted {
+                    self.categorizer.learn(&target, categorizer::CategorizationContext::new(repo, chat.0).with_file(&staged.file));
+                }
                 self.bot
                     .send_message(chat, format!("Commit: {}", commit.unwrap()))
                     .await?;
@@ -194,22 +655,388 @@ impl<
     
             Ok(())
         }
-    }
 
-impl<
-        T: RepositoryFactory,
-        P: Publisher,
-        C: Categorizer,
-        M: CommitMessageGenerator,
-    > Archivist for ArchivistImpl<T, P, C, M>
-{
-    fn trigger_upload_document(
-        &self,
-        chat: ChatId,
-        document: &Document,
-        caption: Option<&String>,
-    ) -> ResponseResult<()> {
-        self.upload_document(chat, document, caption);
-        Ok(())
+        /**
+         * Sends a stored file to admins of the repository, encrypted files are decrypted with the key of the bot.
+         * Members of the chat may only add files, they must not read the files of other members.
+         */
+        pub async fn get_document(&self, chat: ChatId, path: &str, sender: Option<&User>) -> ResponseResult<()> {
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
+                return Ok(());
+            }
+            let repo = repo.unwrap();
+
+            if !self.check_admin(chat, repo, sender).await? {
+                return Ok(());
+            }
+
+            if !self.update_repository(chat, repo).await? {
+                return Ok(());
+            }
+            let path = match path_matcher::validate_readable_path(repo, path) {
+                Ok(path) => path,
+                Err(error) => {
+                    self.report_match_error(chat, &error).await?;
+                    return Ok(());
+                }
+            };
+            let file = Path::new(repo.path()).join(&path);
+            if !file.is_file() {
+                self.bot.send_message(chat, format!("File {} does not exist", path)).await?;
+                return Ok(());
+            }
+            // Checkouts of git2 do not download the content of LFS files
+            if lfs::is_pointer(&file) {
+                self.bot.send_message(chat, format!("File {} is stored in LFS and was not downloaded", path)).await?;
+                return Ok(());
+            }
+            let decrypted = match encrypt::decrypted_path(Path::new(&path)) {
+                Some(decrypted) => decrypted,
+                None => {
+                    self.bot.send_document(chat, InputFile::file(file)).await?;
+                    return Ok(());
+                }
+            };
+
+            let name = decrypted.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let target = staging_path(chat, &format!("decrypted-{}", name)).await?;
+            let (source, decrypted_file) = (file.clone(), target.clone());
+            let result = tokio::task::spawn_blocking(move || encrypt::decrypt(&source, &decrypted_file))
+                .await
+                .map_err(|error| std::io::Error::other(error.to_string()))
+                .and_then(|decrypted| decrypted);
+            let sent = match result {
+                Ok(()) => {
+                    log::info!("[chat: {}] Sending decrypted {}", chat, path);
+                    self.bot.send_document(chat, InputFile::file(&target).file_name(name)).await.map(|_| ())
+                }
+                Err(error) => {
+                    log::error!("[chat: {}] Decryption of {} failed: {}", chat, path, error);
+                    self.bot.send_message(chat, format!("Could not decrypt {}: {}", path, error)).await.map(|_| ())
+                }
+            };
+            if target.exists() {
+                fs::remove_file(&target).await?;
+            }
+            sent
+        }
+
+        /**
+         * Replies where a file with the caption would be stored without storing or pulling anything.
+         * Renames of the stages, e.g. by conversion or encryption, are included.
+         */
+        pub async fn where_document(&self, chat: ChatId, caption: Option<&str>) -> ResponseResult<()> {
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
+                return Ok(());
+            }
+            let repo = repo.unwrap();
+
+            let matcher = match self.get_matcher(chat, repo).await? {
+                Some(matcher) => matcher,
+                None => return Ok(()),
+            };
+            let pipeline = match self.get_pipeline(chat, repo).await? {
+                Some(pipeline) => pipeline,
+                None => return Ok(()),
+            };
+
+            let categorized = self.categorizer.categorize(
+                caption,
+                categorizer::CategorizationContext::new(repo, chat.0),
+            );
+            let resolutions = match matcher.explain(repo, categorized.path_matcher.clone()) {
+                Ok(resolutions) => resolutions,
+                Err(error) => {
+                    self.report_match_error(chat, &error).await?;
+                    return Ok(());
+                }
+            };
+            let segments: Vec<String> = resolutions.iter()
+                .map(|resolution| format!("{} → {} ({})", resolution.segment, resolution.resolved, resolution.rule))
+                .collect();
+            let target: PathBuf = resolutions.iter().map(|resolution| resolution.resolved.as_str()).collect();
+            let target = pipeline.preview(target, &categorized, &StageContext::new(repo, chat.0));
+            self.bot
+                .send_message(
+                    chat,
+                    format!(
+                        "File would be stored at {}\nMatched: {}\nPath matcher: {}\n{}",
+                        target.display(), categorized.source, categorized.path_matcher, segments.join("\n")
+                    ),
+                )
+                .await?;
+            Ok(())
+        }
+
+        /**
+         * Moves an archived file to the location the categorization resolves to
+         */
+        pub async fn move_document(
+            &self,
+            chat: ChatId,
+            old_path: &str,
+            categorization: &str,
+            sender: Option<&User>,
+        ) -> ResponseResult<()> {
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
+                return Ok(());
+            }
+            let repo = repo.unwrap();
+
+            if !self.update_repository(chat, repo).await? {
+                return Ok(());
+            }
+
+            let old_path = match self.validate_path(chat, repo, old_path).await? {
+                Some(old_path) => old_path,
+                None => return Ok(()),
+            };
+            if !Path::new(repo.path()).join(&old_path).is_file() {
+                self.bot
+                    .send_message(chat, format!("File {} not found", old_path))
+                    .await?;
+                return Ok(());
+            }
+
+            let matching_template = self.categorizer.categorize(
+                Some(categorization),
+                categorizer::CategorizationContext::new(repo, chat.0),
+            );
+            // Encrypted files are matched by the name of their content
+            let name = encrypt::decrypted_path(Path::new(&old_path))
+                .map(|decrypted| decrypted.to_string_lossy().to_string())
+                .unwrap_or_else(|| old_path.clone());
+            let target = match self.resolve_path(chat, repo, matching_template.path_matcher.clone(), None, &name).await? {
+                Some(target) => target,
+                None => return Ok(()),
+            };
+            // Files are not encrypted or decrypted on a move, the category has to store them as they are
+            let encryption = categorizer::encryption_for(repo, &matching_template.path_matcher)
+                .or_else(|| categorizer::encryption_for_target(repo, Path::new(&target)));
+            let target = match (encrypt::EncryptionFormat::of(Path::new(&old_path)), encryption) {
+                (None, None) => target,
+                (Some(format), Some(encryption)) if format == encryption.format => {
+                    let encrypted = encrypt::encrypted_path(Path::new(&target), format).to_string_lossy().to_string();
+                    match self.validate_path(chat, repo, &encrypted).await? {
+                        Some(encrypted) => encrypted,
+                        None => return Ok(()),
+                    }
+                }
+                (format, encryption) => {
+                    let reason = match (format, encryption) {
+                        (None, _) => "the target category is encrypted".to_string(),
+                        (Some(_), None) => "the file is encrypted and the target category is not".to_string(),
+                        (Some(format), Some(encryption)) => format!(
+                            "the file is encrypted with {} and the target category with {}",
+                            format.extension(), encryption.format.extension()
+                        ),
+                    };
+                    log::info!("[chat: {}] Refused to move {} to {}: {}", chat, old_path, target, reason);
+                    self.bot
+                        .send_message(chat, format!("Can not move {}, {}. Upload the file to the category again instead.", old_path, reason))
+                        .await?;
+                    return Ok(());
+                }
+            };
+            log::info!("[chat: {}] Moving {} to {}", chat, old_path, target);
+
+            let author = self.get_author(repo, sender);
+            let commit_msg = commit_messages::with_chat_trailer(&self.message_generator.generate().await, chat.0);
+            match self.publisher.move_file(repo, Path::new(&old_path), Path::new(&target), &author, &commit_msg) {
+                Ok(commit) => {
+                    log::info!("[chat: {}] Committed move {}", chat, commit);
+                    let moved = Path::new(repo.path()).join(&target);
+                    let context = categorizer::CategorizationContext::new(repo, chat.0).with_file(&moved);
+                    self.categorizer.forget(&old_path, context);
+                    self.categorizer.learn(&target, context);
+                    self.bot
+                        .send_message(
+                            chat,
+                            format!("File moved from {}\nFile stored at {}\nCommit: {}", old_path, target, commit),
+                        )
+                        .await?;
+                }
+                Err(error) => {
+                    log::error!("[chat: {}] Move failed: {}", chat, &error);
+                    self.bot
+                        .send_message(chat, format!("Error during move: {}", &error))
+                        .await?;
+                }
+            }
+            Ok(())
+        }
+
+        /**
+         * Asks an admin of the repository to confirm the removal of a file
+         */
+        pub async fn request_removal(&self, chat: ChatId, path: &str, sender: Option<&User>) -> ResponseResult<()> {
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
+                return Ok(());
+            }
+            let repo = repo.unwrap();
+
+            if !self.check_admin(chat, repo, sender).await? {
+                return Ok(());
+            }
+            if !self.update_repository(chat, repo).await? {
+                return Ok(());
+            }
+            let path = match self.validate_path(chat, repo, path).await? {
+                Some(path) => path,
+                None => return Ok(()),
+            };
+            if !Path::new(repo.path()).join(&path).is_file() {
+                self.bot
+                    .send_message(chat, format!("File {} not found", path))
+                    .await?;
+                return Ok(());
+            }
+
+            let keyboard = InlineKeyboardMarkup::new(vec![vec![
+                InlineKeyboardButton::callback("Delete", format!("{}yes", REMOVE_CALLBACK)),
+                InlineKeyboardButton::callback("Cancel", format!("{}no", REMOVE_CALLBACK)),
+            ]]);
+            self.bot
+                .send_message(chat, format!("{}{}", REMOVE_CONFIRMATION, path))
+                .reply_markup(keyboard)
+                .await?;
+            Ok(())
+        }
+
+        /**
+         * Removes a file after the removal was confirmed
+         */
+        pub async fn remove_document(&self, chat: ChatId, path: &str, sender: Option<&User>) -> ResponseResult<()> {
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
+                return Ok(());
+            }
+            let repo = repo.unwrap();
+
+            if !self.check_admin(chat, repo, sender).await? {
+                return Ok(());
+            }
+
+            let path = match self.validate_path(chat, repo, path).await? {
+                Some(path) => path,
+                None => return Ok(()),
+            };
+
+            // The content is needed to forget the file, it is learned again if the removal fails
+            let file = Path::new(repo.path()).join(&path);
+            let context = categorizer::CategorizationContext::new(repo, chat.0).with_file(&file);
+            self.categorizer.forget(&path, context);
+
+            let author = self.get_author(repo, sender);
+            let commit_msg = commit_messages::with_chat_trailer(&self.message_generator.generate().await, chat.0);
+            match self.publisher.remove_file(repo, Path::new(&path), &author, &commit_msg) {
+                Ok(commit) => {
+                    log::info!("[chat: {}] Removed {} in {}", chat, path, commit);
+                    self.bot
+                        .send_message(chat, format!("File {} removed\nCommit: {}", path, commit))
+                        .await?;
+                }
+                Err(error) => {
+                    log::error!("[chat: {}] Removal failed: {}", chat, &error);
+                    self.categorizer.learn(&path, context);
+                    self.bot
+                        .send_message(chat, format!("Error during removal: {}", &error))
+                        .await?;
+                }
+            }
+            Ok(())
+        }
+
+        /**
+         * Forgets the files the revert removed and learns the ones it restored again
+         */
+        async fn learn_revert(&self, chat: ChatId, repo: &Repository, revert: &publisher::Revert) {
+            let context = categorizer::CategorizationContext::new(repo, chat.0);
+            for path in revert.removed.iter().filter(|path| !metadata::is_sidecar(path)) {
+                // The removed file is gone from the working tree, its content is taken from the reverted commit
+                let name = Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                let content = self.publisher.read_file(repo, revert.reverted, Path::new(path));
+                let staged = match content {
+                    Ok(content) => match staging_path(chat, &name).await {
+                        Ok(staged) => fs::write(&staged, content).await.map(|_| staged),
+                        Err(error) => Err(error),
+                    },
+                    Err(error) => Err(std::io::Error::other(error.to_string())),
+                };
+                match staged {
+                    Ok(staged) => {
+                        self.categorizer.forget(path, context.with_file(&staged));
+                        if let Err(error) = fs::remove_file(&staged).await {
+                            log::warn!("[chat: {}] Could not remove {}: {}", chat, staged.display(), error);
+                        }
+                    }
+                    Err(error) => log::error!("[chat: {}] Could not forget {}: {}", chat, path, error),
+                }
+            }
+            for path in revert.restored.iter().filter(|path| !metadata::is_sidecar(path)) {
+                let file = Path::new(repo.path()).join(path);
+                self.categorizer.learn(path, context.with_file(&file));
+            }
+        }
+
+        async fn check_admin(&self, chat: ChatId, repo: &Repository, sender: Option<&User>) -> ResponseResult<bool> {
+            if sender.map(|user| repo.is_admin(user.id.0)).unwrap_or(false) {
+                return Ok(true);
+            }
+            log::info!("[chat: {}] Denied admin action for {:?}", chat, sender.map(|user| user.id));
+            self.bot
+                .send_message(chat, "Only admins of this repository may do that!")
+                .await?;
+            Ok(false)
+        }
+
+        /**
+         * Reverts the latest commit created from the chat
+         */
+        pub async fn undo(&self, chat: ChatId, sender: Option<&User>) -> ResponseResult<()> {
+            let repo = self.get_repository(chat).await?;
+            if repo.is_none() {
+                return Ok(());
+            }
+            let repo = repo.unwrap();
+
+            let author = self.get_author(repo, sender);
+            match self.publisher.revert_last(repo, chat.0, &author) {
+                Ok(revert) => {
+                    log::info!("[chat: {}] Reverted commit {} with {}", chat, revert.reverted, revert.commit);
+                    self.learn_revert(chat, repo, &revert).await;
+                    self.bot
+                        .send_message(
+                            chat,
+                            format!(
+                                "Reverted commit {} \"{}\"\nRemoved changes to: {}\nCommit: {}",
+                                revert.reverted, revert.summary, revert.files.join(", "), revert.commit
+                            ),
+                        )
+                        .await?;
+                }
+                Err(error) => {
+                    log::error!("[chat: {}] Undo failed: {}", chat, &error);
+                    self.bot
+                        .send_message(chat, format!("Undo failed: {}", &error))
+                        .await?;
+                }
+            }
+            Ok(())
+        }
     }
+
+/**
+ * Unique temporary path for a file of the chat, the same file may be staged several times at once
+ */
+async fn staging_path(chat: ChatId, name: &str) -> std::io::Result<PathBuf> {
+    static STAGED: AtomicU64 = AtomicU64::new(0);
+    let staging_dir = std::env::temp_dir().join("archivist-bot");
+    fs::create_dir_all(&staging_dir).await?;
+    let count = STAGED.fetch_add(1, Ordering::Relaxed);
+    Ok(staging_dir.join(format!("{}-{}-{}-{}", chat, std::process::id(), count, name)))
 }
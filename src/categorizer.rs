@@ -1,17 +1,111 @@
-use std::path::Path;
+use std::{cmp::Reverse, collections::HashMap, path::{Component, Path}};
 use serde_json::from_str;
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
 use serde::{Serialize, Deserialize};
 
-use crate::config::Repository;
+use regex::Regex;
 
+use crate::{config::Repository, convert::ConversionConfig, encrypt::EncryptionConfig, extract, path_matcher::Matcher, policy::FilePolicy};
+
+#[derive(Clone, Copy)]
 pub struct CategorizationContext<'a> {
     repo: &'a Repository,
     chat_id: i64,
+    /**
+     * Downloaded file that is categorized, if available
+     */
+    file: Option<&'a Path>,
+    /**
+     * Name the file was uploaded with, the downloaded file has a generated name
+     */
+    name: Option<&'a str>,
 }
 
-impl CategorizationContext<'_> {
+impl<'a> CategorizationContext<'a> {
     pub fn new(repo: &Repository, chat_id: i64) -> CategorizationContext {
-        CategorizationContext { repo, chat_id }
+        CategorizationContext { repo, chat_id, file: None, name: None }
+    }
+
+    pub fn with_file(mut self, file: &'a Path) -> CategorizationContext<'a> {
+        self.file = Some(file);
+        self
+    }
+
+    pub fn with_name(mut self, name: &'a str) -> CategorizationContext<'a> {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn repo(&self) -> &'a Repository {
+        self.repo
+    }
+
+    pub fn chat_id(&self) -> i64 {
+        self.chat_id
+    }
+
+    pub fn file(&self) -> Option<&'a Path> {
+        self.file
+    }
+
+    pub fn name(&self) -> Option<&'a str> {
+        self.name
+    }
+}
+
+/**
+ * Where the path matcher of a categorization comes from
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum CategorySource {
+    /**
+     * Category with the given tags matched
+     */
+    Category(Vec<String>),
+    /**
+     * Content of the file matched the given keywords and patterns of the category
+     */
+    Content(Vec<String>),
+    /**
+     * Category was suggested by the classifier with the given confidence
+     */
+    Suggested(f64),
+    /**
+     * Default category of the repository
+     */
+    Default,
+    /**
+     * Categorization is used as path
+     */
+    Verbatim,
+    /**
+     * Repository has no categories
+     */
+    Fallback
+}
+
+impl std::fmt::Display for CategorySource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CategorySource::Category(tags) => write!(f, "category with tags {}", tags.join(" ")),
+            CategorySource::Content(hits) => write!(f, "content matching {}", hits.join(", ")),
+            CategorySource::Suggested(confidence) => write!(f, "suggested by classifier with {:.0}% confidence", confidence * 100.0),
+            CategorySource::Default => write!(f, "default category"),
+            CategorySource::Verbatim => write!(f, "no category, used as path"),
+            CategorySource::Fallback => write!(f, "no categories configured"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Categorized {
+    pub path_matcher: String,
+    pub source: CategorySource
+}
+
+impl Categorized {
+    pub fn new(path_matcher: String, source: CategorySource) -> Categorized {
+        Categorized { path_matcher, source }
     }
 }
 
@@ -19,7 +113,24 @@ impl CategorizationContext<'_> {
  * Returns the path for a file based on a categorization string.
  */
 pub trait Categorizer {
-    fn categorize(&self, categorization: Option<&str>, context: CategorizationContext) -> String;
+    fn categorize(&self, categorization: Option<&str>, context: CategorizationContext) -> Categorized;
+
+    /**
+     * All known categories, the user can pick from
+     */
+    fn categories(&self, context: CategorizationContext) -> Vec<Categorized>;
+
+    /**
+     * Learns from a file that was stored at the path, the file of the context is the stored file
+     */
+    fn learn(&self, path: &str, context: CategorizationContext);
+
+    /**
+     * Forgets a file that was stored at the path and is moved or removed, the file of the context
+     * has the content of the file
+     */
+    fn forget(&self, path: &str, context: CategorizationContext);
+
     fn tag(&mut self, tag: &str, path_matcher: &str, context: CategorizationContext);
 }
 
@@ -28,8 +139,21 @@ pub struct ExactPathCategorizer {
 
 
 impl Categorizer for ExactPathCategorizer {
-    fn categorize(&self, categorization: Option<&str>, _context: CategorizationContext) -> String {
-        categorization.unwrap_or("tmp.pdf").to_string()
+    fn categorize(&self, categorization: Option<&str>, _context: CategorizationContext) -> Categorized {
+        match categorization {
+            Some(path) => Categorized::new(path.to_string(), CategorySource::Verbatim),
+            None => Categorized::new("tmp.pdf".to_string(), CategorySource::Fallback),
+        }
+    }
+
+    fn categories(&self, _context: CategorizationContext) -> Vec<Categorized> {
+        Vec::new()
+    }
+
+    fn learn(&self, _path: &str, _context: CategorizationContext) {
+    }
+
+    fn forget(&self, _path: &str, _context: CategorizationContext) {
     }
 
     fn tag(&mut self, _tag: &str, _path_matcher: &str, _context: CategorizationContext) {
@@ -42,7 +166,52 @@ impl Categorizer for ExactPathCategorizer {
 #[serde(rename_all = "camelCase")]
 struct Category {
     tags: Vec<String>,
-    path_matcher: String
+    path_matcher: String,
+    /**
+     * Alternative spellings per tag, e.g. `{"invoice": ["rechnung", "bill"]}`
+     */
+    #[serde(default)]
+    aliases: HashMap<String, Vec<String>>,
+    /**
+     * Tags that must not be present for the category to match
+     */
+    #[serde(default)]
+    excluded_tags: Vec<String>,
+    /**
+     * Categories with higher priority win over more specific ones
+     */
+    #[serde(default)]
+    priority: i32,
+    /**
+     * Words that identify the category in the content of a file
+     */
+    #[serde(default)]
+    keywords: Vec<String>,
+    /**
+     * Regexes that identify the category in the content of a file, e.g. an IBAN
+     */
+    #[serde(default)]
+    patterns: Vec<String>,
+    /**
+     * Valid patterns, compiled once when the categories are read
+     */
+    #[serde(skip)]
+    compiled_patterns: Vec<Regex>,
+    /**
+     * Converts the files of the category to PDF before they are stored
+     */
+    #[serde(default)]
+    conversion: Option<ConversionConfig>,
+    /**
+     * Restricts the files of the category further than the policy of the repository
+     */
+    #[serde(default)]
+    policy: Option<FilePolicy>,
+    /**
+     * Encrypts the files of the category before they are committed
+     */
+    #[serde(default)]
+    encryption: Option<EncryptionConfig>
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -52,25 +221,93 @@ struct Categorization {
     categories: Vec<Category>
 }
 
+/**
+ * Lower cases the tag and removes diacritics, so `Café` matches `cafe`
+ */
+fn normalize_tag(tag: &str) -> String {
+    tag.nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+}
+
 impl Category {
-    fn matches(&self, tags: &Vec<String>) -> bool {
-        for tag in &self.tags {
-            if !tags.contains(&tag) {
-                return false;
-            }
+    fn matches_tag(&self, tag: &str, tags: &[String]) -> bool {
+        let alternatives = self.aliases.get(tag).map(|aliases| aliases.as_slice()).unwrap_or(&[]);
+        std::iter::once(tag)
+            .chain(alternatives.iter().map(|alias| alias.as_str()))
+            .any(|alternative| tags.contains(&normalize_tag(alternative)))
+    }
+
+    /**
+     * Number of matched tags if all tags and no excluded tag are present
+     */
+    fn score(&self, tags: &[String]) -> Option<usize> {
+        if self.excluded_tags.iter().any(|tag| tags.contains(&normalize_tag(tag))) {
+            return None;
         }
-        return true;
+        if self.tags.iter().all(|tag| self.matches_tag(tag, tags)) {
+            return Some(self.tags.len());
+        }
+        None
+    }
+}
+
+impl Category {
+    /**
+     * Keywords and patterns that are found in the text
+     */
+    fn content_hits(&self, text: &str) -> Vec<String> {
+        let normalized = normalize_tag(text);
+        let keywords = self.keywords.iter()
+            .filter(|keyword| normalized.contains(&normalize_tag(keyword)))
+            .cloned();
+        let patterns = self.compiled_patterns.iter()
+            .filter(|regex| regex.is_match(text))
+            .map(|regex| regex.as_str().to_string());
+        keywords.chain(patterns).collect()
+    }
+
+    /**
+     * Compiles the patterns, invalid ones are logged and ignored
+     */
+    fn compile_patterns(&mut self) {
+        self.compiled_patterns = self.patterns.iter()
+            .filter_map(|pattern| match Regex::new(pattern) {
+                Ok(regex) => Some(regex),
+                Err(error) => {
+                    log::error!("Invalid content pattern {}: {}", pattern, error);
+                    None
+                }
+            })
+            .collect();
     }
 }
 
 impl Categorization {
-   fn get_category(&self, tags: &Vec<String>) -> Option<&Category> {
-       for category in &self.categories {
-           if category.matches(tags) {
-               return Some(category);
-           }
-       }
-       return None;
+   /**
+    * Returns the category with the most keyword and pattern hits in the text
+    */
+   fn get_category_by_content(&self, text: &str) -> Option<(&Category, Vec<String>)> {
+       self.categories.iter()
+           .enumerate()
+           .map(|(i, category)| (i, category, category.content_hits(text)))
+           .filter(|(_, _, hits)| !hits.is_empty())
+           .max_by_key(|(i, category, hits)| (hits.len(), category.priority, Reverse(*i)))
+           .map(|(_, category, hits)| (category, hits))
+   }
+
+   /**
+    * Returns the matching category with the highest priority and the most matched tags,
+    * the first one in the file on a tie
+    */
+   fn get_category(&self, tags: &[String]) -> Option<&Category> {
+       let tags: Vec<String> = tags.iter().map(|tag| normalize_tag(tag)).collect();
+       self.categories.iter()
+           .enumerate()
+           .filter_map(|(i, category)| category.score(&tags).map(|score| ((category.priority, score, Reverse(i)), category)))
+           .max_by_key(|(key, _)| *key)
+           .map(|(_, category)| category)
    } 
 }
 
@@ -87,41 +324,276 @@ impl RepoBasedCategorizer {
     }
 
     fn get_categories(&self, context: CategorizationContext) -> Option<Categorization> {
-        let categorization_file = Path::new(context.repo.path()).join("categories.json");
-        if categorization_file.exists() {
-            let contents = std::fs::read_to_string(categorization_file).unwrap();
-            let categories: Categorization = from_str(&contents).unwrap();
-            return Some(categories);
-        }
-        return None;
+        read_categories(context.repo)
+    }
+}
+
+fn read_categories(repo: &Repository) -> Option<Categorization> {
+    let categorization_file = Path::new(repo.path()).join("categories.json");
+    if categorization_file.exists() {
+        let contents = std::fs::read_to_string(categorization_file).unwrap();
+        let mut categories: Categorization = from_str(&contents).unwrap();
+        categories.categories.iter_mut().for_each(Category::compile_patterns);
+        return Some(categories);
     }
+    None
+}
+
+/**
+ * Category with the path matcher, e.g. to look up its settings after the file was categorized
+ */
+fn find_category(repo: &Repository, path_matcher: &str) -> Option<Category> {
+    read_categories(repo)?
+        .categories
+        .into_iter()
+        .find(|category| category.path_matcher == path_matcher)
+}
+
+/**
+ * Conversion of the category with the path matcher, if it has one
+ */
+pub fn conversion_for(repo: &Repository, path_matcher: &str) -> Option<ConversionConfig> {
+    find_category(repo, path_matcher)?.conversion
+}
+
+/**
+ * File policy of the category with the path matcher, if it has one
+ */
+pub fn policy_for(repo: &Repository, path_matcher: &str) -> Option<FilePolicy> {
+    find_category(repo, path_matcher)?.policy
+}
+
+/**
+ * Encryption of the category with the path matcher, if it has one
+ */
+pub fn encryption_for(repo: &Repository, path_matcher: &str) -> Option<EncryptionConfig> {
+    find_category(repo, path_matcher)?.encryption
+}
+
+/**
+ * Encryption of the category whose directory contains the target, if it has one. The target
+ * may not match the path matcher of the category, e.g. if it was given verbatim or moved there.
+ * Of nested categories the innermost one wins.
+ */
+pub fn encryption_for_target(repo: &Repository, target: &Path) -> Option<EncryptionConfig> {
+    let matcher = Matcher::for_repository(repo);
+    let segments: Vec<String> = target.components()
+        .filter_map(|component| match component {
+            Component::Normal(segment) => Some(segment.to_string_lossy().to_string()),
+            _ => None,
+        })
+        .collect();
+    read_categories(repo)?
+        .categories
+        .into_iter()
+        .filter_map(|category| Some((matcher.static_prefix(&category.path_matcher), category.encryption?)))
+        .filter(|(prefix, _)| !prefix.is_empty() && prefix.len() < segments.len() && segments.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, encryption)| encryption)
 }
 
 impl Categorizer for RepoBasedCategorizer {
-    fn categorize(&self, categorization: Option<&str>, context: CategorizationContext) -> String {
+    fn categorize(&self, categorization: Option<&str>, context: CategorizationContext) -> Categorized {
         let categories = self.get_categories(context);
         if categories.is_none() {
-            return "tmp.pdf".to_string();
+            return Categorized::new("tmp.pdf".to_string(), CategorySource::Fallback);
         }
         if categorization.is_none() {
-            return categories.unwrap().default_category.to_string();
+            return Categorized::new(categories.unwrap().default_category.to_string(), CategorySource::Default);
         }
 
         let categorization_string = categorization.unwrap().to_string();
-        let tags = categorization_string.split(" ");
+        let tags: Vec<String> = categorization_string.split_whitespace().map(|s|s.to_string()).collect();
         
-        let found = categories.as_ref().unwrap().get_category(&tags.map(|s|s.to_string()).collect()).map(|cat| cat.path_matcher.clone());
+        let found = categories.as_ref().unwrap().get_category(&tags)
+            .map(|cat| Categorized::new(cat.path_matcher.clone(), CategorySource::Category(cat.tags.clone())));
         if found.is_none() {
             if categorization.is_some() {
-                return categorization_string;
+                return Categorized::new(categorization_string, CategorySource::Verbatim);
             }else{
-                return categories.unwrap().default_category.to_string();
+                return Categorized::new(categories.unwrap().default_category.to_string(), CategorySource::Default);
             }
         }
         return found.unwrap();
     }
 
+    fn categories(&self, context: CategorizationContext) -> Vec<Categorized> {
+        match self.get_categories(context) {
+            Some(categorization) => categorization.categories.iter()
+                .map(|cat| Categorized::new(cat.path_matcher.clone(), CategorySource::Category(cat.tags.clone())))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    fn learn(&self, _path: &str, _context: CategorizationContext) {
+    }
+
+    fn forget(&self, _path: &str, _context: CategorizationContext) {
+    }
+
     fn tag(&mut self, _tag: &str, _path_matcher: &str, _context: CategorizationContext) {
         todo!()
     }
 }
+
+
+/**
+ * Categorizes files without caption by their content, using the keywords and patterns
+ * of the categories. Everything else is delegated to the fallback categorizer.
+ */
+pub struct ContentCategorizer<C: Categorizer> {
+    fallback: C
+}
+
+impl<C: Categorizer> ContentCategorizer<C> {
+    pub fn new(fallback: C) -> ContentCategorizer<C> {
+        ContentCategorizer { fallback }
+    }
+
+    pub fn fallback(&self) -> &C {
+        &self.fallback
+    }
+}
+
+impl<C: Categorizer> Categorizer for ContentCategorizer<C> {
+    fn categorize(&self, categorization: Option<&str>, context: CategorizationContext) -> Categorized {
+        if categorization.is_some() || context.file.is_none() {
+            return self.fallback.categorize(categorization, context);
+        }
+        let text = context.file.and_then(extract::extract_text);
+        let categories = read_categories(context.repo);
+        if let (Some(text), Some(categories)) = (text, categories) {
+            if let Some((category, hits)) = categories.get_category_by_content(&text) {
+                log::info!("[chat: {}] Content matched {} with {:?}", context.chat_id, category.path_matcher, hits);
+                return Categorized::new(category.path_matcher.clone(), CategorySource::Content(hits));
+            }
+        }
+        self.fallback.categorize(None, context)
+    }
+
+    fn categories(&self, context: CategorizationContext) -> Vec<Categorized> {
+        self.fallback.categories(context)
+    }
+
+    fn learn(&self, path: &str, context: CategorizationContext) {
+        self.fallback.learn(path, context)
+    }
+
+    fn forget(&self, path: &str, context: CategorizationContext) {
+        self.fallback.forget(path, context)
+    }
+
+    fn tag(&mut self, tag: &str, path_matcher: &str, context: CategorizationContext) {
+        self.fallback.tag(tag, path_matcher, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn categorization() -> Categorization {
+        from_str(r#"{
+            "defaultCategory": "inbox/+",
+            "categories": [
+                {"tags": ["invoice"], "pathMatcher": "invoices/+", "aliases": {"invoice": ["Rechnung", "bill"]}},
+                {"tags": ["invoice", "car"], "pathMatcher": "car/invoices/+", "excludedTags": ["draft"]},
+                {"tags": ["car"], "pathMatcher": "car/+"},
+                {"tags": ["tax"], "pathMatcher": "taxes/+", "priority": 1},
+                {"tags": ["tax", "car"], "pathMatcher": "car/taxes/+"}
+            ]
+        }"#).unwrap()
+    }
+
+    fn matched(categorization: &Categorization, caption: &str) -> Option<String> {
+        let tags: Vec<String> = caption.split_whitespace().map(|tag| tag.to_string()).collect();
+        categorization.get_category(&tags).map(|category| category.path_matcher.clone())
+    }
+
+    #[test]
+    fn normalize_tag_ignores_case_and_diacritics() {
+        assert_eq!(normalize_tag("Café"), "cafe");
+        assert_eq!(normalize_tag("ÜBERWEISUNG"), "uberweisung");
+        assert_eq!(normalize_tag("Straße"), "straße");
+        assert_eq!(normalize_tag("inv-2023"), "inv-2023");
+    }
+
+    #[test]
+    fn score_requires_all_tags_and_no_excluded_tag() {
+        let categorization = categorization();
+        let car_invoices = &categorization.categories[1];
+        let tags = |tags: &[&str]| tags.iter().map(|tag| tag.to_string()).collect::<Vec<String>>();
+
+        assert_eq!(car_invoices.score(&tags(&["invoice", "car"])), Some(2));
+        assert_eq!(car_invoices.score(&tags(&["invoice"])), None);
+        assert_eq!(car_invoices.score(&tags(&["invoice", "car", "draft"])), None);
+        assert_eq!(categorization.categories[0].score(&tags(&["rechnung"])), Some(1));
+    }
+
+    #[test]
+    fn get_category_prefers_priority_then_more_tags() {
+        let categorization = categorization();
+
+        assert_eq!(matched(&categorization, "Rechnung"), Some("invoices/+".to_string()));
+        assert_eq!(matched(&categorization, "BILL car"), Some("invoices/+".to_string()));
+        assert_eq!(matched(&categorization, "Invoice CAR"), Some("car/invoices/+".to_string()));
+        assert_eq!(matched(&categorization, "invoice car draft"), Some("invoices/+".to_string()));
+        assert_eq!(matched(&categorization, "tax car"), Some("taxes/+".to_string()));
+        assert_eq!(matched(&categorization, "holiday"), None);
+    }
+
+    #[test]
+    fn get_category_by_content_counts_keywords_and_compiled_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("categories.json"), r#"{
+            "defaultCategory": "inbox/+",
+            "categories": [
+                {"tags": ["invoice"], "pathMatcher": "invoices/+", "keywords": ["Rechnung"], "patterns": ["DE\\d{20}", "(unclosed"]},
+                {"tags": ["tax"], "pathMatcher": "taxes/+", "keywords": ["Finanzamt", "Steuer"]}
+            ]
+        }"#).unwrap();
+        let settings = crate::config::RepositorySettings { path: dir.path().to_string_lossy().to_string(), ..Default::default() };
+        let repo = Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string());
+        let categorization = read_categories(&repo).unwrap();
+        let matched = |text: &str| categorization.get_category_by_content(text)
+            .map(|(category, hits)| (category.path_matcher.clone(), hits));
+
+        // The invalid pattern is dropped when the categories are read
+        assert_eq!(categorization.categories[0].compiled_patterns.len(), 1);
+        assert_eq!(
+            matched("RECHNUNG an DE12345678901234567890"),
+            Some(("invoices/+".to_string(), vec!["Rechnung".to_string(), "DE\\d{20}".to_string()]))
+        );
+        assert_eq!(
+            matched("Rechnung vom Finanzamt zur Steuer"),
+            Some(("taxes/+".to_string(), vec!["Finanzamt".to_string(), "Steuer".to_string()]))
+        );
+        assert_eq!(matched("Urlaub"), None);
+    }
+
+    #[test]
+    fn encryption_for_target_finds_the_innermost_encrypted_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("categories.json"), r#"{
+            "defaultCategory": "inbox/+",
+            "categories": [
+                {"tags": ["tax"], "pathMatcher": "taxes/{date:%Y}/+", "encryption": {"recipients": ["age1tax"]}},
+                {"tags": ["key"], "pathMatcher": "taxes/keys/+", "encryption": {"format": "openpgp", "recipients": ["KEY"]}},
+                {"tags": ["invoice"], "pathMatcher": "invoices/+"},
+                {"tags": ["any"], "pathMatcher": "^/+", "encryption": {"recipients": ["age1any"]}}
+            ]
+        }"#).unwrap();
+        let settings = crate::config::RepositorySettings { path: dir.path().to_string_lossy().to_string(), ..Default::default() };
+        let repo = Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string());
+        let recipients = |target: &str| encryption_for_target(&repo, Path::new(target)).map(|encryption| encryption.recipients);
+
+        assert_eq!(recipients("taxes/manual.pdf"), Some(vec!["age1tax".to_string()]));
+        assert_eq!(recipients("taxes/2023/return.pdf"), Some(vec!["age1tax".to_string()]));
+        assert_eq!(recipients("./taxes/keys/private.asc"), Some(vec!["KEY".to_string()]));
+        assert_eq!(recipients("invoices/inv-1.pdf"), None);
+        // A file named like the directory is not in it
+        assert_eq!(recipients("taxes"), None);
+        assert_eq!(recipients("taxes.pdf"), None);
+    }
+}
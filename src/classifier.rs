@@ -0,0 +1,446 @@
+use std::{collections::HashMap, fs, path::{Path, PathBuf}, sync::Mutex};
+use serde::{Serialize, Deserialize};
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+use crate::{
+    categorizer::{Categorized, CategorizationContext, CategorySource, Categorizer},
+    config::Repository,
+    encrypt,
+    extract,
+    metadata,
+    path_matcher::Matcher,
+};
+
+/**
+ * Only the first tokens of a document are used, so large documents do not dominate
+ */
+const MAX_TOKENS: usize = 2000;
+
+/**
+ * Models that are trained in the background right now
+ */
+static TRAINING: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/**
+ * Held while a model is written or read, updated and written back, so concurrent uploads
+ * and a finished training do not overwrite each other's changes
+ */
+static WRITING: Mutex<()> = Mutex::new(());
+
+/**
+ * Multinomial naive bayes model over the tokens of file names and contents.
+ * The labels are the path matchers of the categories.
+ */
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct NaiveBayesModel {
+    /**
+     * Number of documents per label
+     */
+    documents: HashMap<String, u64>,
+    /**
+     * Number of occurrences of a token per label
+     */
+    tokens: HashMap<String, HashMap<String, u64>>,
+    /**
+     * Number of tokens per label
+     */
+    token_totals: HashMap<String, u64>,
+    vocabulary_size: u64
+}
+
+impl NaiveBayesModel {
+    pub fn add(&mut self, label: &str, tokens: &[String]) {
+        *self.documents.entry(label.to_string()).or_insert(0) += 1;
+        *self.token_totals.entry(label.to_string()).or_insert(0) += tokens.len() as u64;
+        for token in tokens {
+            let known = self.tokens.values().any(|counts| counts.contains_key(token));
+            if !known {
+                self.vocabulary_size += 1;
+            }
+            *self.tokens.entry(label.to_string()).or_default().entry(token.clone()).or_insert(0) += 1;
+        }
+    }
+
+    /**
+     * Removes a document that was added with the label and tokens before
+     */
+    pub fn remove(&mut self, label: &str, tokens: &[String]) {
+        let documents = match self.documents.get_mut(label) {
+            Some(documents) => documents,
+            None => return,
+        };
+        *documents -= 1;
+        if *documents == 0 {
+            self.documents.remove(label);
+            self.token_totals.remove(label);
+            let removed = self.tokens.remove(label).unwrap_or_default();
+            self.forget_unknown(removed.keys());
+            return;
+        }
+        if let Some(total) = self.token_totals.get_mut(label) {
+            *total = total.saturating_sub(tokens.len() as u64);
+        }
+        let mut removed = Vec::new();
+        if let Some(counts) = self.tokens.get_mut(label) {
+            for token in tokens {
+                if let Some(count) = counts.get_mut(token) {
+                    *count -= 1;
+                    if *count == 0 {
+                        counts.remove(token);
+                        removed.push(token.clone());
+                    }
+                }
+            }
+        }
+        self.forget_unknown(removed.iter());
+    }
+
+    /**
+     * Shrinks the vocabulary by the tokens no label knows anymore
+     */
+    fn forget_unknown<'a>(&mut self, tokens: impl Iterator<Item = &'a String>) {
+        for token in tokens {
+            if !self.tokens.values().any(|counts| counts.contains_key(token)) {
+                self.vocabulary_size = self.vocabulary_size.saturating_sub(1);
+            }
+        }
+    }
+
+    /**
+     * Returns the most probable label and its probability
+     */
+    pub fn predict(&self, tokens: &[String]) -> Option<(String, f64)> {
+        let total_documents: u64 = self.documents.values().sum();
+        if total_documents == 0 || tokens.is_empty() {
+            return None;
+        }
+        let vocabulary = (self.vocabulary_size + 1) as f64;
+        let scores: Vec<(&String, f64)> = self.documents.iter()
+            .map(|(label, documents)| {
+                let counts = self.tokens.get(label);
+                let total = *self.token_totals.get(label).unwrap_or(&0) as f64;
+                let prior = (*documents as f64 / total_documents as f64).ln();
+                let likelihood: f64 = tokens.iter()
+                    .map(|token| {
+                        let count = counts.and_then(|counts| counts.get(token)).copied().unwrap_or(0) as f64;
+                        ((count + 1.0) / (total + vocabulary)).ln()
+                    })
+                    .sum();
+                (label, prior + likelihood)
+            })
+            .collect();
+        let max = scores.iter().map(|(_, score)| *score).fold(f64::NEG_INFINITY, f64::max);
+        let normalization: f64 = scores.iter().map(|(_, score)| (score - max).exp()).sum();
+        scores.into_iter()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map(|(label, score)| (label.clone(), (score - max).exp() / normalization))
+    }
+}
+
+/**
+ * Splits the name and content of a file into lower case tokens without diacritics
+ */
+pub fn tokenize(path: &Path, file: Option<&Path>) -> Vec<String> {
+    let name = path.file_stem().and_then(|name| name.to_str()).unwrap_or("");
+    let content = file.and_then(extract::extract_text).unwrap_or_default();
+    format!("{} {}", name, content)
+        .nfd()
+        .filter(|c| !is_combining_mark(*c))
+        .collect::<String>()
+        .to_lowercase()
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|token| token.chars().count() > 1 && !token.chars().all(|c| c.is_ascii_digit()))
+        .take(MAX_TOKENS)
+        .map(|token| token.to_string())
+        .collect()
+}
+
+/**
+ * Returns the category whose static prefix is the longest prefix of the path
+ */
+fn label_for(path: &str, categories: &[Categorized], matcher: &Matcher) -> Option<String> {
+    let segments: Vec<String> = path.split('/').map(|segment| segment.to_string()).collect();
+    categories.iter()
+        .map(|category| (matcher.static_prefix(&category.path_matcher), category))
+        .filter(|(prefix, _)| !prefix.is_empty() && prefix.len() < segments.len() && segments.starts_with(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, category)| category.path_matcher.clone())
+}
+
+/**
+ * Suggests categories for files without caption with a naive bayes classifier that
+ * is trained on the files already stored in the repository.
+ * Suggestions below the threshold and captioned files are delegated to the fallback.
+ */
+pub struct NaiveBayesCategorizer<C: Categorizer> {
+    fallback: C,
+    state_dir: PathBuf,
+    threshold: f64
+}
+
+impl<C: Categorizer> NaiveBayesCategorizer<C> {
+    pub fn new(fallback: C, state_dir: &str, threshold: f64) -> NaiveBayesCategorizer<C> {
+        NaiveBayesCategorizer { fallback, state_dir: PathBuf::from(state_dir), threshold }
+    }
+
+    fn model_path(&self, repo: &Repository) -> PathBuf {
+        let name: String = repo.path()
+            .chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect();
+        self.state_dir.join("models").join(format!("{}.json", name))
+    }
+
+    fn load_model(&self, repo: &Repository) -> Option<NaiveBayesModel> {
+        let data = fs::read_to_string(self.model_path(repo)).ok()?;
+        match serde_json::from_str(&data) {
+            Ok(model) => Some(model),
+            Err(error) => {
+                log::error!("[repo: {}] Could not read model: {}", repo.path(), error);
+                None
+            }
+        }
+    }
+
+    fn save_model(&self, repo: &Repository, model: &NaiveBayesModel) -> std::io::Result<()> {
+        write_model(&self.model_path(repo), model)
+    }
+
+    /**
+     * Trains a new model on all files tracked in the repository and persists it
+     */
+    pub fn train(&self, context: CategorizationContext) -> Result<NaiveBayesModel, git2::Error> {
+        let categories = self.fallback.categories(context);
+        train_model(context.repo(), &categories, &self.model_path(context.repo()))
+    }
+
+    /**
+     * Trains the model on a blocking thread, so the upload is not held up.
+     * Does nothing if the model is already being trained.
+     */
+    fn train_in_background(&self, context: CategorizationContext) {
+        let model_path = self.model_path(context.repo());
+        {
+            let mut training = TRAINING.lock().unwrap();
+            if training.contains(&model_path) {
+                return;
+            }
+            training.push(model_path.clone());
+        }
+        let categories = self.fallback.categories(context);
+        let repo = context.repo().clone();
+        log::info!("[repo: {}] Training model in the background", repo.path());
+        tokio::task::spawn_blocking(move || {
+            if let Err(error) = train_model(&repo, &categories, &model_path) {
+                log::error!("[repo: {}] Training failed: {}", repo.path(), error);
+            }
+            TRAINING.lock().unwrap().retain(|training| *training != model_path);
+        });
+    }
+
+    /**
+     * Label and tokens of a stored file, None for encrypted files as their content can not be read
+     */
+    fn document(&self, path: &str, context: CategorizationContext) -> Option<(String, Vec<String>)> {
+        if encrypt::decrypted_path(Path::new(path)).is_some() {
+            return None;
+        }
+        let label = label_for(path, &self.fallback.categories(context), &Matcher::for_repository(context.repo()))?;
+        Some((label, tokenize(Path::new(path), context.file())))
+    }
+
+    fn update_model(&self, context: CategorizationContext, update: impl FnOnce(&mut NaiveBayesModel)) {
+        let _writing = WRITING.lock().unwrap();
+        if let Some(mut model) = self.load_model(context.repo()) {
+            update(&mut model);
+            if let Err(error) = self.save_model(context.repo(), &model) {
+                log::error!("[repo: {}] Could not save model: {}", context.repo().path(), error);
+            }
+        }
+    }
+}
+
+/**
+ * Trains a new model on all files tracked in the repository and persists it at `model_path`
+ */
+fn train_model(repo: &Repository, categories: &[Categorized], model_path: &Path) -> Result<NaiveBayesModel, git2::Error> {
+    let git_repo = git2::Repository::open(repo.path())?;
+    let index = git_repo.index()?;
+
+    let matcher = Matcher::for_repository(repo);
+    let mut model = NaiveBayesModel::default();
+    for entry in index.iter() {
+        let path = String::from_utf8_lossy(&entry.path).to_string();
+        if metadata::is_sidecar(&path) || encrypt::decrypted_path(Path::new(&path)).is_some() {
+            continue;
+        }
+        if let Some(label) = label_for(&path, categories, &matcher) {
+            let file = Path::new(repo.path()).join(&path);
+            model.add(&label, &tokenize(Path::new(&path), Some(&file)));
+        }
+    }
+    log::info!("[repo: {}] Trained model on {} files", repo.path(), model.documents.values().sum::<u64>());
+    let _writing = WRITING.lock().unwrap();
+    write_model(model_path, &model).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+    Ok(model)
+}
+
+fn write_model(path: &Path, model: &NaiveBayesModel) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string(model)?)
+}
+
+impl<C: Categorizer> Categorizer for NaiveBayesCategorizer<C> {
+    fn categorize(&self, categorization: Option<&str>, context: CategorizationContext) -> Categorized {
+        if categorization.is_some() || context.file().is_none() {
+            return self.fallback.categorize(categorization, context);
+        }
+        // Until the model is trained the fallback categorizes
+        let model = self.load_model(context.repo());
+        if model.is_none() {
+            self.train_in_background(context);
+        }
+        // The model is trained on the names the files are stored with, not the generated download names
+        let file = context.file().unwrap();
+        let name = context.name().map(Path::new).unwrap_or(file);
+        let prediction = model.and_then(|model| model.predict(&tokenize(name, Some(file))));
+        if let Some((label, confidence)) = prediction {
+            log::info!("[chat: {}] Classifier suggests {} with {:.2}", context.chat_id(), label, confidence);
+            if confidence >= self.threshold {
+                return Categorized::new(label, CategorySource::Suggested(confidence));
+            }
+        }
+        self.fallback.categorize(None, context)
+    }
+
+    fn categories(&self, context: CategorizationContext) -> Vec<Categorized> {
+        self.fallback.categories(context)
+    }
+
+    fn learn(&self, path: &str, context: CategorizationContext) {
+        if let Some((label, tokens)) = self.document(path, context) {
+            self.update_model(context, |model| model.add(&label, &tokens));
+        }
+        self.fallback.learn(path, context)
+    }
+
+    fn forget(&self, path: &str, context: CategorizationContext) {
+        if let Some((label, tokens)) = self.document(path, context) {
+            self.update_model(context, |model| model.remove(&label, &tokens));
+        }
+        self.fallback.forget(path, context)
+    }
+
+    fn tag(&mut self, tag: &str, path_matcher: &str, context: CategorizationContext) {
+        self.fallback.tag(tag, path_matcher, context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{categorizer::RepoBasedCategorizer, config::RepositorySettings};
+
+    fn tokens(text: &str) -> Vec<String> {
+        text.split_whitespace().map(|token| token.to_string()).collect()
+    }
+
+    #[test]
+    fn model_predicts_the_label_with_matching_tokens() {
+        let mut model = NaiveBayesModel::default();
+        model.add("invoices/+", &tokens("invoice amount due iban"));
+        model.add("car/+", &tokens("vehicle registration tyres"));
+
+        assert_eq!(model.predict(&tokens("invoice iban")).map(|(label, _)| label), Some("invoices/+".to_string()));
+        assert_eq!(model.predict(&tokens("tyres")).map(|(label, _)| label), Some("car/+".to_string()));
+        assert_eq!(model.predict(&[]), None);
+    }
+
+    #[test]
+    fn removing_a_document_restores_the_model() {
+        let mut model = NaiveBayesModel::default();
+        model.add("invoices/+", &tokens("invoice amount"));
+        let before = serde_json::to_value(&model).unwrap();
+
+        model.add("invoices/+", &tokens("invoice reminder"));
+        model.add("car/+", &tokens("vehicle"));
+        model.remove("car/+", &tokens("vehicle"));
+        model.remove("invoices/+", &tokens("invoice reminder"));
+        model.remove("unknown/+", &tokens("invoice"));
+
+        assert_eq!(serde_json::to_value(&model).unwrap(), before);
+    }
+
+    #[test]
+    fn label_for_uses_the_configured_tokens() {
+        let categories: Vec<Categorized> = ["invoices/#", "invoices/car/+", "{date:%Y}/+"].iter()
+            .map(|path_matcher| Categorized::new(path_matcher.to_string(), CategorySource::Default))
+            .collect();
+        let settings = RepositorySettings {
+            rules: serde_json::from_str(r##"[{"rule": "add", "token": "#"}, {"rule": "date"}]"##).unwrap(),
+            ..Default::default()
+        };
+        let custom = Matcher::for_repository(&Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string()));
+
+        assert_eq!(label_for("invoices/car/1.pdf", &categories, &Matcher::new()), Some("invoices/car/+".to_string()));
+        // `+` is no token of the custom rules, so `invoices/car/+` is a plain path
+        assert_eq!(label_for("invoices/car/1.pdf", &categories, &custom), Some("invoices/#".to_string()));
+        assert_eq!(label_for("invoices/car/+/1.pdf", &categories, &custom), Some("invoices/car/+".to_string()));
+        assert_eq!(label_for("2023/1.pdf", &categories, &custom), None);
+    }
+
+    fn categorizer(dir: &Path) -> (NaiveBayesCategorizer<RepoBasedCategorizer>, Repository) {
+        std::fs::write(dir.join("categories.json"), r#"{
+            "defaultCategory": "inbox/+",
+            "categories": [{"tags": ["invoice"], "pathMatcher": "invoices/+"}, {"tags": ["car"], "pathMatcher": "car/+"}]
+        }"#).unwrap();
+        let settings = RepositorySettings { path: dir.to_string_lossy().to_string(), ..Default::default() };
+        let repo = Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string());
+        let categorizer = NaiveBayesCategorizer::new(RepoBasedCategorizer::new(), &dir.join("state").to_string_lossy(), 0.6);
+        categorizer.save_model(&repo, &NaiveBayesModel::default()).unwrap();
+        (categorizer, repo)
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = tempfile::tempdir().unwrap();
+        let (categorizer, repo) = categorizer(dir.path());
+        let file = dir.path().join("upload.txt");
+        std::fs::write(&file, "amount due").unwrap();
+
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let (categorizer, repo, file) = (&categorizer, &repo, &file);
+                scope.spawn(move || {
+                    let context = CategorizationContext::new(repo, 1).with_file(file);
+                    categorizer.learn(&format!("invoices/invoice-{}.pdf", i), context);
+                    categorizer.learn(&format!("car/tyres-{}.pdf", i), context);
+                    categorizer.forget(&format!("car/tyres-{}.pdf", i), context);
+                });
+            }
+        });
+
+        let model = categorizer.load_model(&repo).unwrap();
+        assert_eq!(model.documents, HashMap::from([("invoices/+".to_string(), 8)]));
+    }
+
+    #[test]
+    fn predicts_by_the_uploaded_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let (categorizer, repo) = categorizer(dir.path());
+        let file = dir.path().join("1-42-7-AgADBAADr6cxG.txt");
+        std::fs::write(&file, "").unwrap();
+        let context = CategorizationContext::new(&repo, 1);
+        categorizer.learn("invoices/invoice-march.pdf", context);
+        categorizer.learn("invoices/invoice-april.pdf", context);
+        categorizer.learn("car/tyres-front.pdf", context);
+        categorizer.learn("car/tyres-rear.pdf", context);
+
+        let categorized = categorizer.categorize(None, context.with_file(&file).with_name("Invoice May.txt"));
+        assert_eq!(categorized.path_matcher, "invoices/+");
+        assert!(matches!(categorized.source, CategorySource::Suggested(_)));
+    }
+}
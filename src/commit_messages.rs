@@ -41,8 +41,47 @@ impl CommitMessageGenerator for WhatTheCommitMessageGenerator {
 }
 
 
+/**
+ * Trailer that records the chat a commit was created from
+ */
+pub const CHAT_TRAILER: &str = "Archived-From-Chat";
+
+/**
+ * Trailer of undo commits that records the reverted commit
+ */
+pub const REVERTS_TRAILER: &str = "Reverts";
+
+pub fn with_chat_trailer(message: &str, chat_id: i64) -> String {
+    format!("{}\n\n{}: {}", message.trim_end(), CHAT_TRAILER, chat_id)
+}
 
+pub fn get_trailer<'a>(message: &'a str, trailer: &str) -> Option<&'a str> {
+    let prefix = format!("{}: ", trailer);
+    message.lines()
+        .rev()
+        .find_map(|line| line.strip_prefix(prefix.as_str()))
+        .map(|value| value.trim())
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chat_trailer_round_trips() {
+        let message = with_chat_trailer("Fix the thing\n", -1001234);
+        assert_eq!(message, "Fix the thing\n\nArchived-From-Chat: -1001234");
+        assert_eq!(get_trailer(&message, CHAT_TRAILER), Some("-1001234"));
+        assert_eq!(get_trailer(&message, REVERTS_TRAILER), None);
+        // Generated messages may be empty
+        assert_eq!(get_trailer(&with_chat_trailer("", 7), CHAT_TRAILER), Some("7"));
+    }
 
-
-
+    #[test]
+    fn get_trailer_prefers_the_last_line() {
+        let message = "Revert \"Archived-From-Chat: 2\"\n\nArchived-From-Chat: 1\nReverts: 0123abc \n";
+        assert_eq!(get_trailer(message, CHAT_TRAILER), Some("1"));
+        assert_eq!(get_trailer(message, REVERTS_TRAILER), Some("0123abc"));
+        assert_eq!(get_trailer("Archived-From-Chat:1", CHAT_TRAILER), None);
+    }
+}
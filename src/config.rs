@@ -1,26 +1,185 @@
 use std::collections::HashMap;
 use std::fs;
 use git2::Signature;
+use serde::{Serialize, Deserialize};
 
-#[derive(Debug)]
+use crate::policy::FilePolicy;
+
+#[derive(Debug, Clone)]
 pub struct Repository {
-    path: String,
     secret: String,
     author_name: String,
-    author_email: String
+    author_email: String,
+    settings: RepositorySettings
+}
+
+/**
+ * Per repository configuration as read from the repository config file
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RepositorySettings {
+    pub path: String,
+    /**
+     * Key used to sign the commits of the bot, commits are unsigned if missing
+     */
+    #[serde(default)]
+    pub signing: Option<SigningConfig>,
+    /**
+     * Telegram user ids that are allowed to delete files
+     */
+    #[serde(default)]
+    pub admins: Vec<u64>,
+    /**
+     * Directories files may be written to, the whole repository if empty
+     */
+    #[serde(default)]
+    pub writable_roots: Vec<String>,
+    /**
+     * Path rules in the order they are applied, the built in rules if empty
+     */
+    #[serde(default)]
+    pub rules: Vec<RuleConfig>,
+    /**
+     * Git LFS server of the files tracked by LFS in `.gitattributes`
+     */
+    #[serde(default)]
+    pub lfs: LfsSettings,
+    /**
+     * Size and types of the files that are accepted
+     */
+    #[serde(default)]
+    pub policy: FilePolicy,
+    /**
+     * Changes to the built in pipeline stages, e.g. `[{"stage": "sanitize", "enabled": false}]`.
+     * Built in stages keep their position, other stages run before `encrypt` in the given order.
+     */
+    #[serde(default)]
+    pub stages: Vec<StageConfig>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct LfsSettings {
+    /**
+     * Endpoint of the LFS server, defaults to `lfs.url` of the git config or `<origin>/info/lfs`
+     */
+    #[serde(default)]
+    pub url: Option<String>,
+    /**
+     * Value of the authorization header sent to the LFS server
+     */
+    #[serde(default)]
+    pub authorization: Option<String>,
+    /**
+     * Largest file in bytes the LFS server accepts
+     */
+    #[serde(default)]
+    pub max_size: Option<u64>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RuleConfig {
+    /**
+     * Name of the rule in the rule registry
+     */
+    pub rule: String,
+    /**
+     * Custom token that triggers the rule
+     */
+    #[serde(default)]
+    pub token: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct StageConfig {
+    /**
+     * Name of the stage in the stage registry
+     */
+    pub stage: String,
+    /**
+     * Options of the stage, their format depends on the stage
+     */
+    #[serde(default)]
+    pub options: Option<serde_json::Value>,
+    /**
+     * Removes the stage from the pipeline if false
+     */
+    #[serde(default = "default_enabled")]
+    pub enabled: bool
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SigningFormat {
+    Ssh,
+    OpenPgp
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SigningConfig {
+    pub format: SigningFormat,
+    /**
+     * Path to the private key for ssh, key id for openpgp
+     */
+    pub key: String
+}
+
+/**
+ * A repository is either configured by its path only or with all settings
+ */
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum RepositoryEntry {
+    Path(String),
+    Settings(Box<RepositorySettings>)
+}
+
+impl RepositoryEntry {
+    fn into_settings(self) -> RepositorySettings {
+        match self {
+            RepositoryEntry::Path(path) => RepositorySettings { path, ..Default::default() },
+            RepositoryEntry::Settings(settings) => *settings,
+        }
+    }
 }
 
 impl Repository {
     pub fn path(&self) -> &String {
-        &self.path
+        &self.settings.path
+    }
+
+    pub fn settings(&self) -> &RepositorySettings {
+        &self.settings
+    }
+
+    pub fn is_admin(&self, user_id: u64) -> bool {
+        self.settings.admins.contains(&user_id)
     }
     
+    /**
+     * Identity of the bot, used as committer of all commits
+     */
     pub fn signature(&self) -> Result<Signature, git2::Error> {
-        Signature::now(self.author_name.clone().as_str(), self.author_email.clone().as_str())
+        Signature::now(&signature_part(&self.author_name), &signature_part(&self.author_email))
     }
 
-    pub fn new(path:String, secret: String, author_name: String, author_email: String) -> Repository {
-        Repository { path, secret, author_name: author_name, author_email}
+    /**
+     * Bot identity as author, used when the sender of a file is unknown
+     */
+    pub fn author(&self) -> Author {
+        Author::new(self.author_name.clone(), self.author_email.clone())
+    }
+
+    pub fn new(settings: RepositorySettings, secret: String, author_name: String, author_email: String) -> Repository {
+        Repository { secret, author_name, author_email, settings }
     }
 }
 
@@ -59,10 +218,10 @@ impl JsonRepositoryFactory {
    pub fn new(config_path: &str, author_name: &str, author_email: &str) -> JsonRepositoryFactory {
         let data = fs::read_to_string(config_path)
             .expect("Unable to read file");
-        let repo_paths = serde_json::from_str::<HashMap<String, String>>(data.as_str()).unwrap();
+        let repo_entries = serde_json::from_str::<HashMap<String, RepositoryEntry>>(data.as_str()).unwrap();
         let mut repos = HashMap::new();
-        for (secret, path) in repo_paths {
-            repos.insert(secret.clone(), Repository::new(path, secret, author_name.to_string(), author_email.to_string()));
+        for (secret, entry) in repo_entries {
+            repos.insert(secret.clone(), Repository::new(entry.into_settings(), secret, author_name.to_string(), author_email.to_string()));
         }
         JsonRepositoryFactory { repos }
    } 
@@ -79,4 +238,64 @@ impl RepositoryFactory for JsonRepositoryFactory {
 }
 
 
+/**
+ * Person who is recorded as author of an archived file
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Author {
+    pub name: String,
+    pub email: String
+}
+
+impl Author {
+    pub fn new(name: String, email: String) -> Author {
+        Author { name, email }
+    }
+
+    /**
+     * Characters git does not allow in names and emails, like the brackets of
+     * `Name <email>`, are removed
+     */
+    pub fn signature(&self) -> Result<Signature<'static>, git2::Error> {
+        Signature::now(&signature_part(&self.name), &signature_part(&self.email))
+    }
+}
 
+fn signature_part(value: &str) -> String {
+    value.chars()
+        .filter(|c| *c != '<' && *c != '>' && !c.is_control())
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+/**
+ * Maps telegram user ids to git authors.
+ * Users without an entry are recorded as `Full Name <id@telegram>`.
+ */
+#[derive(Default)]
+pub struct AuthorMapping {
+    authors: HashMap<u64, Author>
+}
+
+impl AuthorMapping {
+    /**
+     * Reads the mapping, a missing file is an empty mapping
+     */
+    pub fn load(config_path: &str) -> Result<AuthorMapping, serde_json::Error> {
+        let mut authors = HashMap::new();
+        if let Ok(data) = fs::read_to_string(config_path) {
+            authors.extend(serde_json::from_str::<HashMap<u64, Author>>(data.as_str())?);
+        } else {
+            log::info!("No author mapping found at {}", config_path);
+        }
+        Ok(AuthorMapping { authors })
+    }
+
+    pub fn get_author(&self, user_id: u64, full_name: &str) -> Author {
+        match self.authors.get(&user_id) {
+            Some(author) => author.clone(),
+            None => Author::new(full_name.to_string(), format!("{}@telegram", user_id)),
+        }
+    }
+}
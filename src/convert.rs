@@ -0,0 +1,328 @@
+use std::{path::{Path, PathBuf}, process::Command};
+use lopdf::{dictionary, Document, Object, Stream, content::{Content, Operation}};
+use serde::{Serialize, Deserialize};
+
+use crate::extract;
+
+/**
+ * Extensions of documents LibreOffice converts
+ */
+const OFFICE_EXTENSIONS: [&str; 10] = ["doc", "docx", "odt", "rtf", "xls", "xlsx", "ods", "ppt", "pptx", "odp"];
+
+/**
+ * Extensions of the images that are converted, see `extract::is_image`
+ */
+const IMAGE_EXTENSIONS: [&str; 5] = ["jpg", "jpeg", "png", "tif", "tiff"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum PdfFormat {
+    #[default]
+    Pdf,
+    /**
+     * PDF/A-2b for long-term archiving, requires LibreOffice
+     */
+    PdfA
+}
+
+/**
+ * Conversion of the files of a category, e.g. `{"format": "pdfa", "keepOriginal": true}`
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversionConfig {
+    #[serde(default)]
+    pub format: PdfFormat,
+    /**
+     * Stores the original next to the converted file
+     */
+    #[serde(default)]
+    pub keep_original: bool,
+}
+
+/**
+ * Converts images and office documents to PDF. JPEG images are converted directly,
+ * everything else and PDF/A requires LibreOffice.
+ */
+pub struct Converter {
+    soffice: String
+}
+
+impl Converter {
+    pub fn new(soffice: &str) -> Converter {
+        Converter { soffice: soffice.to_string() }
+    }
+
+    pub fn from_env() -> Converter {
+        Converter::new(&std::env::var("SOFFICE").unwrap_or("soffice".to_string()))
+    }
+
+    /**
+     * Converts the file to a PDF next to it and returns its path,
+     * None if the file is already a PDF or can not be converted.
+     * Fails with `NotFound` if the conversion needs LibreOffice and it is not installed.
+     */
+    pub fn convert(&self, file: &Path, config: &ConversionConfig) -> std::io::Result<Option<PathBuf>> {
+        if extract::is_pdf(file) {
+            return Ok(None);
+        }
+        let target = converted_path(file);
+        if target == file {
+            log::info!("Can not convert {}, it would overwrite itself", file.display());
+            return Ok(None);
+        }
+        if config.format == PdfFormat::Pdf && is_jpeg(file) {
+            jpeg_to_pdf(file, &target)?;
+            return Ok(Some(target));
+        }
+        if !extract::is_image(file) && !is_office_document(file) {
+            log::info!("Can not convert {}, unsupported type", file.display());
+            return Ok(None);
+        }
+        self.convert_with_libreoffice(file, config.format)
+    }
+
+    fn convert_with_libreoffice(&self, file: &Path, format: PdfFormat) -> std::io::Result<Option<PathBuf>> {
+        let filter = match (format, extract::is_image(file)) {
+            (PdfFormat::Pdf, _) => "pdf".to_string(),
+            (PdfFormat::PdfA, true) => r#"pdf:draw_pdf_Export:{"SelectPdfVersion":{"type":"long","value":"2"}}"#.to_string(),
+            (PdfFormat::PdfA, false) => r#"pdf:writer_pdf_Export:{"SelectPdfVersion":{"type":"long","value":"2"}}"#.to_string(),
+        };
+        let outdir = file.parent().unwrap_or(Path::new("."));
+        let output = match Command::new(&self.soffice)
+            .arg("--headless")
+            .arg("--convert-to")
+            .arg(filter)
+            .arg("--outdir")
+            .arg(outdir)
+            .arg(file)
+            .output() {
+            Ok(output) => output,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                log::info!("Can not convert {}, {} is not available", file.display(), self.soffice);
+                return Err(std::io::Error::new(error.kind(), format!("{} not available", self.soffice)));
+            }
+            Err(error) => return Err(error),
+        };
+        let target = converted_path(file);
+        if !output.status.success() || !target.exists() {
+            return Err(std::io::Error::other(format!(
+                "{} failed: {}", self.soffice, String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(Some(target))
+    }
+}
+
+/**
+ * Path the converted PDF of a file is written to
+ */
+pub fn converted_path(file: &Path) -> PathBuf {
+    file.with_extension("pdf")
+}
+
+/**
+ * Tells by the extension whether a file would be converted, for files that do not exist yet
+ */
+pub fn is_convertible_name(file: &Path) -> bool {
+    let extension = file.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase())
+        .unwrap_or_default();
+    IMAGE_EXTENSIONS.contains(&extension.as_str()) || OFFICE_EXTENSIONS.contains(&extension.as_str())
+}
+
+fn is_jpeg(file: &Path) -> bool {
+    let mut header = [0u8; 3];
+    match std::fs::File::open(file) {
+        Ok(mut handle) => std::io::Read::read_exact(&mut handle, &mut header).is_ok() && header == [0xFF, 0xD8, 0xFF],
+        Err(_) => false,
+    }
+}
+
+fn is_office_document(file: &Path) -> bool {
+    file.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| OFFICE_EXTENSIONS.contains(&extension.to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+/**
+ * Size and number of color components of a JPEG from its start of frame marker
+ */
+fn jpeg_dimensions(data: &[u8]) -> Option<(u16, u16, u8)> {
+    let mut i = 2;
+    while i + 9 < data.len() {
+        if data[i] != 0xFF {
+            return None;
+        }
+        let marker = data[i + 1];
+        let length = u16::from_be_bytes([data[i + 2], data[i + 3]]) as usize;
+        // SOF0 to SOF15 except DHT, JPG and DAC
+        if (0xC0..=0xCF).contains(&marker) && ![0xC4, 0xC8, 0xCC].contains(&marker) {
+            if length < 8 {
+                return None;
+            }
+            let height = u16::from_be_bytes([data[i + 5], data[i + 6]]);
+            let width = u16::from_be_bytes([data[i + 7], data[i + 8]]);
+            return Some((width, height, data[i + 9])).filter(|_| width > 0 && height > 0);
+        }
+        i += 2 + length;
+    }
+    None
+}
+
+/**
+ * Embeds the JPEG unchanged in a single page PDF of the same size
+ */
+fn jpeg_to_pdf(file: &Path, target: &Path) -> std::io::Result<()> {
+    let data = std::fs::read(file)?;
+    let (width, height, components) = jpeg_dimensions(&data)
+        .ok_or_else(|| std::io::Error::other(format!("{} is no valid JPEG", file.display())))?;
+    let color_space = match components {
+        1 => "DeviceGray",
+        4 => "DeviceCMYK",
+        _ => "DeviceRGB",
+    };
+
+    let mut document = Document::with_version("1.5");
+    let pages_id = document.new_object_id();
+    let image_id = document.add_object(Stream::new(dictionary! {
+        "Type" => "XObject",
+        "Subtype" => "Image",
+        "Width" => width as i64,
+        "Height" => height as i64,
+        "ColorSpace" => color_space,
+        "BitsPerComponent" => 8,
+        "Filter" => "DCTDecode",
+    }, data));
+    let content = Content {
+        operations: vec![
+            Operation::new("q", vec![]),
+            Operation::new("cm", vec![(width as i64).into(), 0.into(), 0.into(), (height as i64).into(), 0.into(), 0.into()]),
+            Operation::new("Do", vec![Object::Name(b"Im0".to_vec())]),
+            Operation::new("Q", vec![]),
+        ],
+    };
+    let content_id = document.add_object(Stream::new(dictionary! {}, content.encode().map_err(std::io::Error::other)?));
+    let page_id = document.add_object(dictionary! {
+        "Type" => "Page",
+        "Parent" => pages_id,
+        "MediaBox" => vec![0.into(), 0.into(), (width as i64).into(), (height as i64).into()],
+        "Resources" => dictionary! { "XObject" => dictionary! { "Im0" => image_id } },
+        "Contents" => content_id,
+    });
+    document.objects.insert(pages_id, Object::Dictionary(dictionary! {
+        "Type" => "Pages",
+        "Kids" => vec![page_id.into()],
+        "Count" => 1,
+    }));
+    let catalog_id = document.add_object(dictionary! {
+        "Type" => "Catalog",
+        "Pages" => pages_id,
+    });
+    document.trailer.set("Root", catalog_id);
+    document.save(target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /**
+     * Start of frame of 32x16 pixels with the given number of components after a JFIF segment
+     */
+    fn jpeg(components: u8) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x07, b'J', b'F', b'I', b'F', 0x00];
+        data.extend([0xFF, 0xC0, 0x00, 0x08 + 3 * components, 0x08, 0x00, 0x10, 0x00, 0x20, components]);
+        for id in 1..=components {
+            data.extend([id, 0x11, 0x00]);
+        }
+        data.extend([0xFF, 0xD9]);
+        data
+    }
+
+    #[test]
+    fn jpeg_dimensions_reads_the_start_of_frame() {
+        assert_eq!(jpeg_dimensions(&jpeg(3)), Some((32, 16, 3)));
+        assert_eq!(jpeg_dimensions(&jpeg(1)), Some((32, 16, 1)));
+        // Progressive
+        let mut progressive = jpeg(3);
+        progressive[12] = 0xC2;
+        assert_eq!(jpeg_dimensions(&progressive), Some((32, 16, 3)));
+    }
+
+    #[test]
+    fn jpeg_dimensions_rejects_truncated_and_invalid_frames() {
+        let data = jpeg(3);
+        let sof = 11;
+        // Cut within the start of frame
+        assert_eq!(jpeg_dimensions(&data[..sof + 8]), None);
+        // Segment length too short for a start of frame
+        let mut short = data.clone();
+        short[sof + 3] = 0x02;
+        assert_eq!(jpeg_dimensions(&short), None);
+        // No image
+        let mut empty = data.clone();
+        empty[sof + 7] = 0x00;
+        empty[sof + 8] = 0x00;
+        assert_eq!(jpeg_dimensions(&empty), None);
+        // A segment does not start with a marker
+        let mut garbage = data.clone();
+        garbage[sof] = 0x00;
+        assert_eq!(jpeg_dimensions(&garbage), None);
+        // Segment length pointing past the end
+        let mut overlong = data;
+        overlong[4] = 0xFF;
+        assert_eq!(jpeg_dimensions(&overlong), None);
+    }
+
+    #[test]
+    fn jpeg_to_pdf_embeds_the_image_in_a_page_of_its_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scan.jpg");
+        std::fs::write(&image, jpeg(1)).unwrap();
+
+        let pdf = Converter::new("/missing/soffice").convert(&image, &ConversionConfig::default()).unwrap().unwrap();
+        assert_eq!(pdf, dir.path().join("scan.pdf"));
+        let document = Document::load(&pdf).unwrap();
+        let pages = document.get_pages();
+        assert_eq!(pages.len(), 1);
+        let page = document.get_dictionary(pages[&1]).unwrap();
+        let media_box: Vec<i64> = page.get(b"MediaBox").unwrap().as_array().unwrap().iter().map(|value| value.as_i64().unwrap()).collect();
+        assert_eq!(media_box, vec![0, 0, 32, 16]);
+        let image = document.objects.values()
+            .filter_map(|object| object.as_stream().ok())
+            .find(|stream| stream.dict.get(b"Subtype").and_then(|subtype| subtype.as_name()).ok() == Some(b"Image".as_slice()))
+            .unwrap();
+        assert_eq!(image.dict.get(b"ColorSpace").unwrap().as_name().unwrap(), b"DeviceGray");
+        assert_eq!(image.content, jpeg(1));
+    }
+
+    #[test]
+    fn jpeg_to_pdf_fails_for_invalid_images() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scan.jpg");
+        std::fs::write(&image, &jpeg(3)[..15]).unwrap();
+
+        let error = jpeg_to_pdf(&image, &dir.path().join("scan.pdf")).unwrap_err();
+        assert_eq!(error.to_string(), format!("{} is no valid JPEG", image.display()));
+        assert!(!dir.path().join("scan.pdf").exists());
+    }
+
+    #[test]
+    fn missing_libreoffice_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let document = dir.path().join("letter.docx");
+        std::fs::write(&document, "PK").unwrap();
+        let text = dir.path().join("notes.txt");
+        std::fs::write(&text, "notes").unwrap();
+        let converter = Converter::new("/missing/soffice");
+
+        let error = converter.convert(&document, &ConversionConfig::default()).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "/missing/soffice not available");
+        assert_eq!(converter.convert(&text, &ConversionConfig::default()).unwrap(), None);
+    }
+}
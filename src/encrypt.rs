@@ -0,0 +1,219 @@
+use std::{path::{Path, PathBuf}, process::Command};
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EncryptionFormat {
+    #[default]
+    Age,
+    OpenPgp
+}
+
+impl EncryptionFormat {
+    /**
+     * Extension appended to the name of encrypted files
+     */
+    pub fn extension(&self) -> &'static str {
+        match self {
+            EncryptionFormat::Age => "age",
+            EncryptionFormat::OpenPgp => "gpg",
+        }
+    }
+
+    /**
+     * Format of an encrypted file by its extension, None if the file is not encrypted
+     */
+    pub fn of(path: &Path) -> Option<EncryptionFormat> {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("age") => Some(EncryptionFormat::Age),
+            Some("gpg") => Some(EncryptionFormat::OpenPgp),
+            _ => None,
+        }
+    }
+}
+
+/**
+ * Encryption of the files of a category, e.g. `{"format": "age", "recipients": ["age1..."]}`.
+ * The recipients are age public keys or OpenPGP key ids, the key of the bot should be one of them.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptionConfig {
+    #[serde(default)]
+    pub format: EncryptionFormat,
+    pub recipients: Vec<String>
+}
+
+/**
+ * Path of the encrypted file
+ */
+pub fn encrypted_path(path: &Path, format: EncryptionFormat) -> PathBuf {
+    let mut encrypted = path.as_os_str().to_owned();
+    encrypted.push(".");
+    encrypted.push(format.extension());
+    PathBuf::from(encrypted)
+}
+
+/**
+ * Path of the decrypted file, None if the file is not encrypted
+ */
+pub fn decrypted_path(path: &Path) -> Option<PathBuf> {
+    EncryptionFormat::of(path).map(|_| path.with_extension(""))
+}
+
+/**
+ * Encrypts the file for all recipients with the `age` or `gpg` binary
+ */
+pub fn encrypt(file: &Path, target: &Path, config: &EncryptionConfig) -> std::io::Result<()> {
+    if config.recipients.is_empty() {
+        return Err(std::io::Error::other("No recipients configured"));
+    }
+    let mut command = match config.format {
+        EncryptionFormat::Age => {
+            let mut command = Command::new(std::env::var("AGE").unwrap_or("age".to_string()));
+            command.arg("--encrypt");
+            for recipient in &config.recipients {
+                command.arg("--recipient").arg(recipient);
+            }
+            command
+        }
+        EncryptionFormat::OpenPgp => {
+            let mut command = Command::new("gpg");
+            command.args(["--batch", "--yes", "--trust-model", "always", "--encrypt"]);
+            for recipient in &config.recipients {
+                command.arg("--recipient").arg(recipient);
+            }
+            command
+        }
+    };
+    run(command.arg("--output").arg(target).arg(file))
+}
+
+/**
+ * Decrypts the file with the identity in `AGE_IDENTITY` or the keyring of `gpg`
+ */
+pub fn decrypt(file: &Path, target: &Path) -> std::io::Result<()> {
+    let mut command = match EncryptionFormat::of(file) {
+        Some(EncryptionFormat::Age) => {
+            let identity = std::env::var("AGE_IDENTITY")
+                .map_err(|_| std::io::Error::other("AGE_IDENTITY is not set"))?;
+            let mut command = Command::new(std::env::var("AGE").unwrap_or("age".to_string()));
+            command.arg("--decrypt").arg("--identity").arg(identity);
+            command
+        }
+        Some(EncryptionFormat::OpenPgp) => {
+            let mut command = Command::new("gpg");
+            command.args(["--batch", "--yes", "--decrypt"]);
+            command
+        }
+        None => return Err(std::io::Error::other(format!("{} is not encrypted", file.display()))),
+    };
+    run(command.arg("--output").arg(target).arg(file))
+}
+
+fn run(command: &mut Command) -> std::io::Result<()> {
+    let output = command.output().map_err(|error| match error.kind() {
+        std::io::ErrorKind::NotFound => std::io::Error::other(format!(
+            "{} is not installed", command.get_program().to_string_lossy()
+        )),
+        _ => error,
+    })?;
+    if output.status.success() {
+        return Ok(());
+    }
+    Err(std::io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::{os::unix::fs::PermissionsExt, sync::OnceLock};
+
+    /**
+     * Stand-in for `age` that prefixes the content with a header listing the recipients.
+     * It is installed once and set as `AGE` for all tests, so they may run in parallel.
+     */
+    pub(crate) fn stand_in_age() {
+        static AGE: OnceLock<PathBuf> = OnceLock::new();
+        AGE.get_or_init(|| {
+            let dir = tempfile::tempdir().unwrap().keep();
+            let path = dir.join("age");
+            std::fs::write(&path, r#"#!/bin/sh
+mode=$1; shift
+recipients=""
+while [ $# -gt 1 ]; do
+    case $1 in
+        --recipient) recipients="$recipients $2"; shift 2 ;;
+        --identity) shift 2 ;;
+        --output) output=$2; shift 2 ;;
+        *) shift ;;
+    esac
+done
+if [ "$mode" = --encrypt ]; then
+    { echo "age$recipients"; cat "$1"; } > "$output"
+elif head -n 1 "$1" | grep -q '^age'; then
+    tail -n +2 "$1" > "$output"
+else
+    echo "no identity matched any of the recipients" >&2
+    exit 1
+fi
+"#).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            std::env::set_var("AGE", &path);
+            std::env::set_var("AGE_IDENTITY", dir.join("identity"));
+            path
+        });
+    }
+
+    fn age(recipients: &[&str]) -> EncryptionConfig {
+        EncryptionConfig {
+            format: EncryptionFormat::Age,
+            recipients: recipients.iter().map(|recipient| recipient.to_string()).collect()
+        }
+    }
+
+    #[test]
+    fn encrypted_and_decrypted_paths() {
+        let encrypted = encrypted_path(Path::new("taxes/2023.pdf"), EncryptionFormat::Age);
+        assert_eq!(encrypted, PathBuf::from("taxes/2023.pdf.age"));
+        assert_eq!(EncryptionFormat::of(&encrypted), Some(EncryptionFormat::Age));
+        assert_eq!(decrypted_path(&encrypted), Some(PathBuf::from("taxes/2023.pdf")));
+        assert_eq!(encrypted_path(Path::new("key.txt"), EncryptionFormat::OpenPgp), PathBuf::from("key.txt.gpg"));
+        assert_eq!(EncryptionFormat::of(Path::new("taxes/2023.pdf")), None);
+        assert_eq!(decrypted_path(Path::new("taxes/2023.pdf")), None);
+    }
+
+    #[test]
+    fn encrypts_for_all_recipients_and_decrypts() {
+        stand_in_age();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("2023.pdf");
+        std::fs::write(&file, "tax return\n").unwrap();
+        let encrypted = encrypted_path(&file, EncryptionFormat::Age);
+
+        encrypt(&file, &encrypted, &age(&["age1alice", "age1bob"])).unwrap();
+        assert_eq!(std::fs::read_to_string(&encrypted).unwrap(), "age age1alice age1bob\ntax return\n");
+
+        let decrypted = dir.path().join("decrypted.pdf");
+        decrypt(&encrypted, &decrypted).unwrap();
+        assert_eq!(std::fs::read_to_string(decrypted).unwrap(), "tax return\n");
+    }
+
+    #[test]
+    fn reports_failures() {
+        stand_in_age();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("2023.pdf");
+        std::fs::write(&file, "tax return\n").unwrap();
+
+        let error = encrypt(&file, &dir.path().join("2023.pdf.age"), &age(&[])).unwrap_err();
+        assert_eq!(error.to_string(), "No recipients configured");
+        let error = decrypt(&file, &dir.path().join("decrypted.pdf")).unwrap_err();
+        assert_eq!(error.to_string(), format!("{} is not encrypted", file.display()));
+        // Not encrypted for the identity
+        let forged = dir.path().join("forged.pdf.age");
+        std::fs::write(&forged, "tax return\n").unwrap();
+        let error = decrypt(&forged, &dir.path().join("decrypted.pdf")).unwrap_err();
+        assert_eq!(error.to_string(), "no identity matched any of the recipients");
+    }
+}
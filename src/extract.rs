@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use crate::metadata;
+
+/**
+ * Files larger than this are not read as plain text
+ */
+const MAX_TEXT_SIZE: u64 = 10 * 1024 * 1024;
+
+/**
+ * Extracts the text of a document, None if the type is not supported or it has no text.
+ * Documents without text layer fall back to the recognized text next to them.
+ */
+pub fn extract_text(path: &Path) -> Option<String> {
+    let text = if is_pdf(path) {
+        extract_pdf_text(path)
+    } else if is_plain_text(path) {
+        std::fs::read_to_string(path).ok()
+    } else {
+        None
+    };
+    text.filter(|text| !text.trim().is_empty())
+        .or_else(|| std::fs::read_to_string(metadata::text_path(path)).ok())
+        .filter(|text| !text.trim().is_empty())
+}
+
+/**
+ * Checks the magic bytes of the file
+ */
+pub fn is_pdf(path: &Path) -> bool {
+    let mut header = [0u8; 5];
+    match std::fs::File::open(path) {
+        Ok(mut file) => std::io::Read::read_exact(&mut file, &mut header).is_ok() && &header == b"%PDF-",
+        Err(_) => false,
+    }
+}
+
+/**
+ * Checks the magic bytes for JPEG, PNG and TIFF
+ */
+pub fn is_image(path: &Path) -> bool {
+    let mut header = [0u8; 4];
+    match std::fs::File::open(path) {
+        Ok(mut file) => std::io::Read::read_exact(&mut file, &mut header).is_ok() && (
+            header[..3] == [0xFF, 0xD8, 0xFF]
+                || header == [0x89, b'P', b'N', b'G']
+                || &header == b"II*\0"
+                || &header == b"MM\0*"
+        ),
+        Err(_) => false,
+    }
+}
+
+fn is_plain_text(path: &Path) -> bool {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_lowercase());
+    let small = std::fs::metadata(path).map(|meta| meta.len() <= MAX_TEXT_SIZE).unwrap_or(false);
+    small && matches!(extension.as_deref(), Some("txt" | "md" | "csv" | "json" | "xml" | "html" | "eml"))
+}
+
+fn extract_pdf_text(path: &Path) -> Option<String> {
+    let document = match lopdf::Document::load(path) {
+        Ok(document) => document,
+        Err(error) => {
+            log::info!("Could not read pdf {}: {}", path.display(), error);
+            return None;
+        }
+    };
+    let pages: Vec<u32> = document.get_pages().keys().copied().collect();
+    match document.extract_text(&pages) {
+        Ok(text) => Some(text),
+        Err(error) => {
+            log::info!("Could not extract text of {}: {}", path.display(), error);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_file_types_by_content_and_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let named_pdf = dir.path().join("fake.pdf");
+        let jpeg = dir.path().join("scan");
+        let png = dir.path().join("scan.png");
+        let notes = dir.path().join("Notes.MD");
+        let binary = dir.path().join("data.bin");
+        std::fs::write(&named_pdf, "not a pdf").unwrap();
+        std::fs::write(&jpeg, [0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        std::fs::write(&png, [0x89, b'P', b'N', b'G', 0x0D]).unwrap();
+        std::fs::write(&notes, "notes").unwrap();
+        std::fs::write(&binary, "data").unwrap();
+
+        assert!(is_pdf(Path::new("pdf-test.pdf")));
+        assert!(!is_pdf(&named_pdf));
+        assert!(!is_pdf(&dir.path().join("missing.pdf")));
+        assert!(is_image(&jpeg));
+        assert!(is_image(&png));
+        assert!(!is_image(&named_pdf));
+        assert!(is_plain_text(&notes));
+        assert!(!is_plain_text(&binary));
+        assert!(!is_plain_text(&dir.path().join("missing.txt")));
+    }
+
+    #[test]
+    fn extracts_text_of_pdfs_and_plain_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let note = dir.path().join("note.txt");
+        std::fs::write(&note, "Rechnung 42").unwrap();
+
+        assert_eq!(extract_text(&note), Some("Rechnung 42".to_string()));
+        assert!(extract_text(Path::new("pdf-test.pdf")).unwrap().contains("Yukon"));
+    }
+
+    #[test]
+    fn falls_back_to_the_recognized_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let scan = dir.path().join("scan.jpg");
+        let empty = dir.path().join("empty.txt");
+        let unknown = dir.path().join("unknown.bin");
+        std::fs::write(&scan, [0xFF, 0xD8, 0xFF, 0xE0]).unwrap();
+        std::fs::write(&empty, "  \n").unwrap();
+        std::fs::write(&unknown, "data").unwrap();
+        std::fs::write(metadata::text_path(&scan), "scanned text").unwrap();
+        std::fs::write(metadata::text_path(&empty), "recognized").unwrap();
+
+        assert_eq!(extract_text(&scan), Some("scanned text".to_string()));
+        assert_eq!(extract_text(&empty), Some("recognized".to_string()));
+        assert_eq!(extract_text(&unknown), None);
+    }
+}
@@ -0,0 +1,425 @@
+use std::{io::Read, path::{Path, PathBuf}};
+use serde::{Serialize, Deserialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{LfsSettings, Repository};
+
+const POINTER_VERSION: &str = "https://git-lfs.github.com/spec/v1";
+
+const MEDIA_TYPE: &str = "application/vnd.git-lfs+json";
+
+/**
+ * Object of the LFS server that replaces the content of a file in git
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pointer {
+    pub oid: String,
+    pub size: u64
+}
+
+impl Pointer {
+    /**
+     * Hashes the content of the file
+     */
+    pub fn from_file(file: &Path) -> std::io::Result<Pointer> {
+        let mut reader = std::fs::File::open(file)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0u8; 64 * 1024];
+        let mut size = 0u64;
+        loop {
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            size += read as u64;
+        }
+        let oid = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+        Ok(Pointer { oid, size })
+    }
+
+    /**
+     * Content of the pointer file that is committed instead of the file
+     */
+    pub fn contents(&self) -> String {
+        format!("version {}\noid sha256:{}\nsize {}\n", POINTER_VERSION, self.oid, self.size)
+    }
+
+    /**
+     * Reads a pointer file, None if the content is no pointer
+     */
+    pub fn parse(contents: &str) -> Option<Pointer> {
+        let mut lines = contents.lines();
+        if lines.next()? != format!("version {}", POINTER_VERSION) {
+            return None;
+        }
+        let oid = lines.next()?.strip_prefix("oid sha256:")?;
+        let size = lines.next()?.strip_prefix("size ")?.parse().ok()?;
+        if oid.len() != 64 || !oid.chars().all(|c| c.is_ascii_hexdigit()) || lines.next().is_some() {
+            return None;
+        }
+        Some(Pointer { oid: oid.to_string(), size })
+    }
+}
+
+/**
+ * Checks whether the file is an LFS pointer that was not replaced by its content
+ */
+pub fn is_pointer(file: &Path) -> bool {
+    let mut contents = String::new();
+    match std::fs::File::open(file) {
+        // Pointers are smaller than 1024 bytes
+        Ok(handle) => handle.take(1024).read_to_string(&mut contents).is_ok() && Pointer::parse(&contents).is_some(),
+        Err(_) => false,
+    }
+}
+
+/**
+ * Checks whether `.gitattributes` assigns the LFS filter to the path
+ */
+pub fn is_tracked(repo: &git2::Repository, path: &Path) -> bool {
+    matches!(repo.get_attr(path, "filter", git2::AttrCheckFlags::default()), Ok(Some("lfs")))
+}
+
+/**
+ * Copies the file to the local object store, like `git lfs` does on clean
+ */
+pub fn store_local(repo: &git2::Repository, file: &Path, pointer: &Pointer) -> std::io::Result<()> {
+    let object = repo.path()
+        .join("lfs")
+        .join("objects")
+        .join(&pointer.oid[0..2])
+        .join(&pointer.oid[2..4])
+        .join(&pointer.oid);
+    if !object.exists() {
+        std::fs::create_dir_all(object.parent().unwrap())?;
+        std::fs::copy(file, object)?;
+    }
+    Ok(())
+}
+
+/**
+ * Uploads the sources of the targets that `.gitattributes` assigns to LFS, before they are added to the working tree.
+ * Blocks on the network, must not be called on a thread of the async runtime.
+ */
+pub fn upload_tracked(repo: &Repository, files: &[(PathBuf, PathBuf)]) -> Result<(), git2::Error> {
+    let git_repo = git2::Repository::open(repo.path())?;
+    let mut client = None;
+    for (source, target) in files {
+        if !is_tracked(&git_repo, target) {
+            continue;
+        }
+        let pointer = Pointer::from_file(source).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        if client.is_none() {
+            client = Some(LfsClient::new(&git_repo, &repo.settings().lfs)?);
+        }
+        client.as_ref().unwrap().upload(source, &pointer)?;
+        log::info!("[repo: {}] Uploaded {} to LFS", repo.path(), target.display());
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct BatchRequest<'a> {
+    operation: &'a str,
+    transfers: Vec<&'a str>,
+    objects: Vec<BatchObject>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct BatchObject {
+    oid: String,
+    size: u64,
+}
+
+#[derive(Deserialize)]
+struct BatchResponse {
+    objects: Vec<ResponseObject>,
+}
+
+#[derive(Deserialize)]
+struct ResponseObject {
+    #[serde(default)]
+    actions: Option<Actions>,
+    #[serde(default)]
+    error: Option<ObjectError>,
+}
+
+#[derive(Deserialize)]
+struct Actions {
+    upload: Option<Action>,
+    verify: Option<Action>,
+}
+
+#[derive(Deserialize)]
+struct Action {
+    href: String,
+    #[serde(default)]
+    header: std::collections::HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectError {
+    code: u16,
+    message: String,
+}
+
+/**
+ * Uploads objects with the basic transfer adapter of the LFS batch API
+ */
+pub struct LfsClient {
+    url: String,
+    authorization: Option<String>,
+    max_size: Option<u64>
+}
+
+impl LfsClient {
+    /**
+     * Uses the configured endpoint, the `lfs.url` of the repository or the LFS endpoint of the origin
+     */
+    pub fn new(repo: &git2::Repository, settings: &LfsSettings) -> Result<LfsClient, git2::Error> {
+        let url = match &settings.url {
+            Some(url) => url.clone(),
+            None => endpoint_of(repo)?,
+        };
+        Ok(LfsClient { url: url.trim_end_matches('/').to_string(), authorization: settings.authorization.clone(), max_size: settings.max_size })
+    }
+
+    /**
+     * Uploads the file unless the server already has it, blocks on the network
+     */
+    pub fn upload(&self, file: &Path, pointer: &Pointer) -> Result<(), git2::Error> {
+        if let Some(max_size) = self.max_size {
+            if pointer.size > max_size {
+                return Err(git2::Error::from_str(&format!(
+                    "{} has {} bytes, the LFS size limit is {} bytes",
+                    file.file_name().unwrap_or_default().to_string_lossy(), pointer.size, max_size
+                )));
+            }
+        }
+        self.upload_blocking(file, pointer)
+            .map_err(|error| git2::Error::from_str(&format!("LFS upload of {} failed: {}", file.display(), error)))
+    }
+
+    fn upload_blocking(&self, file: &Path, pointer: &Pointer) -> Result<(), String> {
+        let client = reqwest::blocking::Client::new();
+        let batch = BatchRequest {
+            operation: "upload",
+            transfers: vec!["basic"],
+            objects: vec![BatchObject { oid: pointer.oid.clone(), size: pointer.size }],
+        };
+        let mut request = client.post(format!("{}/objects/batch", self.url))
+            .header("Accept", MEDIA_TYPE)
+            .header("Content-Type", MEDIA_TYPE)
+            .body(serde_json::to_string(&batch).map_err(|e| e.to_string())?);
+        if let Some(authorization) = &self.authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let response = request.send().map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(format!("batch request returned {}", response.status()));
+        }
+        let response: BatchResponse = serde_json::from_str(&response.text().map_err(|e| e.to_string())?)
+            .map_err(|e| e.to_string())?;
+        let object = response.objects.into_iter().next().ok_or("batch response has no object")?;
+        if let Some(error) = object.error {
+            return Err(format!("{} {}", error.code, error.message));
+        }
+        let actions = match object.actions {
+            Some(actions) => actions,
+            // The server already has the object
+            None => return Ok(()),
+        };
+        if let Some(upload) = actions.upload {
+            let body = std::fs::File::open(file).map_err(|e| e.to_string())?;
+            let mut request = client.put(&upload.href).body(body);
+            for (name, value) in &upload.header {
+                request = request.header(name, value);
+            }
+            let response = request.send().map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("upload returned {}", response.status()));
+            }
+        }
+        if let Some(verify) = actions.verify {
+            let mut request = client.post(&verify.href)
+                .header("Accept", MEDIA_TYPE)
+                .header("Content-Type", MEDIA_TYPE)
+                .body(serde_json::to_string(&BatchObject { oid: pointer.oid.clone(), size: pointer.size }).map_err(|e| e.to_string())?);
+            for (name, value) in &verify.header {
+                request = request.header(name, value);
+            }
+            let response = request.send().map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("verify returned {}", response.status()));
+            }
+        }
+        Ok(())
+    }
+}
+
+/**
+ * Endpoint from `lfs.url` in the git config or `.lfsconfig`, otherwise derived from the origin
+ */
+fn endpoint_of(repo: &git2::Repository) -> Result<String, git2::Error> {
+    if let Ok(url) = repo.config()?.get_string("lfs.url") {
+        return Ok(url);
+    }
+    if let Some(workdir) = repo.workdir() {
+        let lfsconfig = workdir.join(".lfsconfig");
+        if lfsconfig.exists() {
+            if let Ok(url) = git2::Config::open(&lfsconfig).and_then(|config| config.get_string("lfs.url")) {
+                return Ok(url);
+            }
+        }
+    }
+    let origin = repo.find_remote("origin")?;
+    let url = origin.url().ok_or_else(|| git2::Error::from_str("Origin has no url"))?;
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(git2::Error::from_str(&format!("No LFS url configured and origin {} is no http url", url)));
+    }
+    let url = url.trim_end_matches('/');
+    let url = if url.ends_with(".git") { url.to_string() } else { format!("{}.git", url) };
+    Ok(format!("{}/info/lfs", url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{io::{BufRead, BufReader, Write}, net::TcpListener, thread::JoinHandle};
+    use crate::config::RepositorySettings;
+
+    /**
+     * Request line and body of a request the stub received
+     */
+    type Request = (String, String);
+
+    /**
+     * HTTP server on a local port that answers one request per connection with the next of the bodies,
+     * `{url}` in a body is replaced with the url of the server
+     */
+    fn serve(bodies: Vec<&'static str>) -> (String, JoinHandle<Vec<Request>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let server_url = url.clone();
+        let handle = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                    if let Some((name, value)) = header.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut content = vec![0u8; length];
+                reader.read_exact(&mut content).unwrap();
+                requests.push((request_line.trim().to_string(), String::from_utf8(content).unwrap()));
+                let body = body.replace("{url}", &server_url);
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+            }
+            requests
+        });
+        (url, handle)
+    }
+
+    /**
+     * Git repository that tracks PDFs with LFS at the url
+     */
+    fn repository(dir: &Path, url: &str, max_size: Option<u64>) -> Repository {
+        git2::Repository::init(dir).unwrap();
+        std::fs::write(dir.join(".gitattributes"), "*.pdf filter=lfs diff=lfs merge=lfs -text\n").unwrap();
+        let lfs = LfsSettings { url: Some(url.to_string()), authorization: Some("Basic secret".to_string()), max_size };
+        let settings = RepositorySettings { path: dir.to_string_lossy().to_string(), lfs, ..Default::default() };
+        Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string())
+    }
+
+    #[test]
+    fn pointer_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("hello.txt");
+        std::fs::write(&file, "hello\n").unwrap();
+
+        let pointer = Pointer::from_file(&file).unwrap();
+        assert_eq!(pointer.oid, "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03");
+        assert_eq!(pointer.size, 6);
+        assert_eq!(Pointer::parse(&pointer.contents()), Some(pointer.clone()));
+
+        assert!(!is_pointer(&file));
+        std::fs::write(&file, pointer.contents()).unwrap();
+        assert!(is_pointer(&file));
+
+        assert_eq!(Pointer::parse("hello\n"), None);
+        assert_eq!(Pointer::parse(&pointer.contents().replace("oid sha256:5", "oid sha256:x")), None);
+        assert_eq!(Pointer::parse(&format!("{}ext-0-foo sha256:00\n", pointer.contents())), None);
+    }
+
+    #[test]
+    fn uploads_and_verifies_tracked_files() {
+        let (url, server) = serve(vec![
+            r#"{"objects": [{"oid": "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03", "size": 6, "actions": {
+                "upload": {"href": "{url}/upload", "header": {"X-Token": "token"}},
+                "verify": {"href": "{url}/verify"}
+            }}]}"#,
+            "",
+            "",
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repository(dir.path(), &url, None);
+        let source = dir.path().join("staged");
+        std::fs::write(&source, "hello\n").unwrap();
+
+        let files = vec![(source.clone(), PathBuf::from("docs/a.pdf")), (source, PathBuf::from("docs/a.txt"))];
+        upload_tracked(&repo, &files).unwrap();
+
+        let requests = server.join().unwrap();
+        assert_eq!(requests[0].0, "POST /objects/batch HTTP/1.1");
+        let batch: serde_json::Value = serde_json::from_str(&requests[0].1).unwrap();
+        assert_eq!(batch["operation"], "upload");
+        assert_eq!(batch["objects"][0]["size"], 6);
+        assert_eq!(requests[1], ("PUT /upload HTTP/1.1".to_string(), "hello\n".to_string()));
+        assert_eq!(requests[2].0, "POST /verify HTTP/1.1");
+        assert_eq!(requests.len(), 3);
+    }
+
+    #[test]
+    fn skips_objects_the_server_has_and_reports_errors() {
+        let (url, server) = serve(vec![
+            r#"{"objects": [{"oid": "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03", "size": 6}]}"#,
+            r#"{"objects": [{"oid": "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03", "size": 6,
+                "error": {"code": 422, "message": "Object is invalid"}}]}"#,
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repository(dir.path(), &url, None);
+        let source = dir.path().join("staged");
+        std::fs::write(&source, "hello\n").unwrap();
+        let files = vec![(source, PathBuf::from("a.pdf"))];
+
+        upload_tracked(&repo, &files).unwrap();
+        let error = upload_tracked(&repo, &files).unwrap_err();
+        assert!(error.message().ends_with("failed: 422 Object is invalid"), "{}", error);
+        assert_eq!(server.join().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn rejects_files_above_the_size_limit_before_any_request() {
+        let dir = tempfile::tempdir().unwrap();
+        // Nothing listens on the port, a request would fail with another error
+        let repo = repository(dir.path(), "http://127.0.0.1:9", Some(5));
+        let source = dir.path().join("staged");
+        std::fs::write(&source, "hello\n").unwrap();
+
+        let error = upload_tracked(&repo, &[(source, PathBuf::from("a.pdf"))]).unwrap_err();
+        assert_eq!(error.message(), "staged has 6 bytes, the LFS size limit is 5 bytes");
+    }
+}
@@ -1,17 +1,29 @@
-use archivist::Archivist;
 use dotenv::dotenv;
 use teloxide::{dispatching::dialogue::InMemStorage, prelude::*};
 
-use crate::archivist::{BilloArchivist};
+use crate::archivist::{BilloArchivist, CATEGORY_CALLBACK, REMOVE_CALLBACK, REMOVE_CONFIRMATION};
+use crate::message_cache::PendingUploads;
 
 // mod bot_action;
 mod archivist;
 mod categorizer;
+mod classifier;
 mod commit_messages;
 mod config;
+mod convert;
+mod encrypt;
+mod extract;
+mod lfs;
 mod message_cache;
+mod metadata;
+#[cfg(feature = "ocr")]
+mod ocr;
 mod path_matcher;
+mod pipeline;
+mod policy;
 mod publisher;
+mod sanitize;
+mod scan;
 mod authenticate;
 
 type UploadDialogue = Dialogue<State, InMemStorage<State>>;
@@ -29,6 +41,12 @@ async fn main() {
     dotenv().ok();
     env_logger::init();
 
+    if std::env::args().any(|arg| arg == "--train") {
+        log::info!("Training classifiers...");
+        BilloArchivist::new(teloxide::Bot::new(""), PendingUploads::new()).train_classifiers();
+        return;
+    }
+
     log::info!("Starting authenticate bot...");
 
     let bot = teloxide::Bot::from_env();
@@ -36,12 +54,16 @@ async fn main() {
 
     Dispatcher::builder(
         bot, 
-        Update::filter_message()
-            .enter_dialogue::<Message, InMemStorage<State>, State>()
-            .branch(dptree::case![State::Start].endpoint(receive_caption))
-            .branch(dptree::case![State::ReceivedCaption(caption)].endpoint(receive_document))
+        dptree::entry()
+            .branch(
+                Update::filter_message()
+                    .enter_dialogue::<Message, InMemStorage<State>, State>()
+                    .branch(dptree::case![State::Start].endpoint(receive_caption))
+                    .branch(dptree::case![State::ReceivedCaption(caption)].endpoint(receive_document))
+            )
+            .branch(Update::filter_callback_query().endpoint(receive_callback))
     )
-    .dependencies(dptree::deps![InMemStorage::<State>::new()])
+    .dependencies(dptree::deps![InMemStorage::<State>::new(), PendingUploads::new()])
     .enable_ctrlc_handler()
     .build()
     .dispatch()
@@ -49,12 +71,56 @@ async fn main() {
 }
 
 
-async fn receive_caption(bot: Bot, dialogue: UploadDialogue, msg: Message) -> HandlerResult {
+async fn receive_caption(bot: Bot, dialogue: UploadDialogue, uploads: PendingUploads, msg: Message) -> HandlerResult {
     match msg.text() {
         Some(text) => {
-            if text.starts_with("/auth") {
+            if let Some(stored_at) = msg.reply_to_message().and_then(stored_path) {
+                // Re-categorize a file by replying to the bot's confirmation with new tags
+                let archivist = BilloArchivist::new(bot.clone(), uploads.clone());
+                archivist.move_document(msg.chat.id, &stored_at, text, msg.from()).await?;
+            } else if text.starts_with("/auth") {
                 bot.pin_chat_message(msg.chat.id, msg.id).await?;
-            }else {
+            } else if text.starts_with("/mv") {
+                let mut args = text.split_whitespace().skip(1);
+                let old_path = args.next();
+                let categorization = args.collect::<Vec<&str>>().join(" ");
+                match old_path {
+                    Some(old_path) if !categorization.is_empty() => {
+                        let archivist = BilloArchivist::new(bot.clone(), uploads.clone());
+                        archivist.move_document(msg.chat.id, old_path, &categorization, msg.from()).await?;
+                    }
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /mv <old path> <new path or tags>").await?;
+                    }
+                }
+            } else if text.starts_with("/rm") {
+                match text.split_once(' ') {
+                    Some((_, path)) if !path.trim().is_empty() => {
+                        let archivist = BilloArchivist::new(bot.clone(), uploads.clone());
+                        archivist.request_removal(msg.chat.id, path.trim(), msg.from()).await?;
+                    }
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /rm <path>").await?;
+                    }
+                }
+            } else if text.starts_with("/where") {
+                let caption = text.split_once(' ').map(|(_, caption)| caption.trim()).filter(|caption| !caption.is_empty());
+                let archivist = BilloArchivist::new(bot.clone(), uploads.clone());
+                archivist.where_document(msg.chat.id, caption).await?;
+            } else if text.starts_with("/get") {
+                match text.split_once(' ') {
+                    Some((_, path)) if !path.trim().is_empty() => {
+                        let archivist = BilloArchivist::new(bot.clone(), uploads.clone());
+                        archivist.get_document(msg.chat.id, path.trim(), msg.from()).await?;
+                    }
+                    _ => {
+                        bot.send_message(msg.chat.id, "Usage: /get <path>").await?;
+                    }
+                }
+            } else if text.starts_with("/undo") {
+                let archivist = BilloArchivist::new(bot.clone(), uploads.clone());
+                archivist.undo(msg.chat.id, msg.from()).await?;
+            } else {
                 dialogue.update(State::ReceivedCaption(text.into())).await?;
                 log::info!("Received text: {}", text);
             }
@@ -66,12 +132,12 @@ async fn receive_caption(bot: Bot, dialogue: UploadDialogue, msg: Message) -> Ha
     match msg.document() {
         // Upload directly if only document
         Some(doc) => {
-            let archivist = BilloArchivist::new(bot);
+            let archivist = BilloArchivist::new(bot, uploads);
             if msg.caption().is_some(){
                 let cap = msg.caption().unwrap();
-                archivist.upload_document(msg.chat.id, doc, Some(&cap.to_string())).await?;
+                archivist.upload_document(msg.chat.id, msg.id, doc, Some(&cap.to_string()), msg.from()).await?;
             } else {
-                archivist.upload_document(msg.chat.id, doc, None).await?;
+                archivist.upload_document(msg.chat.id, msg.id, doc, None, msg.from()).await?;
             }
         }
         None => {
@@ -83,11 +149,11 @@ async fn receive_caption(bot: Bot, dialogue: UploadDialogue, msg: Message) -> Ha
 }
 
 
-async fn receive_document(bot: Bot, dialogue: UploadDialogue, caption: String, msg: Message) -> HandlerResult {
+async fn receive_document(bot: Bot, dialogue: UploadDialogue, uploads: PendingUploads, caption: String, msg: Message) -> HandlerResult {
     match msg.document() {
         Some(doc) => {
-            let archivist = BilloArchivist::new(bot);
-            archivist.upload_document(msg.chat.id, doc, Some(&caption)).await?;
+            let archivist = BilloArchivist::new(bot, uploads);
+            archivist.upload_document(msg.chat.id, msg.id, doc, Some(&caption), msg.from()).await?;
         }
         None => {
             log::info!("No document in message");
@@ -97,3 +163,48 @@ async fn receive_document(bot: Bot, dialogue: UploadDialogue, caption: String, m
 
     Ok(())
 }
+
+async fn receive_callback(bot: Bot, uploads: PendingUploads, q: CallbackQuery) -> HandlerResult {
+    bot.answer_callback_query(q.id.clone()).await?;
+    let (data, msg) = match (q.data.as_ref(), q.message.as_ref()) {
+        (Some(data), Some(msg)) => (data, msg),
+        _ => return Ok(()),
+    };
+
+    if let Some(answer) = data.strip_prefix(REMOVE_CALLBACK) {
+        // Remove the keyboard so the removal can only be confirmed once
+        bot.edit_message_reply_markup(msg.chat.id, msg.id).await?;
+        let path = msg.text().and_then(|text| text.strip_prefix(REMOVE_CONFIRMATION));
+        match (answer, path) {
+            ("yes", Some(path)) => {
+                let archivist = BilloArchivist::new(bot, uploads);
+                archivist.remove_document(msg.chat.id, path, Some(&q.from)).await?;
+            }
+            _ => {
+                bot.send_message(msg.chat.id, "Removal cancelled").await?;
+            }
+        }
+    } else if let Some(choice) = data.strip_prefix(CATEGORY_CALLBACK) {
+        // Remove the keyboard so the document is only uploaded once
+        bot.edit_message_reply_markup(msg.chat.id, msg.id).await?;
+        // The picker replies to the upload, its sender is the author
+        let sender = msg.reply_to_message().and_then(|upload| upload.from());
+        let archivist = BilloArchivist::new(bot, uploads);
+        archivist.upload_picked_document(msg.chat.id, msg.id, choice, sender).await?;
+    }
+
+    Ok(())
+}
+
+/**
+ * Extracts the path from a "File stored at" message of the bot
+ */
+fn stored_path(msg: &Message) -> Option<String> {
+    if !msg.from().map(|user| user.is_bot).unwrap_or(false) {
+        return None;
+    }
+    msg.text()?
+        .lines()
+        .find_map(|line| line.strip_prefix("File stored at "))
+        .map(|path| path.trim().to_string())
+}
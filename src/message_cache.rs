@@ -5,6 +5,10 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use teloxide::types::MessageId;
+
+use crate::{categorizer::Categorized, pipeline::PipelineFile};
 
 pub trait MessageCache {
     fn lock_chat(&mut self, chat_id: ChatId);
@@ -113,3 +117,149 @@ impl MessageCache for AsyncInMemoryMessageCache {
         }
     }
 }
+
+
+/**
+ * Pending uploads are dropped after an hour, their staged files are removed
+ */
+const UPLOAD_TTL: Duration = Duration::from_secs(60 * 60);
+
+/**
+ * Most uploads waiting at once, the oldest are dropped first
+ */
+const MAX_PENDING_UPLOADS: usize = 100;
+
+/**
+ * Upload that waits for the user to pick a category
+ */
+#[derive(Debug)]
+pub struct PendingUpload {
+    pub caption: Option<String>,
+    /**
+     * Categories in the order they were offered, the callback data holds the index
+     */
+    pub categories: Vec<Categorized>,
+    /**
+     * Downloaded file, it is stored once a category is picked
+     */
+    pub staged: PipelineFile
+}
+
+/**
+ * Pending uploads keyed by the chat and the id of the message with the category picker
+ */
+#[derive(Clone)]
+pub struct PendingUploads {
+    cache: Arc<Mutex<HashMap<(ChatId, MessageId), (Instant, PendingUpload)>>>,
+    ttl: Duration,
+    capacity: usize
+}
+
+impl PendingUploads {
+    pub fn new() -> PendingUploads {
+        PendingUploads::with_limits(UPLOAD_TTL, MAX_PENDING_UPLOADS)
+    }
+
+    pub fn with_limits(ttl: Duration, capacity: usize) -> PendingUploads {
+        PendingUploads { cache: Arc::new(Mutex::new(HashMap::new())), ttl, capacity }
+    }
+
+    pub fn put(&self, chat_id: ChatId, message_id: MessageId, upload: PendingUpload) {
+        log::info!("[chat: {}] Caching upload for message {}", chat_id, message_id);
+        let mut cache = self.cache.lock().unwrap();
+        let now = Instant::now();
+        let expired: Vec<(ChatId, MessageId)> = cache.iter()
+            .filter(|(_, (added, _))| now.duration_since(*added) >= self.ttl)
+            .map(|(key, _)| *key)
+            .collect();
+        for key in expired {
+            log::info!("[chat: {}] Upload for message {} expired", key.0, key.1);
+            Self::drop_upload(cache.remove(&key));
+        }
+        while cache.len() >= self.capacity.max(1) {
+            let oldest = cache.iter().min_by_key(|(key, (added, _))| (*added, key.1 .0)).map(|(key, _)| *key);
+            match oldest {
+                Some(key) => {
+                    log::info!("[chat: {}] Dropping upload for message {}, too many uploads are pending", key.0, key.1);
+                    Self::drop_upload(cache.remove(&key));
+                }
+                None => break,
+            }
+        }
+        cache.insert((chat_id, message_id), (now, upload));
+    }
+
+    pub fn pop(&self, chat_id: ChatId, message_id: MessageId) -> Option<PendingUpload> {
+        let upload = self.cache.lock().unwrap().remove(&(chat_id, message_id));
+        match upload {
+            Some((added, upload)) if added.elapsed() < self.ttl => Some(upload),
+            Some(expired) => {
+                log::info!("[chat: {}] Upload for message {} expired", chat_id, message_id);
+                Self::drop_upload(Some(expired));
+                None
+            }
+            None => {
+                log::info!("[chat: {}] No upload cached for message {}", chat_id, message_id);
+                None
+            }
+        }
+    }
+
+    fn drop_upload(upload: Option<(Instant, PendingUpload)>) {
+        if let Some((_, upload)) = upload {
+            upload.staged.remove();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::categorizer::CategorySource;
+
+    fn upload(dir: &std::path::Path, name: &str) -> PendingUpload {
+        let file = dir.join(name);
+        std::fs::write(&file, "%PDF-1.4").unwrap();
+        PendingUpload {
+            caption: None,
+            categories: vec![Categorized::new("invoices/+".to_string(), CategorySource::Default)],
+            staged: PipelineFile::new(&file, name, None),
+        }
+    }
+
+    #[test]
+    fn pops_an_upload_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = PendingUploads::new();
+        uploads.put(ChatId(1), MessageId(10), upload(dir.path(), "a.pdf"));
+
+        assert!(uploads.pop(ChatId(2), MessageId(10)).is_none());
+        let pending = uploads.pop(ChatId(1), MessageId(10)).unwrap();
+        assert_eq!(pending.staged.file, dir.path().join("a.pdf"));
+        assert!(uploads.pop(ChatId(1), MessageId(10)).is_none());
+    }
+
+    #[test]
+    fn expired_uploads_are_dropped_with_their_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = PendingUploads::with_limits(Duration::ZERO, 10);
+        uploads.put(ChatId(1), MessageId(10), upload(dir.path(), "a.pdf"));
+
+        assert!(uploads.pop(ChatId(1), MessageId(10)).is_none());
+        assert!(!dir.path().join("a.pdf").exists());
+    }
+
+    #[test]
+    fn oldest_uploads_are_dropped_beyond_the_capacity() {
+        let dir = tempfile::tempdir().unwrap();
+        let uploads = PendingUploads::with_limits(UPLOAD_TTL, 2);
+        for (id, name) in [(10, "a.pdf"), (11, "b.pdf"), (12, "c.pdf")] {
+            uploads.put(ChatId(1), MessageId(id), upload(dir.path(), name));
+        }
+
+        assert!(!dir.path().join("a.pdf").exists());
+        assert!(uploads.pop(ChatId(1), MessageId(10)).is_none());
+        assert!(uploads.pop(ChatId(1), MessageId(11)).is_some());
+        assert!(uploads.pop(ChatId(1), MessageId(12)).is_some());
+    }
+}
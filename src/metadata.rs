@@ -0,0 +1,269 @@
+use std::path::{Path, PathBuf};
+use lopdf::{Dictionary, Document, Object};
+use regex::Regex;
+use serde::{Serialize, Deserialize};
+
+use crate::extract;
+
+/**
+ * Suffix of the sidecar file that is stored next to an archived file
+ */
+pub const SIDECAR_SUFFIX: &str = ".meta.json";
+
+/**
+ * Suffix of the file with the recognized text of a scanned document
+ */
+pub const TEXT_SUFFIX: &str = ".ocr.txt";
+
+/**
+ * Only the first matches in the text are recorded
+ */
+const MAX_MATCHES: usize = 20;
+
+/**
+ * Metadata of an archived PDF, stored as `<file>.meta.json` next to it
+ */
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub subject: Option<String>,
+    pub page_count: usize,
+    /**
+     * Creation date of the document as `YYYY-MM-DDTHH:MM:SS`, as far as it is known
+     */
+    pub created: Option<String>,
+    /**
+     * Dates found in the text as `YYYY-MM-DD`
+     */
+    pub dates: Vec<String>,
+    /**
+     * Amounts of money found in the text as written
+     */
+    pub amounts: Vec<String>,
+    pub caption: Option<String>,
+    /**
+     * Tags of the category the file was stored in
+     */
+    pub tags: Vec<String>,
+}
+
+/**
+ * Path of the sidecar of a file
+ */
+pub fn sidecar_path(path: &Path) -> PathBuf {
+    with_suffix(path, SIDECAR_SUFFIX)
+}
+
+/**
+ * Path of the recognized text of a file
+ */
+pub fn text_path(path: &Path) -> PathBuf {
+    with_suffix(path, TEXT_SUFFIX)
+}
+
+/**
+ * Paths of all files that accompany a file
+ */
+pub fn sidecars(path: &Path) -> Vec<PathBuf> {
+    vec![sidecar_path(path), text_path(path)]
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(suffix);
+    PathBuf::from(sidecar)
+}
+
+pub fn is_sidecar(name: &str) -> bool {
+    name.ends_with(SIDECAR_SUFFIX) || name.ends_with(TEXT_SUFFIX)
+}
+
+/**
+ * Reads the metadata of a PDF, None if the file is no readable PDF
+ */
+pub fn extract_metadata(path: &Path, caption: Option<&str>, tags: &[String]) -> Option<Metadata> {
+    if !extract::is_pdf(path) {
+        return None;
+    }
+    let document = match Document::load(path) {
+        Ok(document) => document,
+        Err(error) => {
+            log::info!("Could not read pdf {}: {}", path.display(), error);
+            return None;
+        }
+    };
+    let info = info_dictionary(&document);
+    let text = extract::extract_text(path).unwrap_or_default();
+    Some(Metadata {
+        title: info.and_then(|info| info_string(info, b"Title")),
+        author: info.and_then(|info| info_string(info, b"Author")),
+        subject: info.and_then(|info| info_string(info, b"Subject")),
+        page_count: document.get_pages().len(),
+        created: info.and_then(|info| info_string(info, b"CreationDate")).and_then(|date| parse_pdf_date(&date)),
+        dates: find_dates(&text),
+        amounts: find_amounts(&text),
+        caption: caption.map(|caption| caption.to_string()),
+        tags: tags.to_vec(),
+    })
+}
+
+/**
+ * Writes the metadata next to the file and returns the path of the sidecar
+ */
+pub fn write_sidecar(path: &Path, metadata: &Metadata) -> std::io::Result<PathBuf> {
+    let sidecar = sidecar_path(path);
+    std::fs::write(&sidecar, serde_json::to_string_pretty(metadata)? + "\n")?;
+    Ok(sidecar)
+}
+
+fn info_dictionary(document: &Document) -> Option<&Dictionary> {
+    match document.trailer.get(b"Info").ok()? {
+        Object::Reference(id) => document.get_dictionary(*id).ok(),
+        Object::Dictionary(dictionary) => Some(dictionary),
+        _ => None,
+    }
+}
+
+fn info_string(info: &Dictionary, key: &[u8]) -> Option<String> {
+    info.get(key).ok()
+        .and_then(|value| lopdf::decode_text_string(value).ok())
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/**
+ * Converts a PDF date like `D:20230412103000+02'00'` to `2023-04-12T10:30:00`
+ */
+fn parse_pdf_date(date: &str) -> Option<String> {
+    let digits: String = date.trim_start_matches("D:").chars().take_while(|c| c.is_ascii_digit()).collect();
+    if digits.len() < 8 {
+        return None;
+    }
+    let part = |from: usize, to: usize, default: &'static str| digits.get(from..to).unwrap_or(default).to_string();
+    Some(format!(
+        "{}-{}-{}T{}:{}:{}",
+        part(0, 4, "0000"), part(4, 6, "01"), part(6, 8, "01"),
+        part(8, 10, "00"), part(10, 12, "00"), part(12, 14, "00")
+    ))
+}
+
+/**
+ * Dates written as `2023-04-12`, `12.04.2023` or `12/04/2023`
+ */
+fn find_dates(text: &str) -> Vec<String> {
+    let iso = Regex::new(r"\b(\d{4})-(\d{2})-(\d{2})\b").unwrap();
+    let european = Regex::new(r"\b(\d{1,2})[./](\d{1,2})[./](\d{4})\b").unwrap();
+    let dates = iso.captures_iter(text)
+        .map(|captures| (captures[1].to_string(), captures[2].to_string(), captures[3].to_string()))
+        .chain(european.captures_iter(text)
+            .map(|captures| (captures[3].to_string(), format!("{:0>2}", &captures[2]), format!("{:0>2}", &captures[1]))))
+        .filter(|(year, month, day)| chrono::NaiveDate::from_ymd_opt(
+            year.parse().unwrap_or(0), month.parse().unwrap_or(0), day.parse().unwrap_or(0)).is_some())
+        .map(|(year, month, day)| format!("{}-{}-{}", year, month, day));
+    unique(dates)
+}
+
+/**
+ * Amounts with a currency before or after them, e.g. `€ 12,50` or `1.234,00 EUR`
+ */
+fn find_amounts(text: &str) -> Vec<String> {
+    let amount = Regex::new(
+        r"(?:[€$£]|\b(?:EUR|USD|CHF|GBP))\s?\d{1,3}(?:[.,' ]?\d{3})*(?:[.,]\d{2})?\b|\b\d{1,3}(?:[.,' ]?\d{3})*[.,]\d{2}\s?(?:[€$£]|(?:EUR|USD|CHF|GBP)\b)"
+    ).unwrap();
+    unique(amount.find_iter(text).map(|found| found.as_str().to_string()))
+}
+
+fn unique(values: impl Iterator<Item = String>) -> Vec<String> {
+    let mut found: Vec<String> = Vec::new();
+    for value in values {
+        if !found.contains(&value) {
+            found.push(value);
+        }
+        if found.len() == MAX_MATCHES {
+            break;
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_pdf_date_fills_missing_parts() {
+        assert_eq!(parse_pdf_date("D:20230412103000+02'00'"), Some("2023-04-12T10:30:00".to_string()));
+        assert_eq!(parse_pdf_date("D:20230412"), Some("2023-04-12T00:00:00".to_string()));
+        assert_eq!(parse_pdf_date("2023041210"), Some("2023-04-12T10:00:00".to_string()));
+        assert_eq!(parse_pdf_date("D:2023"), None);
+        assert_eq!(parse_pdf_date("D:2023-04-12"), None);
+        assert_eq!(parse_pdf_date(""), None);
+    }
+
+    #[test]
+    fn find_dates_skips_impossible_dates() {
+        let text = "Invoice of 2023-04-12, due 3.5.2023 or 12/04/2023. Not 2023-02-30, 31.04.2023 or 2023-13-01.";
+        assert_eq!(find_dates(text), vec!["2023-04-12", "2023-05-03"]);
+        assert_eq!(find_dates("order 12345-67-89, version 1.2.3"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn find_amounts_requires_a_currency() {
+        let text = "Total € 12,50 plus 1.234,00 EUR and $5 or USD 1,000.00, again € 12,50";
+        assert_eq!(find_amounts(text), vec!["€ 12,50", "1.234,00 EUR", "$5", "USD 1,000.00"]);
+        assert_eq!(find_amounts("12,50 and EURO 5 and 3,5 €"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn find_dates_records_the_first_matches_only() {
+        let text: Vec<String> = (1..=28).map(|day| format!("2023-01-{:02}", day)).collect();
+        let dates = find_dates(&text.join(" "));
+        assert_eq!(dates.len(), MAX_MATCHES);
+        assert_eq!(dates.last().map(|date| date.as_str()), Some("2023-01-20"));
+    }
+
+    #[test]
+    fn extracts_the_metadata_of_a_pdf() {
+        let tags = vec!["manual".to_string()];
+        let metadata = extract_metadata(Path::new("pdf-test.pdf"), Some("test page"), &tags).unwrap();
+
+        assert_eq!(metadata, Metadata {
+            title: Some("PDF Test Page".to_string()),
+            author: Some("Yukon Department of Education".to_string()),
+            subject: None,
+            page_count: 1,
+            created: Some("2008-06-04T15:44:00".to_string()),
+            dates: Vec::new(),
+            amounts: Vec::new(),
+            caption: Some("test page".to_string()),
+            tags,
+        });
+    }
+
+    #[test]
+    fn extract_metadata_ignores_unreadable_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let text = dir.path().join("notes.txt");
+        std::fs::write(&text, "2023-04-12 € 12,50").unwrap();
+        let truncated = dir.path().join("truncated.pdf");
+        std::fs::write(&truncated, &std::fs::read("pdf-test.pdf").unwrap()[..200]).unwrap();
+
+        assert_eq!(extract_metadata(&text, None, &[]), None);
+        assert_eq!(extract_metadata(&truncated, None, &[]), None);
+    }
+
+    #[test]
+    fn writes_the_sidecar_next_to_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("scan.pdf");
+        let metadata = Metadata { title: Some("Scan".to_string()), page_count: 2, ..Default::default() };
+
+        let sidecar = write_sidecar(&file, &metadata).unwrap();
+        assert_eq!(sidecar, dir.path().join("scan.pdf.meta.json"));
+        assert!(is_sidecar(&sidecar.to_string_lossy()));
+        let written: Metadata = serde_json::from_str(&std::fs::read_to_string(sidecar).unwrap()).unwrap();
+        assert_eq!(written, metadata);
+    }
+}
@@ -0,0 +1,176 @@
+use std::{path::{Path, PathBuf}, process::Command};
+
+use crate::{extract, metadata};
+
+/**
+ * Recognizes the text of scanned documents with a local Tesseract binary.
+ * The binary is configurable, so a stand-in script can be used offline.
+ */
+pub struct Tesseract {
+    binary: String,
+    languages: String
+}
+
+impl Tesseract {
+    pub fn new(binary: &str, languages: &str) -> Tesseract {
+        Tesseract { binary: binary.to_string(), languages: languages.to_string() }
+    }
+
+    pub fn from_env() -> Tesseract {
+        let binary = std::env::var("TESSERACT").unwrap_or("tesseract".to_string());
+        let languages = std::env::var("OCR_LANGUAGES").unwrap_or("eng".to_string());
+        Tesseract::new(&binary, &languages)
+    }
+
+    /**
+     * Images and PDFs without text layer need to be recognized
+     */
+    pub fn needs_recognition(path: &Path) -> bool {
+        extract::is_image(path) || (extract::is_pdf(path) && extract::extract_text(path).is_none())
+    }
+
+    /**
+     * Recognizes the text of the file and stores it next to it, returns the path of the text
+     * or None if no text was found
+     */
+    pub fn write_text(&self, path: &Path) -> std::io::Result<Option<PathBuf>> {
+        let text = self.recognize(path)?;
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+        let text_path = metadata::text_path(path);
+        std::fs::write(&text_path, text)?;
+        Ok(Some(text_path))
+    }
+
+    /**
+     * Recognizes the text of an image or of the images embedded in a PDF
+     */
+    pub fn recognize(&self, path: &Path) -> std::io::Result<String> {
+        if !extract::is_pdf(path) {
+            return self.recognize_image(path);
+        }
+        let images = extract_images(path)?;
+        let mut pages = Vec::new();
+        for image in &images {
+            let text = self.recognize_image(image);
+            let _ = std::fs::remove_file(image);
+            pages.push(text?);
+        }
+        Ok(pages.join("\n"))
+    }
+
+    fn recognize_image(&self, image: &Path) -> std::io::Result<String> {
+        let output = Command::new(&self.binary)
+            .arg(image)
+            .arg("stdout")
+            .arg("-l")
+            .arg(&self.languages)
+            .output()?;
+        if !output.status.success() {
+            return Err(std::io::Error::other(format!(
+                "{} failed: {}", self.binary, String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+/**
+ * Writes the JPEG and JPEG 2000 images of all pages next to the PDF, in page order
+ */
+fn extract_images(path: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let document = lopdf::Document::load(path).map_err(std::io::Error::other)?;
+    let mut images = Vec::new();
+    for (page, page_id) in document.get_pages() {
+        let page_images = document.get_page_images(page_id).unwrap_or_default();
+        for (i, image) in page_images.iter().enumerate() {
+            let extension = match image.filters.as_deref() {
+                Some([filter]) if filter == "DCTDecode" => "jpg",
+                Some([filter]) if filter == "JPXDecode" => "jp2",
+                _ => {
+                    log::info!("Skipping image {} on page {} of {}, unsupported encoding", i, page, path.display());
+                    continue;
+                }
+            };
+            let mut image_path = path.as_os_str().to_owned();
+            image_path.push(format!(".page{}-{}.{}", page, i, extension));
+            let image_path = PathBuf::from(image_path);
+            std::fs::write(&image_path, image.content)?;
+            images.push(image_path);
+        }
+    }
+    Ok(images)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use crate::convert::{ConversionConfig, Converter};
+
+    /**
+     * Smallest JPEG the converter accepts, a start of frame of 16x16 pixels
+     */
+    const JPEG: [u8; 23] = [
+        0xFF, 0xD8, 0xFF, 0xC0, 0x00, 0x11, 0x08, 0x00, 0x10, 0x00, 0x10, 0x03,
+        0x01, 0x22, 0x00, 0x02, 0x11, 0x01, 0x03, 0x11, 0x01, 0xFF, 0xD9,
+    ];
+
+    fn fake_tesseract(dir: &Path, script: &str) -> String {
+        let path = dir.join("tesseract");
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        path.to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn writes_the_text_recognized_by_the_binary_from_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scan.jpg");
+        std::fs::write(&image, JPEG).unwrap();
+        std::env::set_var("TESSERACT", fake_tesseract(dir.path(), r#"echo "text of $(basename "$1") in $4""#));
+        std::env::set_var("OCR_LANGUAGES", "deu+eng");
+
+        assert!(Tesseract::needs_recognition(&image));
+        let text = Tesseract::from_env().write_text(&image).unwrap().unwrap();
+        assert_eq!(text, metadata::text_path(&image));
+        assert_eq!(std::fs::read_to_string(text).unwrap(), "text of scan.jpg in deu+eng\n");
+    }
+
+    #[test]
+    fn recognizes_the_images_of_a_pdf() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scan.jpg");
+        std::fs::write(&image, JPEG).unwrap();
+        let pdf = Converter::new("soffice").convert(&image, &ConversionConfig::default()).unwrap().unwrap();
+        let tesseract = Tesseract::new(&fake_tesseract(dir.path(), r#"echo "text of $(basename "$1")""#), "eng");
+
+        assert!(Tesseract::needs_recognition(&pdf));
+        assert_eq!(tesseract.recognize(&pdf).unwrap(), "text of scan.pdf.page1-0.jpg\n");
+        // The extracted images are removed again
+        let mut entries: Vec<String> = std::fs::read_dir(dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, vec!["scan.jpg", "scan.pdf", "tesseract"]);
+    }
+
+    #[test]
+    fn reports_failures_and_missing_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let image = dir.path().join("scan.jpg");
+        std::fs::write(&image, JPEG).unwrap();
+
+        let failing = Tesseract::new(&fake_tesseract(dir.path(), "echo 'unknown language' >&2; exit 1"), "xyz");
+        let error = failing.write_text(&image).unwrap_err();
+        assert!(error.to_string().ends_with("failed: unknown language"), "{}", error);
+
+        let empty = Tesseract::new(&fake_tesseract(dir.path(), "echo '  '"), "eng");
+        assert_eq!(empty.write_text(&image).unwrap(), None);
+        assert!(!metadata::text_path(&image).exists());
+
+        let missing = Tesseract::new(&dir.path().join("missing").to_string_lossy(), "eng");
+        assert!(missing.write_text(&image).is_err());
+    }
+}
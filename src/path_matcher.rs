@@ -1,5 +1,8 @@
-use regex::{Regex,Captures};
-use crate::config::Repository;
+use std::{cmp::Ordering, collections::HashMap, fmt::Write, path::Path, sync::OnceLock};
+use chrono::{format::{Item, StrftimeItems}, Local, NaiveDate, NaiveDateTime, TimeZone};
+use regex::Regex;
+use teloxide::utils::html;
+use crate::{config::{Repository, RuleConfig}, metadata};
 
 
 #[derive(Debug)]
@@ -12,7 +15,19 @@ pub struct RuleContext<'a> {
     /**
      * Index of the element in the path
      */
-    index: usize
+    index: usize,
+    /**
+     * Already resolved elements before the current one
+     */
+    resolved: &'a [String],
+    /**
+     * Date of the file, e.g. when a photo was taken, the current date if unknown
+     */
+    date: Option<NaiveDateTime>,
+    /**
+     * Extension of the stored file without the dot, if known
+     */
+    extension: Option<&'a str>
 }
 
 impl<'a> RuleContext<'a>{
@@ -21,112 +36,1253 @@ impl<'a> RuleContext<'a>{
     }
 
     fn until_current(&self) -> String {
-        self.path[0..self.index].join("/")
+        self.resolved.join("/")
+    }
+
+    /**
+     * Reads the names of the entries of the directory the current element is located in
+     */
+    fn read_entries(&self, only_dirs: bool) -> Result<Vec<String>, PathError> {
+        let dir = self.until_current();
+        let paths = match std::fs::read_dir(Path::new(self.repo.path()).join(&dir)) {
+            Ok(paths) => paths,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Err(PathError::MissingDirectory(dir)),
+            Err(error) => return Err(PathError::UnreadableDirectory(dir, error.to_string())),
+        };
+        Ok(paths
+            .filter_map(|dir_entry| dir_entry.ok())
+            .filter(|dir_entry| !only_dirs || dir_entry.file_type().map(|t| t.is_dir()).unwrap_or(false))
+            .filter_map(|dir_entry| dir_entry.file_name().into_string().ok())
+            .filter(|name| !metadata::is_sidecar(name))
+            .collect())
     }
 
     fn is_last(&self) -> bool {
        return self.index == self.path.len() - 1
     }
+
+    /**
+     * Extension a generated file name should end with, `known` if the name already has one
+     */
+    fn file_extension(&self, known: &str) -> String {
+        match self.extension {
+            Some(extension) if known.is_empty() && self.is_last() && !extension.is_empty() => format!(".{}", extension),
+            _ => known.to_string(),
+        }
+    }
 }
 
-pub trait PathRule {
-    fn resolve(&self, context: &RuleContext) -> String;
+pub trait PathRule: Send + Sync {
+    /**
+     * Name of the rule as used in the configuration
+     */
+    fn name(&self) -> &str;
+
+    /**
+     * Resolves the current element, None if the element is not resolvable by this rule
+     */
+    fn resolve(&self, context: &RuleContext) -> Result<Option<String>, PathError>;
+
+    /**
+     * Prefixes of the segments the rule resolves, the prefixes of a matcher must not overlap
+     */
+    fn tokens(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /**
+     * Tokens of segments that may contain `/` up to a closing `/`, e.g. `~` of `~/<regex>/`
+     */
+    fn delimited_tokens(&self) -> Vec<String> {
+        Vec::new()
+    }
 }
 
+/**
+ * Keeps the element as it is
+ */
 pub struct DefaultRule{}
 
 impl PathRule for DefaultRule {
-   fn resolve(&self, context: &RuleContext) -> String {
-        context.current().clone()
-   }
+    fn name(&self) -> &str {
+        "default"
+    }
+
+    fn resolve(&self, context: &RuleContext) -> Result<Option<String>, PathError> {
+        Ok(Some(context.current().clone()))
+    }
 }
 
-pub struct LatestRule<N: PathRule> {
-    next: N
+/**
+ * Replaces `{date:<format>}` with the current date in the strftime format
+ */
+pub struct DateRule {
+    token: String
+}
+
+impl DateRule {
+    pub fn new() -> DateRule {
+        DateRule::with_token("date")
+    }
+
+    pub fn with_token(token: &str) -> DateRule {
+        DateRule { token: token.to_string() }
+    }
+}
+
+impl PathRule for DateRule {
+    fn name(&self) -> &str {
+        "date"
+    }
+
+    fn resolve(&self, context: &RuleContext) -> Result<Option<String>, PathError> {
+        let placeholder = Regex::new(&format!(r"\{{{}:([^}}]*)\}}", regex::escape(&self.token))).unwrap();
+        if !placeholder.is_match(context.current()) {
+            return Ok(None);
+        }
+        // With the time zone the date can be formatted with offset specifiers like `%z`
+        let now = match context.date {
+            Some(date) => Local.from_local_datetime(&date).earliest().unwrap_or_else(|| Local.from_utc_datetime(&date)),
+            None => Local::now(),
+        };
+        let mut invalid = false;
+        let resolved = placeholder.replace_all(context.current(), |captures: &regex::Captures| {
+            let format = &captures[1];
+            let mut formatted = String::new();
+            if StrftimeItems::new(format).any(|item| item == Item::Error) || write!(formatted, "{}", now.format(format)).is_err() {
+                invalid = true;
+                return String::new();
+            }
+            formatted
+        });
+        if invalid {
+            return Err(PathError::InvalidPattern(context.current().clone()));
+        }
+        Ok(Some(resolved.to_string()))
+    }
+
+    fn tokens(&self) -> Vec<String> {
+        vec![format!("{{{}:", self.token)]
+    }
+}
+
+/**
+ * Resolves `^` to the latest entry of the directory
+ */
+pub struct LatestRule {
+    token: String,
+    regex_token: String
+}
+
+/**
+ * Order in which the latest rule compares the entries of a directory
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EntryOrder {
+    /**
+     * Plain comparison of the names (`^`)
+     */
+    Lexicographic,
+    /**
+     * Numbers within the names are compared by value (`^n`)
+     */
+    Natural,
+    /**
+     * Dates within the names are compared (`^d`)
+     */
+    Date,
+    /**
+     * Time of the latest commit that touched the entry (`^t`)
+     */
+    CommitTime,
+    /**
+     * Modification time in the filesystem (`^m`)
+     */
+    ModificationTime
+}
+
+impl EntryOrder {
+    fn from_flag(flag: &str) -> Option<EntryOrder> {
+        match flag {
+            "" => Some(EntryOrder::Lexicographic),
+            "n" => Some(EntryOrder::Natural),
+            "d" => Some(EntryOrder::Date),
+            "t" => Some(EntryOrder::CommitTime),
+            "m" => Some(EntryOrder::ModificationTime),
+            _ => None,
+        }
+    }
+
+    /**
+     * Returns the greatest of the entries in `dir` (relative to the repository)
+     */
+    fn latest(&self, repo: &Repository, dir: &str, entries: Vec<String>) -> Option<String> {
+        match self {
+            EntryOrder::Lexicographic => entries.into_iter().max(),
+            EntryOrder::Natural => entries.into_iter().max_by(|a, b| natural_cmp(a, b)),
+            EntryOrder::Date => entries.into_iter()
+                .max_by(|a, b| parse_date(a).cmp(&parse_date(b)).then_with(|| natural_cmp(a, b))),
+            EntryOrder::CommitTime => {
+                let times = commit_times(repo, dir).unwrap_or_default();
+                entries.into_iter()
+                    .max_by_key(|entry| (times.get(entry).copied().unwrap_or(i64::MIN), entry.clone()))
+            }
+            EntryOrder::ModificationTime => entries.into_iter()
+                .max_by_key(|entry| {
+                    let modified = std::fs::metadata(Path::new(repo.path()).join(dir).join(entry))
+                        .and_then(|meta| meta.modified())
+                        .ok();
+                    (modified, entry.clone())
+                }),
+        }
+    }
+}
+
+impl LatestRule {
+    pub fn new() -> LatestRule {
+        LatestRule::with_tokens("^", "~")
+    }
+
+    pub fn with_tokens(token: &str, regex_token: &str) -> LatestRule {
+        LatestRule { token: token.to_string(), regex_token: regex_token.to_string() }
+    }
+
+    /**
+     * Returns the order and the filter for the entries if the segment is resolvable by this rule.
+     * Supported are `^` (all entries), `^<glob>` and `~/<regex>/`, an order can be selected
+     * with a flag like `^n`, `^n:<glob>` or `~n/<regex>/`.
+     */
+    fn parse_segment(&self, segment: &str) -> Result<Option<(EntryOrder, Option<Regex>)>, PathError> {
+        if let Some(rest) = segment.strip_prefix(self.token.as_str()) {
+            if let Some(order) = EntryOrder::from_flag(rest) {
+                return Ok(Some((order, None)));
+            }
+            if let Some((flag, glob)) = rest.split_once(':') {
+                if let Some(order) = EntryOrder::from_flag(flag) {
+                    return Ok(Some((order, Some(glob_to_regex(glob)?))));
+                }
+            }
+            return Ok(Some((EntryOrder::Lexicographic, Some(glob_to_regex(rest)?))));
+        }
+        if let Some((flag, regex)) = segment.strip_prefix(self.regex_token.as_str()).and_then(|r| r.strip_suffix('/')).and_then(|r| r.split_once('/')) {
+            let order = EntryOrder::from_flag(flag).ok_or_else(|| PathError::InvalidPattern(segment.to_string()))?;
+            let regex = Regex::new(&format!("^(?:{})$", regex)).map_err(|_| PathError::InvalidPattern(segment.to_string()))?;
+            return Ok(Some((order, Some(regex))));
+        }
+        Ok(None)
+    }
 }
 
-impl<T: PathRule> PathRule for LatestRule<T> {
-    fn resolve(&self, context: &RuleContext) -> String {
-       if context.current() == "^" {
-            // log::info!("trying to read dir {}/{}",context.repo.path(), context.until_current());
-            let paths = std::fs::read_dir(format!("{}/{}",context.repo.path(), context.until_current())).unwrap();
+impl PathRule for LatestRule {
+    fn name(&self) -> &str {
+        "latest"
+    }
 
-            return paths
-                .filter(|dir_entry| context.is_last() || dir_entry.as_ref().unwrap().file_type().unwrap().is_dir())
-                .map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap())
-                .max().unwrap();
+    fn resolve(&self, context: &RuleContext) -> Result<Option<String>, PathError> {
+       if let Some((order, filter)) = self.parse_segment(context.current())? {
+            let entries: Vec<String> = context.read_entries(!context.is_last())?
+                .into_iter()
+                .filter(|name| filter.as_ref().is_none_or(|regex| regex.is_match(name)))
+                .collect();
+            return order.latest(context.repo, &context.until_current(), entries)
+                .map(Some)
+                .ok_or_else(|| PathError::EmptyDirectory(context.until_current()));
        }
        // not resolvable by this rule
-       return self.next.resolve(context);
+       Ok(None)
+    }
+
+    fn tokens(&self) -> Vec<String> {
+        vec![self.token.clone(), self.regex_token.clone()]
+    }
+
+    fn delimited_tokens(&self) -> Vec<String> {
+        vec![self.regex_token.clone()]
+    }
+
+}
+
+/**
+ * Compares names chunk wise, so that `9` is smaller than `10`
+ */
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    static CHUNKS: OnceLock<Regex> = OnceLock::new();
+    let chunks = CHUNKS.get_or_init(|| Regex::new(r"[0-9]+|[^0-9]+").unwrap());
+    let mut a_chunks = chunks.find_iter(a).map(|m| m.as_str());
+    let mut b_chunks = chunks.find_iter(b).map(|m| m.as_str());
+    loop {
+        match (a_chunks.next(), b_chunks.next()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(a_chunk), Some(b_chunk)) => {
+                let a_number = a_chunk.trim_start_matches('0');
+                let b_number = b_chunk.trim_start_matches('0');
+                let ordering = if a_chunk.as_bytes()[0].is_ascii_digit() && b_chunk.as_bytes()[0].is_ascii_digit() {
+                    a_number.len().cmp(&b_number.len()).then_with(|| a_number.cmp(b_number))
+                } else {
+                    a_chunk.cmp(b_chunk)
+                };
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Finds a date in a name like `2023-10-01`, `2023-9`, `01.10.2023` or `2023`
+ */
+fn parse_date(name: &str) -> Option<NaiveDate> {
+    static ISO: OnceLock<Regex> = OnceLock::new();
+    static GERMAN: OnceLock<Regex> = OnceLock::new();
+    static YEAR: OnceLock<Regex> = OnceLock::new();
+    let iso = ISO.get_or_init(|| Regex::new(r"(\d{4})[-_.](\d{1,2})(?:[-_.](\d{1,2}))?").unwrap());
+    if let Some(captures) = iso.captures(name) {
+        let day = captures.get(3).map_or(Some(1), |d| d.as_str().parse().ok())?;
+        return NaiveDate::from_ymd_opt(captures[1].parse().ok()?, captures[2].parse().ok()?, day);
+    }
+    let german = GERMAN.get_or_init(|| Regex::new(r"(\d{1,2})\.(\d{1,2})\.(\d{4})").unwrap());
+    if let Some(captures) = german.captures(name) {
+        return NaiveDate::from_ymd_opt(captures[3].parse().ok()?, captures[2].parse().ok()?, captures[1].parse().ok()?);
+    }
+    let year = YEAR.get_or_init(|| Regex::new(r"(?:^|[^0-9])(\d{4})(?:$|[^0-9])").unwrap());
+    let captures = year.captures(name)?;
+    NaiveDate::from_ymd_opt(captures[1].parse().ok()?, 1, 1)
+}
+
+/**
+ * Returns the time of the latest commit that touched each entry of `dir`
+ */
+fn commit_times(repo: &Repository, dir: &str) -> Result<HashMap<String, i64>, git2::Error> {
+    let git_repo = git2::Repository::open(repo.path())?;
+    let mut revwalk = git_repo.revwalk()?;
+    revwalk.push_head()?;
+    revwalk.set_sorting(git2::Sort::TIME)?;
+
+    let mut options = git2::DiffOptions::new();
+    if !dir.is_empty() {
+        options.pathspec(dir);
+    }
+    let mut times = HashMap::new();
+    for oid in revwalk {
+        let commit = git_repo.find_commit(oid?)?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = git_repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), Some(&mut options))?;
+        for delta in diff.deltas() {
+            let entry = delta.new_file().path()
+                .and_then(|path| path.strip_prefix(dir).ok())
+                .and_then(|path| path.components().next())
+                .map(|component| component.as_os_str().to_string_lossy().to_string());
+            if let Some(entry) = entry {
+                times.entry(entry).or_insert(commit.time().seconds());
+            }
+        }
     }
+    Ok(times)
+}
 
+/**
+ * Translates a glob with `*` and `?` wildcards to an anchored regex
+ */
+fn glob_to_regex(glob: &str) -> Result<Regex, PathError> {
+    let mut regex = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => regex.push_str(".*"),
+            '?' => regex.push('.'),
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|_| PathError::InvalidPattern(glob.to_string()))
 }
 
 
-pub struct AddRule<N: PathRule> {
-    next: N
+/**
+ * Resolves `+` to the entry following the latest numbered entry of the directory
+ */
+pub struct AddRule {
+    token: String
 }
 
-impl<T: PathRule> PathRule for AddRule<T> {
-    fn resolve(&self, context: &RuleContext) -> String {
-        if context.current() == "+" {
-            let regex =  Regex::new(r"^([A-Za-z]*)([0-9]+)?$").unwrap();
+impl AddRule {
+    pub fn new() -> AddRule {
+        AddRule::with_token("+")
+    }
 
-            let paths = std::fs::read_dir(format!("{}/{}",context.repo.path(), context.until_current())).unwrap();
-            let dir_names : Vec<String> = paths.map(|dir_entry| dir_entry.unwrap().file_name().into_string().unwrap()).collect();
+    pub fn with_token(token: &str) -> AddRule {
+        AddRule { token: token.to_string() }
+    }
+}
+
+/**
+ * Parsed form of a `+` segment.
+ * `+` alone continues the numbering of the most frequent `<prefix><n>` entries,
+ * `+<template>` continues `{n}` within the template, e.g. `+inv-{n:03}.pdf`.
+ * A template without placeholder is used as prefix, `{n=5}` sets the initial value.
+ * Entries are matched by prefix and number whatever their extension, without suffix the
+ * extension of the stored file is appended.
+ */
+struct AddPattern {
+    prefix: String,
+    suffix: String,
+    width: Option<usize>,
+    start: u64
+}
 
-            let latest = dir_names.iter()
-                .map(|dir| regex.captures(dir))
-                .filter(|r|r.is_some())
-                .map(|o|o.unwrap())
-                .map(| r: Captures | { (r.get(1).map_or("".to_string(), |m| m.as_str().to_string()), r.get(2).map_or("".to_string(), |m| m.as_str().to_string()))})
-                .map(| (prefix, index_str) | { (prefix, index_str.parse::<u32>().unwrap(), index_str)})
-                .max_by(| (_a_prefix, a_index, _a_index_str), (_b_prefix, b_index, _b_index_str) | a_index.cmp(&b_index));
-            if latest.is_none() {
-                return "new".to_string();
+impl AddPattern {
+    fn parse(template: &str) -> Result<Option<AddPattern>, PathError> {
+        if template.is_empty() {
+            return Ok(None);
+        }
+        let placeholder = Regex::new(r"\{n(?::0?([0-9]+))?(?:=([0-9]+))?\}").unwrap();
+        let captures = match placeholder.captures(template) {
+            Some(captures) => captures,
+            None => {
+                return Ok(Some(AddPattern { prefix: template.to_string(), suffix: "".to_string(), width: None, start: 1 }));
             }
+        };
+        let whole = captures.get(0).unwrap();
+        let invalid = || PathError::InvalidPattern(template.to_string());
+        let width = captures.get(1).map(|w| w.as_str().parse::<usize>()).transpose().map_err(|_| invalid())?;
+        let start = captures.get(2).map(|s| s.as_str().parse::<u64>()).transpose().map_err(|_| invalid())?.unwrap_or(1);
+        let suffix = &template[whole.end()..];
+        if placeholder.is_match(suffix) {
+            return Err(invalid());
+        }
+        Ok(Some(AddPattern {
+            prefix: template[..whole.start()].to_string(),
+            suffix: suffix.to_string(),
+            width,
+            start,
+        }))
+    }
+
+    fn regex(&self) -> Regex {
+        // Without explicit suffix entries may carry any file extension
+        let suffix = if self.suffix.is_empty() { r"(?:\.[^.]+)?".to_string() } else { regex::escape(&self.suffix) };
+        Regex::new(&format!("^{}([0-9]+){}$", regex::escape(&self.prefix), suffix)).unwrap()
+    }
+}
+
+/**
+ * Formats the index padded to `width` digits
+ */
+fn format_index(index: u64, width: usize) -> String {
+    format!("{:0width$}", index, width = width)
+}
 
-            let ( prefix, index, index_str) = latest.unwrap();
+fn parse_index(index_str: &str) -> Result<u64, PathError> {
+    index_str.parse::<u64>().map_err(|_| PathError::IndexOverflow(index_str.to_string()))
+}
 
-            // Count latest up by one
-            let new_index = index + 1;
+fn next_index(index: u64) -> Result<u64, PathError> {
+    index.checked_add(1).ok_or_else(|| PathError::IndexOverflow(index.to_string()))
+}
+
+impl PathRule for AddRule {
+    fn name(&self) -> &str {
+        "add"
+    }
 
-            let mut new_index_str : String = new_index.to_string();
-            while new_index_str.len() < index_str.len() {
-                new_index_str.insert(0, '0');
+    fn resolve(&self, context: &RuleContext) -> Result<Option<String>, PathError> {
+        if let Some(template) = context.current().strip_prefix(self.token.as_str()) {
+            // A missing directory is created, so numbering starts from the beginning
+            let dir_names = match context.read_entries(false) {
+                Ok(dir_names) => dir_names,
+                Err(PathError::MissingDirectory(_)) => Vec::new(),
+                Err(error) => return Err(error),
+            };
+
+            if let Some(pattern) = AddPattern::parse(template)? {
+                let regex = pattern.regex();
+                let mut latest: Option<(u64, usize)> = None;
+                for captures in dir_names.iter().filter_map(|dir| regex.captures(dir)) {
+                    let index_str = captures.get(1).unwrap().as_str();
+                    let index = parse_index(index_str)?;
+                    if latest.is_none_or(|(latest_index, _)| index > latest_index) {
+                        latest = Some((index, index_str.len()));
+                    }
+                }
+                let (index, width) = match latest {
+                    Some((index, width)) => (next_index(index)?, pattern.width.unwrap_or(width)),
+                    None => (pattern.start, pattern.width.unwrap_or(0)),
+                };
+                let suffix = if pattern.suffix.is_empty() { context.file_extension("") } else { pattern.suffix };
+                return Ok(Some(format!("{}{}{}", pattern.prefix, format_index(index, width), suffix)));
+            }
+
+            let regex =  Regex::new(r"^([^0-9]*)([0-9]+)(\.[^.0-9][^.]*)?$").unwrap();
+
+            // Entries are grouped by prefix whatever their extension, the largest group is continued
+            let mut groups: HashMap<String, Vec<(u64, usize)>> = HashMap::new();
+            for captures in dir_names.iter().filter_map(|dir| regex.captures(dir)) {
+                let prefix = captures.get(1).map_or("", |m| m.as_str()).to_string();
+                let index_str = captures.get(2).unwrap().as_str();
+                groups.entry(prefix).or_default().push((parse_index(index_str)?, index_str.len()));
             }
+            let latest = groups.into_iter()
+                .filter_map(|(key, indices)| {
+                    let count = indices.len();
+                    indices.into_iter().max().map(|max| (count, max, key))
+                })
+                .max();
+            let (prefix, index, width) = match latest {
+                Some((_, (index, width), prefix)) => (prefix, next_index(index)?, width),
+                None => ("".to_string(), 1, 0),
+            };
 
-            return prefix + new_index_str.as_str();
+            return Ok(Some(prefix + format_index(index, width).as_str() + context.file_extension("").as_str()));
         }
 
-        self.next.resolve(context)
+        Ok(None)
+    }
+
+    fn tokens(&self) -> Vec<String> {
+        vec![self.token.clone()]
     }
 }
 
+/**
+ * Reasons why a path matcher can not be resolved
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathError {
+    /**
+     * The directory the segment should be resolved in does not exist
+     */
+    MissingDirectory(String),
+    /**
+     * The directory contains no entry the segment can be resolved to
+     */
+    EmptyDirectory(String),
+    /**
+     * The directory exists but can not be read
+     */
+    UnreadableDirectory(String, String),
+    /**
+     * The segment is not a valid pattern
+     */
+    InvalidPattern(String),
+    /**
+     * The index of an entry is too large to be counted up
+     */
+    IndexOverflow(String),
+    /**
+     * The resolved path points outside of the repository
+     */
+    EscapesRepository(String),
+    /**
+     * The resolved path points into the git directory
+     */
+    GitDirectory(String),
+    /**
+     * The resolved path is not located in a writable root of the repository
+     */
+    NotWritable(String),
+    /**
+     * The configuration refers to a rule that is not registered
+     */
+    UnknownRule(String),
+    /**
+     * The configured token is empty, contains a path separator or overlaps with the token of another rule
+     */
+    InvalidToken(String)
+}
+
+impl std::fmt::Display for PathError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathError::MissingDirectory(dir) => write!(f, "directory /{} does not exist", dir),
+            PathError::EmptyDirectory(dir) => write!(f, "directory /{} has no matching entry", dir),
+            PathError::UnreadableDirectory(dir, reason) => write!(f, "directory /{} can not be read: {}", dir, reason),
+            PathError::InvalidPattern(pattern) => write!(f, "invalid pattern {}", pattern),
+            PathError::IndexOverflow(index) => write!(f, "index {} is too large", index),
+            PathError::EscapesRepository(path) => write!(f, "path {} points outside of the repository", path),
+            PathError::GitDirectory(path) => write!(f, "path {} points into the git directory", path),
+            PathError::NotWritable(path) => write!(f, "path {} is not writable", path),
+            PathError::UnknownRule(rule) => write!(f, "unknown path rule {}", rule),
+            PathError::InvalidToken(token) => write!(f, "invalid rule token {}", token),
+        }
+    }
+}
 
+/**
+ * Error of a path matcher together with the segment that caused it
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchError {
+    pub segments: Vec<String>,
+    /**
+     * Index of the offending segment
+     */
+    pub index: usize,
+    pub error: PathError
+}
+
+impl MatchError {
+    /**
+     * Path matcher as html with the offending segment highlighted
+     */
+    pub fn highlighted(&self) -> String {
+        self.segments.iter()
+            .enumerate()
+            .map(|(i, segment)| if i == self.index { html::bold(&html::underline(&html::escape(segment))) } else { html::escape(segment) })
+            .collect::<Vec<String>>()
+            .join("/")
+    }
+}
 
-pub struct Matcher<T: PathRule> {
-    rule_set: T
+impl std::fmt::Display for MatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "segment {} of {}: {}", self.segments[self.index], self.segments.join("/"), self.error)
+    }
 }
 
-impl<T:PathRule> Matcher<T> {
+impl std::error::Error for MatchError {}
+
+impl std::error::Error for PathError {}
+
+/**
+ * Splits a path matcher at the path separator, keeping segments like `~/<regex>/` that start
+ * with one of the delimited tokens intact
+ */
+fn split_segments(path_matcher: &str, delimited_tokens: &[String]) -> Vec<String> {
+    let mut segments = Vec::new();
+    let mut current = String::new();
+    let mut in_regex = false;
+    let mut regex_done = false;
+    let mut escaped = false;
+    for c in path_matcher.chars() {
+        if in_regex {
+            current.push(c);
+            if escaped {
+                escaped = false;
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '/' {
+                in_regex = false;
+                regex_done = true;
+            }
+        } else if c == '/' && !regex_done && delimited_tokens.iter().any(|token| current.starts_with(token.as_str())) {
+            current.push(c);
+            in_regex = true;
+        } else if c == '/' {
+            segments.push(current);
+            current = String::new();
+            regex_done = false;
+        } else {
+            current.push(c);
+        }
+    }
+    segments.push(current);
+    segments
+}
+
+/**
+ * Resolves path matchers by applying the first rule that is able to resolve an element
+ */
+pub struct Matcher {
+    rules: Vec<Box<dyn PathRule>>,
+    date: Option<NaiveDateTime>,
+    extension: Option<String>
+}
+
+impl Matcher {
+    /**
+     * Matcher with the built in rules
+     */
+    pub fn new() -> Matcher {
+        Matcher::builder()
+            .with(AddRule::new())
+            .with(LatestRule::new())
+            .with(DateRule::new())
+            .build()
+    }
+
+    pub fn builder() -> MatcherBuilder {
+        MatcherBuilder { rules: Vec::new() }
+    }
+
+    /**
+     * Matcher of the built in rules with the tokens configured for the repository,
+     * the built in tokens if the configuration is invalid
+     */
+    pub fn for_repository(repo: &Repository) -> Matcher {
+        RuleRegistry::with_defaults()
+            .matcher(&repo.settings().rules)
+            .unwrap_or_else(|_| Matcher::new())
+    }
+
+    /**
+     * Leading segments of the path matcher that no rule resolves, e.g. `invoices/2023` of
+     * `invoices/2023/+`. Every file the path matcher resolves to is stored below them.
+     */
+    pub fn static_prefix(&self, path_matcher: &str) -> Vec<String> {
+        let tokens: Vec<String> = self.rules.iter().flat_map(|rule| rule.tokens()).collect();
+        split_segments(path_matcher, &self.delimited_tokens())
+            .into_iter()
+            .filter(|segment| !segment.is_empty() && segment != ".")
+            .take_while(|segment| !tokens.iter().any(|token| segment.contains(token.as_str())))
+            .collect()
+    }
+
+    fn delimited_tokens(&self) -> Vec<String> {
+        self.rules.iter().flat_map(|rule| rule.delimited_tokens()).collect()
+    }
+
+    /**
+     * Date the date rule resolves to instead of the current date
+     */
+    pub fn with_date(mut self, date: Option<NaiveDateTime>) -> Matcher {
+        self.date = date;
+        self
+    }
+
+    /**
+     * Extension of the stored file, kept by the add rule when it generates a file name
+     */
+    pub fn with_extension(mut self, extension: Option<&str>) -> Matcher {
+        self.extension = extension.map(|extension| extension.to_string());
+        self
+    }
+
+    /**
+     * Resolves the element with the first applicable rule, returns the result and the name of the rule
+     */
+    fn resolve_segment(&self, context: &RuleContext) -> Result<(String, String), PathError> {
+        for rule in &self.rules {
+            if let Some(resolved) = rule.resolve(context)? {
+                log::info!("[repo: {}] rule {} resolved {} to {}", context.repo.path(), rule.name(), context.current(), resolved);
+                return Ok((resolved, rule.name().to_string()));
+            }
+        }
+        let default = DefaultRule{};
+        Ok((default.resolve(context)?.unwrap_or_default(), default.name().to_string()))
+    }
+
+    pub fn resolve(&self, repo: &Repository, path_matcher: String) -> Result<String, MatchError> {
+        let resolutions = self.explain(repo, path_matcher)?;
+        Ok(resolutions.into_iter().map(|resolution| resolution.resolved).collect::<Vec<String>>().join("/"))
+    }
+
+    /**
+     * Resolves the path matcher and returns how each element was resolved
+     */
+    pub fn explain(&self, repo: &Repository, path_matcher: String) -> Result<Vec<Resolution>, MatchError> {
+        let path: Vec<String> = split_segments(&path_matcher, &self.delimited_tokens());
+        let fail = |index: usize, error: PathError| MatchError { segments: path.clone(), index, error };
+        if path.len() > 1 && path[0].is_empty() {
+            return Err(fail(0, PathError::EscapesRepository(path_matcher.clone())));
+        }
 
-    pub fn resolve(&self, repo: &Repository, path_matcher: String) -> String {
-        let path: Vec<String> = path_matcher.split("/").map(|c| c.to_string()).collect();
         let mut resulting_path : Vec<String> = Vec::new();
+        let mut resolutions : Vec<Resolution> = Vec::new();
         for i in 0..path.len() {
-            let context = RuleContext{path: &path, index: i, repo};
+            check_segment(&path[i], &path_matcher).map_err(|error| fail(i, error))?;
+            // Empty and `.` segments are dropped to normalize the path
+            if path[i].is_empty() || path[i] == "." {
+                continue;
+            }
+            let context = RuleContext{path: &path, index: i, repo, resolved: &resulting_path, date: self.date, extension: self.extension.as_deref()};
             log::info!("[repo: {}] resolving context {:?}", repo.path(), &context);
-            resulting_path.push(self.rule_set.resolve(&context))
+            let (resolved, rule) = self.resolve_segment(&context)
+                .and_then(|(segment, rule)| check_resolved_segment(&segment, &path_matcher).map(|_| (segment, rule)))
+                .map_err(|error| fail(i, error))?;
+            if !resolved.is_empty() && resolved != "." {
+                resulting_path.push(resolved.clone());
+                resolutions.push(Resolution { segment: path[i].clone(), resolved, rule });
+            }
         }
 
-        return resulting_path.join("/");
+        check_writable(repo, &resulting_path)
+            .and_then(|_| check_canonical(repo, &resulting_path, true))
+            .map_err(|error| fail(path.len() - 1, error))?;
+        Ok(resolutions)
+    }
+}
+
+/**
+ * Result of resolving a single element of a path matcher
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolution {
+    pub segment: String,
+    pub resolved: String,
+    /**
+     * Name of the rule that resolved the element
+     */
+    pub rule: String
+}
+
+/**
+ * Rejects segments that leave the repository or point into the git directory
+ */
+fn check_segment(segment: &str, path: &str) -> Result<(), PathError> {
+    if segment == ".." || segment.contains('\0') {
+        return Err(PathError::EscapesRepository(path.to_string()));
+    }
+    if segment.eq_ignore_ascii_case(".git") {
+        return Err(PathError::GitDirectory(path.to_string()));
     }
+    Ok(())
 }
 
+/**
+ * Rejects resolved segments that would add path separators, a resolved segment is always a single path component
+ */
+fn check_resolved_segment(segment: &str, path: &str) -> Result<(), PathError> {
+    if segment.contains(['/', '\\']) {
+        return Err(PathError::EscapesRepository(path.to_string()));
+    }
+    check_segment(segment, path)
+}
+
+/**
+ * Follows the symlinks of the existing part of the path and checks that it stays in the repository,
+ * and in its writable roots if the path is written
+ */
+fn check_canonical(repo: &Repository, segments: &[String], writable: bool) -> Result<(), PathError> {
+    let path = segments.join("/");
+    let escapes = || PathError::EscapesRepository(path.clone());
+    let root = std::fs::canonicalize(repo.path())
+        .map_err(|error| PathError::UnreadableDirectory(String::new(), error.to_string()))?;
+    // Missing entries are created later, only the existing ancestor can be a symlink
+    let mut existing = root.join(&path);
+    let mut missing: Vec<String> = Vec::new();
+    while std::fs::symlink_metadata(&existing).is_err() {
+        match existing.file_name() {
+            Some(name) => missing.push(name.to_string_lossy().to_string()),
+            None => return Err(escapes()),
+        }
+        existing.pop();
+    }
+    let canonical = std::fs::canonicalize(&existing).map_err(|_| escapes())?;
+    let relative = canonical.strip_prefix(&root).map_err(|_| escapes())?;
+    let mut components: Vec<String> = relative.components()
+        .map(|component| component.as_os_str().to_string_lossy().to_string())
+        .collect();
+    components.extend(missing.into_iter().rev());
+    if components.first().is_some_and(|first| first.eq_ignore_ascii_case(".git")) {
+        return Err(PathError::GitDirectory(path));
+    }
+    if writable {
+        check_writable(repo, &components)?;
+    }
+    Ok(())
+}
+
+/**
+ * Checks that the normalized path is located in one of the writable roots of the repository
+ */
+fn check_writable(repo: &Repository, segments: &[String]) -> Result<(), PathError> {
+    let path = segments.join("/");
+    if segments.is_empty() {
+        return Err(PathError::InvalidPattern(path));
+    }
+    let roots = &repo.settings().writable_roots;
+    if roots.is_empty() {
+        return Ok(());
+    }
+    let writable = roots.iter()
+        .map(|root| root.trim_matches('/'))
+        .any(|root| root.is_empty() || path == root || path.starts_with(&format!("{}/", root)));
+    if writable {
+        Ok(())
+    } else {
+        Err(PathError::NotWritable(path))
+    }
+}
+
+/**
+ * Normalizes and validates a path given by a user, e.g. the file to remove
+ */
+pub fn validate_path(repo: &Repository, path: &str) -> Result<String, MatchError> {
+    let normalized = normalize_path(path)?;
+    let segments: Vec<String> = path.split('/').map(|s| s.to_string()).collect();
+    let normalized_segments: Vec<String> = normalized.split('/').map(|s| s.to_string()).collect();
+    check_writable(repo, &normalized_segments)
+        .and_then(|_| check_canonical(repo, &normalized_segments, true))
+        .map_err(|error| MatchError { index: segments.len() - 1, segments, error })?;
+    Ok(normalized)
+}
+
+/**
+ * Validates a path that is only read, it may be outside of the writable roots
+ */
+pub fn validate_readable_path(repo: &Repository, path: &str) -> Result<String, MatchError> {
+    let normalized = normalize_path(path)?;
+    let segments: Vec<String> = path.split('/').map(|s| s.to_string()).collect();
+    let normalized_segments: Vec<String> = normalized.split('/').map(|s| s.to_string()).collect();
+    check_canonical(repo, &normalized_segments, false)
+        .map_err(|error| MatchError { index: segments.len() - 1, segments, error })?;
+    Ok(normalized)
+}
+
+/**
+ * Drops empty and `.` segments of a path given by a user and rejects traversal
+ */
+fn normalize_path(path: &str) -> Result<String, MatchError> {
+    let segments: Vec<String> = path.split('/').map(|s| s.to_string()).collect();
+    let fail = |index: usize, error: PathError| MatchError { segments: segments.clone(), index, error };
+    if segments.len() > 1 && segments[0].is_empty() {
+        return Err(fail(0, PathError::EscapesRepository(path.to_string())));
+    }
+    let mut normalized: Vec<String> = Vec::new();
+    for (i, segment) in segments.iter().enumerate() {
+        check_resolved_segment(segment, path).map_err(|error| fail(i, error))?;
+        if !segment.is_empty() && segment != "." {
+            normalized.push(segment.clone());
+        }
+    }
+    if normalized.is_empty() {
+        return Err(fail(segments.len() - 1, PathError::InvalidPattern(path.to_string())));
+    }
+    Ok(normalized.join("/"))
+}
+
+
+pub struct MatcherBuilder {
+    rules: Vec<Box<dyn PathRule>>
+}
+
+impl MatcherBuilder {
+    /**
+     * Appends a rule, rules are tried in the order they were added
+     */
+    pub fn with<R: PathRule + 'static>(mut self, rule: R) -> MatcherBuilder {
+        self.rules.push(Box::new(rule));
+        self
+    }
+
+    pub fn with_boxed(mut self, rule: Box<dyn PathRule>) -> MatcherBuilder {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn build(self) -> Matcher {
+        Matcher { rules: self.rules, date: None, extension: None }
+    }
+}
+
+type RuleFactory = Box<dyn Fn(Option<&str>) -> Box<dyn PathRule> + Send + Sync>;
+
+/**
+ * Named rules that can be composed to a matcher from the configuration.
+ * The factories receive the custom token of the configuration, if any.
+ */
+pub struct RuleRegistry {
+    factories: HashMap<String, RuleFactory>
+}
+
+impl RuleRegistry {
+    pub fn new() -> RuleRegistry {
+        RuleRegistry { factories: HashMap::new() }
+    }
+
+    /**
+     * Registry with the built in rules `add`, `latest` and `date`
+     */
+    pub fn with_defaults() -> RuleRegistry {
+        let mut registry = RuleRegistry::new();
+        registry.register("add", |token| Box::new(AddRule::with_token(token.unwrap_or("+"))));
+        registry.register("latest", |token| Box::new(LatestRule::with_tokens(token.unwrap_or("^"), "~")));
+        registry.register("date", |token| Box::new(DateRule::with_token(token.unwrap_or("date"))));
+        registry
+    }
+
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(Option<&str>) -> Box<dyn PathRule> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    /**
+     * Builds the matcher for the configured rules, the default matcher if none are configured.
+     * Tokens must not be empty, contain `/` or be a prefix of another token, otherwise it would
+     * depend on the order of the rules which one resolves a segment.
+     */
+    pub fn matcher(&self, rules: &[RuleConfig]) -> Result<Matcher, PathError> {
+        if rules.is_empty() {
+            return Ok(Matcher::new());
+        }
+        let mut builder = Matcher::builder();
+        let mut tokens: Vec<String> = Vec::new();
+        for rule in rules {
+            let factory = self.factories.get(&rule.rule)
+                .ok_or_else(|| PathError::UnknownRule(rule.rule.clone()))?;
+            if let Some(token) = rule.token.as_deref().filter(|token| token.is_empty() || token.contains('/')) {
+                return Err(PathError::InvalidToken(token.to_string()));
+            }
+            let path_rule = factory(rule.token.as_deref());
+            for token in path_rule.tokens() {
+                if let Some(other) = tokens.iter().find(|other| other.starts_with(&token) || token.starts_with(other.as_str())) {
+                    return Err(PathError::InvalidToken(format!("{} (overlaps with {})", token, other)));
+                }
+                tokens.push(token);
+            }
+            builder = builder.with_boxed(path_rule);
+        }
+        Ok(builder.build())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RepositorySettings;
+
+    fn repository(dir: &Path) -> Repository {
+        let settings = RepositorySettings { path: dir.to_string_lossy().to_string(), ..Default::default() };
+        Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string())
+    }
+
+    fn create_files(dir: &Path, names: &[&str]) {
+        for name in names {
+            let path = dir.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+    }
+
+    fn resolve(repo: &Repository, path_matcher: &str) -> Result<String, PathError> {
+        Matcher::new().resolve(repo, path_matcher.to_string()).map_err(|error| error.error)
+    }
+
+    #[test]
+    fn static_prefix_ends_at_the_first_resolved_segment() {
+        let matcher = Matcher::new();
+        assert_eq!(matcher.static_prefix("taxes/{date:%Y}/+"), vec!["taxes"]);
+        assert_eq!(matcher.static_prefix("./car/invoices/inv-+.pdf"), vec!["car", "invoices"]);
+        assert_eq!(matcher.static_prefix("docs/~/a\\/b/"), vec!["docs"]);
+        assert!(matcher.static_prefix("^/+").is_empty());
+        assert_eq!(matcher.static_prefix("manuals/car.pdf"), vec!["manuals", "car.pdf"]);
+    }
+
+    #[test]
+    fn latest_rule_filters_by_glob_and_regex() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/a.txt", "docs/b.pdf", "docs/c.txt"]);
+        let repo = repository(dir.path());
+
+        assert_eq!(resolve(&repo, "docs/^"), Ok("docs/c.txt".to_string()));
+        assert_eq!(resolve(&repo, "docs/^*.pdf"), Ok("docs/b.pdf".to_string()));
+        assert_eq!(resolve(&repo, "docs/~/[ab]\\..*/"), Ok("docs/b.pdf".to_string()));
+    }
+
+    #[test]
+    fn regex_segments_are_split_by_the_token_of_the_rule() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/a.txt", "docs/b.pdf", "docs/c.txt", "docs/~/a.txt"]);
+        let repo = repository(dir.path());
+        let matcher = Matcher::builder().with(LatestRule::with_tokens("^", "%")).build();
+        let resolve = |path_matcher: &str| matcher.resolve(&repo, path_matcher.to_string()).map_err(|error| error.error);
+
+        assert_eq!(resolve("docs/%/[ab]\\..*/"), Ok("docs/b.pdf".to_string()));
+        assert_eq!(resolve("docs/%n/a\\/b|c.*/"), Ok("docs/c.txt".to_string()));
+        // `~` is an ordinary name for this matcher
+        assert_eq!(resolve("docs/~/a.txt"), Ok("docs/~/a.txt".to_string()));
+        assert_eq!(matcher.static_prefix("docs/%/a\\/b/"), vec!["docs"]);
+        assert_eq!(matcher.static_prefix("docs/~/a/^"), vec!["docs", "~", "a"]);
+    }
+
+    #[test]
+    fn latest_rule_rejects_invalid_regex() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/a.txt"]);
+        let repo = repository(dir.path());
+
+        assert_eq!(resolve(&repo, "docs/~/[/"), Err(PathError::InvalidPattern("~/[/".to_string())));
+        assert_eq!(resolve(&repo, "docs/~x/a/"), Err(PathError::InvalidPattern("~x/a/".to_string())));
+    }
+
+    #[test]
+    fn latest_rule_fails_without_matching_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/a.txt"]);
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        let repo = repository(dir.path());
+
+        assert_eq!(resolve(&repo, "docs/^*.pdf"), Err(PathError::EmptyDirectory("docs".to_string())));
+        assert_eq!(resolve(&repo, "docs/~/b.*/"), Err(PathError::EmptyDirectory("docs".to_string())));
+        assert_eq!(resolve(&repo, "empty/^"), Err(PathError::EmptyDirectory("empty".to_string())));
+    }
+
+    #[test]
+    fn natural_cmp_compares_numbers_by_value() {
+        assert_eq!(natural_cmp("9", "10"), Ordering::Less);
+        assert_eq!(natural_cmp("file-10.pdf", "file-9.pdf"), Ordering::Greater);
+        assert_eq!(natural_cmp("007", "7"), Ordering::Equal);
+        assert_eq!(natural_cmp("a2", "b1"), Ordering::Less);
+        assert_eq!(natural_cmp("scan", "scan1"), Ordering::Less);
+        assert_eq!(natural_cmp("", ""), Ordering::Equal);
+    }
+
+    #[test]
+    fn parse_date_finds_dates_in_names() {
+        let date = |y, m, d| NaiveDate::from_ymd_opt(y, m, d);
+        assert_eq!(parse_date("2023-10-01 invoice"), date(2023, 10, 1));
+        assert_eq!(parse_date("report_2023_9"), date(2023, 9, 1));
+        assert_eq!(parse_date("01.10.2023"), date(2023, 10, 1));
+        assert_eq!(parse_date("taxes 2021"), date(2021, 1, 1));
+        assert_eq!(parse_date("2023-13-01"), None);
+        assert_eq!(parse_date("12345"), None);
+        assert_eq!(parse_date("notes"), None);
+    }
+
+    #[test]
+    fn latest_rule_orders_naturally_and_by_date() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/v9.pdf", "docs/v10.pdf", "dates/01.12.2022.pdf", "dates/2023-02-01.pdf"]);
+        let repo = repository(dir.path());
+
+        assert_eq!(resolve(&repo, "docs/^"), Ok("docs/v9.pdf".to_string()));
+        assert_eq!(resolve(&repo, "docs/^n"), Ok("docs/v10.pdf".to_string()));
+        assert_eq!(resolve(&repo, "dates/^d"), Ok("dates/2023-02-01.pdf".to_string()));
+    }
+
+    #[test]
+    fn date_rule_formats_the_captured_date_with_offset() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repository(dir.path());
+        let captured = NaiveDate::from_ymd_opt(2023, 6, 1).unwrap().and_hms_opt(12, 30, 0).unwrap();
+        let offset = Local.from_local_datetime(&captured).unwrap().format("%z").to_string();
+        let matcher = Matcher::new().with_date(Some(captured));
+        let resolve = |path_matcher: &str| matcher.resolve(&repo, path_matcher.to_string()).map_err(|error| error.error);
+
+        assert_eq!(resolve("docs/{date:%Y-%m}/a.pdf"), Ok("docs/2023-06/a.pdf".to_string()));
+        assert_eq!(resolve("docs/{date:%Y-%z}.pdf"), Ok(format!("docs/2023-{}.pdf", offset)));
+        assert_eq!(resolve("docs/{date:%Q}.pdf"), Err(PathError::InvalidPattern("{date:%Q}.pdf".to_string())));
+    }
+
+    #[test]
+    fn add_pattern_parses_templates() {
+        let pattern = AddPattern::parse("inv-{n:03=5}.pdf").unwrap().unwrap();
+        assert_eq!((pattern.prefix.as_str(), pattern.suffix.as_str(), pattern.width, pattern.start), ("inv-", ".pdf", Some(3), 5));
+        let pattern = AddPattern::parse("inv-").unwrap().unwrap();
+        assert_eq!((pattern.prefix.as_str(), pattern.suffix.as_str(), pattern.width, pattern.start), ("inv-", "", None, 1));
+        assert!(AddPattern::parse("").unwrap().is_none());
+        assert_eq!(AddPattern::parse("{n}-{n}").err(), Some(PathError::InvalidPattern("{n}-{n}".to_string())));
+    }
+
+    #[test]
+    fn add_rule_continues_numbering() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/inv-008.pdf", "docs/inv-009.pdf", "docs/notes.txt", "scans/3.jpg", "scans/4.jpg", "scans/10.png"]);
+        let repo = repository(dir.path());
+
+        assert_eq!(resolve(&repo, "docs/+inv-{n}.pdf"), Ok("docs/inv-010.pdf".to_string()));
+        assert_eq!(resolve(&repo, "docs/+inv-{n:2}.pdf"), Ok("docs/inv-10.pdf".to_string()));
+        assert_eq!(resolve(&repo, "docs/+rec-{n=7}.pdf"), Ok("docs/rec-7.pdf".to_string()));
+        // Entries are numbered whatever their extension
+        assert_eq!(resolve(&repo, "scans/+"), Ok("scans/11".to_string()));
+        assert_eq!(resolve(&repo, "new/+"), Ok("new/1".to_string()));
+        assert_eq!(resolve(&repo, "new/+/a.pdf"), Ok("new/1/a.pdf".to_string()));
+    }
+
+    #[test]
+    fn add_rule_keeps_the_extension() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/inv-3.pdf", "scans/1.png", "scans/2.png"]);
+        std::fs::create_dir(dir.path().join("empty")).unwrap();
+        let repo = repository(dir.path());
+        let resolve = |path_matcher: &str| Matcher::new().with_extension(Some("jpg")).resolve(&repo, path_matcher.to_string());
+
+        // The extension of the uploaded file wins over the one of the numbered entries
+        assert_eq!(resolve("docs/+inv-"), Ok("docs/inv-4.jpg".to_string()));
+        assert_eq!(resolve("docs/+inv-{n}"), Ok("docs/inv-4.jpg".to_string()));
+        assert_eq!(resolve("scans/+"), Ok("scans/3.jpg".to_string()));
+        assert_eq!(resolve("docs/+rec-"), Ok("docs/rec-1.jpg".to_string()));
+        assert_eq!(resolve("empty/+"), Ok("empty/1.jpg".to_string()));
+        assert_eq!(resolve("empty/+/a.pdf"), Ok("empty/1/a.pdf".to_string()));
+        assert_eq!(resolve("docs/+inv-{n}.txt"), Ok("docs/inv-1.txt".to_string()));
+    }
+
+    #[test]
+    fn check_segment_rejects_traversal() {
+        assert_eq!(check_segment("docs", "docs"), Ok(()));
+        assert_eq!(check_segment("..", "../x"), Err(PathError::EscapesRepository("../x".to_string())));
+        assert_eq!(check_segment("a\0b", "a\0b"), Err(PathError::EscapesRepository("a\0b".to_string())));
+        assert_eq!(check_segment(".GIT", ".GIT/config"), Err(PathError::GitDirectory(".GIT/config".to_string())));
+        assert_eq!(check_resolved_segment("..x", "..x"), Ok(()));
+        assert_eq!(check_resolved_segment("x/../..", "p"), Err(PathError::EscapesRepository("p".to_string())));
+        assert_eq!(check_resolved_segment("..\\..", "p"), Err(PathError::EscapesRepository("p".to_string())));
+    }
+
+    #[test]
+    fn matcher_rejects_caption_escaping_the_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        create_files(dir.path(), &["docs/a.txt"]);
+        let repo = repository(dir.path());
+
+        for caption in ["~x/../../etc/passwd", "docs/~/../../", "../etc/passwd", "/etc/passwd", "docs/../../etc", ".git/config"] {
+            assert!(resolve(&repo, caption).is_err(), "{} was resolved", caption);
+        }
+        // Without the latest rule the regex segments are kept as they are by the default rule
+        let matcher = Matcher::builder().with(AddRule::new()).build();
+        for caption in ["~x/../../etc/passwd", "~x/..\\/..\\/etc\\/passwd"] {
+            assert_eq!(
+                matcher.resolve(&repo, caption.to_string()).map_err(|error| error.error),
+                Err(PathError::EscapesRepository(caption.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn matcher_rejects_symlinks_leaving_the_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        std::os::unix::fs::symlink(outside.path(), dir.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join(".git"), dir.path().join("git")).unwrap();
+        std::fs::create_dir(dir.path().join(".git")).unwrap();
+        let repo = repository(dir.path());
+
+        assert_eq!(resolve(&repo, "link/a.txt"), Err(PathError::EscapesRepository("link/a.txt".to_string())));
+        assert_eq!(resolve(&repo, "git/config"), Err(PathError::GitDirectory("git/config".to_string())));
+        assert_eq!(resolve(&repo, "docs/a.txt"), Ok("docs/a.txt".to_string()));
+    }
+
+    #[test]
+    fn validate_path_normalizes_and_checks_writable_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut repo = repository(dir.path());
+
+        assert_eq!(validate_path(&repo, "./docs//a.txt"), Ok("docs/a.txt".to_string()));
+        assert!(validate_path(&repo, "docs/../../a.txt").is_err());
+        assert!(validate_path(&repo, "/etc/passwd").is_err());
+        assert!(validate_path(&repo, "docs\\..\\..\\a.txt").is_err());
+        assert!(validate_path(&repo, ".").is_err());
+
+        let settings = RepositorySettings { writable_roots: vec!["inbox/".to_string()], ..repo.settings().clone() };
+        repo = Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string());
+        assert_eq!(validate_path(&repo, "inbox/a.txt"), Ok("inbox/a.txt".to_string()));
+        assert_eq!(validate_path(&repo, "docs/a.txt").map_err(|error| error.error), Err(PathError::NotWritable("docs/a.txt".to_string())));
+        assert_eq!(validate_readable_path(&repo, "docs/a.txt"), Ok("docs/a.txt".to_string()));
+    }
+
+    fn rule(rule: &str, token: Option<&str>) -> RuleConfig {
+        RuleConfig { rule: rule.to_string(), token: token.map(|token| token.to_string()) }
+    }
+
+    #[test]
+    fn registry_rejects_invalid_tokens() {
+        let registry = RuleRegistry::with_defaults();
+        let error = |rules: &[RuleConfig]| registry.matcher(rules).err();
 
-impl Matcher<AddRule<LatestRule<DefaultRule>>> {
-    pub fn new() -> Matcher<AddRule<LatestRule<DefaultRule>>> {
-       Matcher { rule_set: AddRule { next: LatestRule { next: DefaultRule{} } }}
+        assert!(registry.matcher(&[rule("add", Some("#")), rule("latest", None), rule("date", Some("d"))]).is_ok());
+        assert_eq!(error(&[rule("add", Some(""))]), Some(PathError::InvalidToken("".to_string())));
+        assert_eq!(error(&[rule("add", Some("a/b"))]), Some(PathError::InvalidToken("a/b".to_string())));
+        assert_eq!(error(&[rule("unknown", None)]), Some(PathError::UnknownRule("unknown".to_string())));
+        assert_eq!(error(&[rule("add", Some("^"))]), None);
+        assert!(matches!(error(&[rule("add", Some("^")), rule("latest", None)]), Some(PathError::InvalidToken(_))));
+        assert!(matches!(error(&[rule("add", Some("+")), rule("latest", Some("++"))]), Some(PathError::InvalidToken(_))));
+        assert!(matches!(error(&[rule("latest", Some("~"))]), Some(PathError::InvalidToken(_))));
+        assert!(matches!(error(&[rule("add", Some("{d")), rule("date", Some("d"))]), Some(PathError::InvalidToken(_))));
     }
 }
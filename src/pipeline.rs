@@ -0,0 +1,874 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+use chrono::NaiveDateTime;
+
+use crate::{
+    categorizer::{self, Categorized, CategorySource},
+    config::{Repository, StageConfig},
+    convert::{self, Converter, PdfFormat},
+    encrypt,
+    metadata,
+    policy,
+    sanitize::{self, SanitizeConfig},
+    scan::{self, ClamdScanner, ScanResult},
+};
+#[cfg(feature = "ocr")]
+use crate::ocr;
+
+/**
+ * Stages that run in every pipeline unless disabled, stages that are not compiled in are left out
+ */
+pub const DEFAULT_STAGES: [&str; 7] = ["scan", "sanitize", "ocr", "policy", "convert", "encrypt", "metadata"];
+
+/**
+ * Point of the upload a stage runs at
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Phase {
+    /**
+     * Right after the download, before the file is categorized
+     */
+    Downloaded,
+    /**
+     * After the path of the file is resolved, before it is written to the repository
+     */
+    Resolved
+}
+
+/**
+ * Result of a stage, everything but `Passed` is reported to the chat
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    /**
+     * Nothing to do for the stage
+     */
+    Passed,
+    /**
+     * The file was inspected or changed
+     */
+    Done(String),
+    /**
+     * The stage could not do its work, the file is stored anyway
+     */
+    Skipped(String),
+    /**
+     * The file must not be stored, no later stage runs
+     */
+    Rejected(String)
+}
+
+impl std::fmt::Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outcome::Passed => write!(f, "passed"),
+            Outcome::Done(message) => write!(f, "{}", message),
+            Outcome::Skipped(message) => write!(f, "skipped, {}", message),
+            Outcome::Rejected(message) => write!(f, "rejected, {}", message),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AttachmentKind {
+    /**
+     * The file before it was converted
+     */
+    Original,
+    /**
+     * Recognized text or metadata of the file
+     */
+    Sidecar
+}
+
+/**
+ * Additional file that is stored with the uploaded file
+ */
+#[derive(Debug, Clone)]
+pub struct Attachment {
+    pub source: PathBuf,
+    /**
+     * Path in the repository
+     */
+    pub target: PathBuf,
+    pub kind: AttachmentKind
+}
+
+/**
+ * Uploaded file on its way through the pipeline
+ */
+#[derive(Debug, Default)]
+pub struct PipelineFile {
+    /**
+     * Local file that is stored, stages may replace it
+     */
+    pub file: PathBuf,
+    /**
+     * Name the file was uploaded with
+     */
+    pub name: String,
+    pub caption: Option<String>,
+    /**
+     * Recognized text of the file
+     */
+    pub text: Option<PathBuf>,
+    /**
+     * Date the file was captured, date placeholders of the path resolve to it
+     */
+    pub captured: Option<NaiveDateTime>,
+    /**
+     * Category the file is stored in, known in the resolved phase
+     */
+    pub category: Option<Categorized>,
+    /**
+     * Path in the repository, known in the resolved phase
+     */
+    pub target: PathBuf,
+    pub attachments: Vec<Attachment>,
+    /**
+     * Set once the file is encrypted, stages must not reveal its content anymore
+     */
+    pub encrypted: bool,
+    /**
+     * Outcomes of the stages that ran so far
+     */
+    pub outcomes: Vec<(String, Outcome)>,
+    temporary: Vec<PathBuf>
+}
+
+impl PipelineFile {
+    pub fn new(file: &Path, name: &str, caption: Option<&str>) -> PipelineFile {
+        PipelineFile {
+            file: file.to_path_buf(),
+            name: name.to_string(),
+            caption: caption.map(|caption| caption.to_string()),
+            text: None,
+            captured: None,
+            category: None,
+            target: PathBuf::new(),
+            attachments: Vec::new(),
+            encrypted: false,
+            outcomes: Vec::new(),
+            temporary: vec![file.to_path_buf()],
+        }
+    }
+
+    /**
+     * Registers a file a stage created, it is removed with the staged file
+     */
+    pub fn add_temporary(&mut self, path: &Path) {
+        if !self.temporary.iter().any(|temporary| temporary == path) {
+            self.temporary.push(path.to_path_buf());
+        }
+    }
+
+    /**
+     * Tags of the category the file is stored in
+     */
+    pub fn tags(&self) -> Vec<String> {
+        match self.category.as_ref().map(|category| &category.source) {
+            Some(CategorySource::Category(tags)) => tags.clone(),
+            _ => Vec::new(),
+        }
+    }
+
+    /**
+     * Lines like `convert: converted to PDF` for the outcomes worth reporting
+     */
+    pub fn report(&self) -> Vec<String> {
+        self.outcomes.iter()
+            .filter(|(_, outcome)| *outcome != Outcome::Passed)
+            .map(|(stage, outcome)| format!("{}: {}", stage, outcome))
+            .collect()
+    }
+
+    /**
+     * Removes the staged file and everything the stages created
+     */
+    pub fn remove(self) {
+        for file in self.temporary {
+            if !file.exists() {
+                continue;
+            }
+            if let Err(error) = std::fs::remove_file(&file) {
+                log::error!("Could not remove staged file {:?}: {}", file, error);
+            }
+        }
+    }
+}
+
+pub struct StageContext<'a> {
+    pub repo: &'a Repository,
+    pub chat_id: i64
+}
+
+impl<'a> StageContext<'a> {
+    pub fn new(repo: &Repository, chat_id: i64) -> StageContext<'_> {
+        StageContext { repo, chat_id }
+    }
+
+    /**
+     * Path matcher of the category the file is stored in
+     */
+    fn path_matcher<'b>(&self, file: &'b PipelineFile) -> Option<&'b str> {
+        file.category.as_ref().map(|category| category.path_matcher.as_str())
+    }
+}
+
+/**
+ * Step an uploaded file passes before it is committed. A stage may inspect the file,
+ * replace it, change its target, add attachments or reject it.
+ */
+pub trait Stage: Send + Sync {
+    /**
+     * Name of the stage as used in the configuration
+     */
+    fn name(&self) -> &str;
+
+    fn phase(&self) -> Phase {
+        Phase::Resolved
+    }
+
+    /**
+     * Whether the stage decides if a file may be stored. Errors of gatekeeping stages reject
+     * the file, other stages only enrich it and are skipped.
+     */
+    fn gatekeeping(&self) -> bool {
+        false
+    }
+
+    /**
+     * Runs the stage, see `gatekeeping` for how errors are handled
+     */
+    fn process(&self, file: &mut PipelineFile, context: &StageContext) -> std::io::Result<Outcome>;
+
+    /**
+     * Target a file of the category would be moved to by the stage, without processing a file.
+     * Only the extension of the target tells the type of the file.
+     */
+    fn preview(&self, target: PathBuf, _category: &Categorized, _context: &StageContext) -> PathBuf {
+        target
+    }
+}
+
+/**
+ * Stages of a repository in the order they run
+ */
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Stage>>) -> Pipeline {
+        Pipeline { stages }
+    }
+
+    /**
+     * Runs the stages of the phase until one rejects the file, returns false if it was rejected
+     */
+    pub fn run(&self, phase: Phase, file: &mut PipelineFile, context: &StageContext) -> bool {
+        for stage in self.stages.iter().filter(|stage| stage.phase() == phase) {
+            let outcome = stage.process(file, context).unwrap_or_else(|error| {
+                log::error!("[chat: {}] Stage {} failed for {}: {}", context.chat_id, stage.name(), file.name, error);
+                match stage.gatekeeping() {
+                    true => Outcome::Rejected(format!("failed: {}", error)),
+                    false => Outcome::Skipped(format!("failed: {}", error)),
+                }
+            });
+            log::info!("[chat: {}] Stage {} for {}: {}", context.chat_id, stage.name(), file.name, outcome);
+            let rejected = matches!(outcome, Outcome::Rejected(_));
+            file.outcomes.push((stage.name().to_string(), outcome));
+            if rejected {
+                return false;
+            }
+        }
+        true
+    }
+
+    /**
+     * Target the resolved phase would store a file of the category at, e.g. converted or encrypted
+     */
+    pub fn preview(&self, target: PathBuf, category: &Categorized, context: &StageContext) -> PathBuf {
+        self.stages.iter()
+            .filter(|stage| stage.phase() == Phase::Resolved)
+            .fold(target, |target, stage| stage.preview(target, category, context))
+    }
+}
+
+#[derive(Debug)]
+pub enum PipelineError {
+    UnknownStage(String),
+    InvalidOptions(String, String)
+}
+
+impl std::fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PipelineError::UnknownStage(stage) => write!(f, "unknown stage {}", stage),
+            PipelineError::InvalidOptions(stage, error) => write!(f, "invalid options of stage {}: {}", stage, error),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+type StageFactory = Box<dyn Fn(Option<&serde_json::Value>) -> Result<Box<dyn Stage>, String> + Send + Sync>;
+
+/**
+ * Builds the stages by the names used in the repository config
+ */
+pub struct StageRegistry {
+    factories: HashMap<String, StageFactory>
+}
+
+impl StageRegistry {
+    pub fn new() -> StageRegistry {
+        StageRegistry { factories: HashMap::new() }
+    }
+
+    /**
+     * Registry with the built in stages `scan`, `sanitize`, `ocr`, `policy`, `convert`, `encrypt` and `metadata`
+     */
+    pub fn with_defaults() -> StageRegistry {
+        let mut registry = StageRegistry::new();
+        registry.register("scan", |_| Ok(Box::new(ScanStage {})));
+        registry.register("sanitize", |options| {
+            let config = match options {
+                Some(options) => serde_json::from_value(options.clone()).map_err(|error| error.to_string())?,
+                None => SanitizeConfig::default(),
+            };
+            Ok(Box::new(SanitizeStage { config }))
+        });
+        #[cfg(feature = "ocr")]
+        registry.register("ocr", |_| Ok(Box::new(OcrStage {})));
+        registry.register("policy", |_| Ok(Box::new(PolicyStage {})));
+        registry.register("convert", |_| Ok(Box::new(ConvertStage {})));
+        registry.register("encrypt", |_| Ok(Box::new(EncryptStage {})));
+        registry.register("metadata", |_| Ok(Box::new(MetadataStage {})));
+        registry
+    }
+
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn(Option<&serde_json::Value>) -> Result<Box<dyn Stage>, String> + Send + Sync + 'static,
+    {
+        self.factories.insert(name.to_string(), Box::new(factory));
+    }
+
+    /**
+     * Builds the pipeline of the default stages with the configured stages merged in. Configured
+     * default stages get their options or are left out if disabled, other stages run before `encrypt`.
+     */
+    pub fn pipeline(&self, stages: &[StageConfig]) -> Result<Pipeline, PipelineError> {
+        let mut merged: Vec<(&str, Option<&serde_json::Value>)> = DEFAULT_STAGES.iter()
+            .filter(|name| self.factories.contains_key(**name))
+            .map(|name| (*name, None))
+            .collect();
+        // Stages after encrypt must not see the content of encrypted files
+        let mut insert_at = merged.iter().position(|(name, _)| *name == "encrypt").unwrap_or(merged.len());
+        for stage in stages {
+            if !self.factories.contains_key(&stage.stage) {
+                return Err(PipelineError::UnknownStage(stage.stage.clone()));
+            }
+            match merged.iter().position(|(name, _)| *name == stage.stage) {
+                Some(index) if !stage.enabled => {
+                    log::info!("Stage {} is disabled", stage.stage);
+                    merged.remove(index);
+                    if index < insert_at {
+                        insert_at -= 1;
+                    }
+                }
+                Some(index) => merged[index].1 = stage.options.as_ref(),
+                None if !stage.enabled => {}
+                None => {
+                    merged.insert(insert_at, (&stage.stage, stage.options.as_ref()));
+                    insert_at += 1;
+                }
+            }
+        }
+        let mut built = Vec::new();
+        for (name, options) in merged {
+            built.push(self.factories[name](options).map_err(|error| PipelineError::InvalidOptions(name.to_string(), error))?);
+        }
+        Ok(Pipeline::new(built))
+    }
+}
+
+/**
+ * Scans the file for malware if a clamd socket is configured, infected files are quarantined
+ */
+pub struct ScanStage {}
+
+impl Stage for ScanStage {
+    fn name(&self) -> &str {
+        "scan"
+    }
+
+    fn gatekeeping(&self) -> bool {
+        true
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Downloaded
+    }
+
+    fn process(&self, file: &mut PipelineFile, context: &StageContext) -> std::io::Result<Outcome> {
+        let scanner = match ClamdScanner::from_env() {
+            Some(scanner) => scanner,
+            None => return Ok(Outcome::Passed),
+        };
+        match scanner.scan(&file.file) {
+            Ok(ScanResult::Clean) => Ok(Outcome::Done("no malware found".to_string())),
+            Ok(ScanResult::Infected(signature)) => {
+                match scan::quarantine(&file.file, &format!("{}-{}", context.chat_id, file.name)) {
+                    Ok(quarantined) => log::warn!("[chat: {}] Found {} in {}, quarantined at {:?}", context.chat_id, signature, file.name, quarantined),
+                    Err(error) => log::error!("[chat: {}] Found {} in {}, quarantine failed: {}", context.chat_id, signature, file.name, error),
+                }
+                Ok(Outcome::Rejected(format!("malware found: {}", signature)))
+            }
+            Err(error) => {
+                log::error!("[chat: {}] Scan of {:?} failed: {}", context.chat_id, file.file, error);
+                Ok(Outcome::Rejected("could not be scanned for malware".to_string()))
+            }
+        }
+    }
+}
+
+/**
+ * Removes the location, the camera and other metadata from photos. Runs by default,
+ * `{"stage": "sanitize", "enabled": false}` keeps the photos as they are.
+ */
+pub struct SanitizeStage {
+    config: SanitizeConfig
+}
+
+impl Stage for SanitizeStage {
+    fn name(&self) -> &str {
+        "sanitize"
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Downloaded
+    }
+
+    // A photo that could not be sanitized may still reveal the location it was taken at
+    fn gatekeeping(&self) -> bool {
+        true
+    }
+
+    fn process(&self, file: &mut PipelineFile, _context: &StageContext) -> std::io::Result<Outcome> {
+        let sanitized = sanitize::sanitize(&file.file, &self.config)?;
+        if !sanitized.removed {
+            return Ok(Outcome::Passed);
+        }
+        file.captured = sanitized.captured;
+        Ok(Outcome::Done(match sanitized.captured {
+            Some(captured) => format!("removed image metadata except the capture date {}", captured.format("%Y-%m-%d %H:%M")),
+            None => "removed image metadata".to_string(),
+        }))
+    }
+}
+
+/**
+ * Recognizes the text of scanned documents, so they can be categorized by their content
+ */
+#[cfg(feature = "ocr")]
+pub struct OcrStage {}
+
+#[cfg(feature = "ocr")]
+impl Stage for OcrStage {
+    fn name(&self) -> &str {
+        "ocr"
+    }
+
+    fn phase(&self) -> Phase {
+        Phase::Downloaded
+    }
+
+    fn process(&self, file: &mut PipelineFile, _context: &StageContext) -> std::io::Result<Outcome> {
+        if !ocr::Tesseract::needs_recognition(&file.file) {
+            return Ok(Outcome::Passed);
+        }
+        match ocr::Tesseract::from_env().write_text(&file.file) {
+            Ok(Some(text)) => {
+                file.add_temporary(&text);
+                file.text = Some(text);
+                Ok(Outcome::Done("recognized text".to_string()))
+            }
+            Ok(None) => Ok(Outcome::Skipped("no text found".to_string())),
+            Err(error) => Ok(Outcome::Skipped(format!("recognition failed: {}", error))),
+        }
+    }
+}
+
+/**
+ * Checks the file against the policy of the category it is stored in
+ */
+pub struct PolicyStage {}
+
+impl Stage for PolicyStage {
+    fn name(&self) -> &str {
+        "policy"
+    }
+
+    fn gatekeeping(&self) -> bool {
+        true
+    }
+
+    fn process(&self, file: &mut PipelineFile, context: &StageContext) -> std::io::Result<Outcome> {
+        let policy = match context.path_matcher(file).and_then(|path_matcher| categorizer::policy_for(context.repo, path_matcher)) {
+            Some(policy) => policy,
+            None => return Ok(Outcome::Passed),
+        };
+        let size = std::fs::metadata(&file.file)?.len();
+        let file_type = policy::detect_file(&file.file, Some(&file.name))?;
+        match policy.check(size, &file_type) {
+            Ok(()) => Ok(Outcome::Passed),
+            Err(violation) => Ok(Outcome::Rejected(violation.to_string())),
+        }
+    }
+}
+
+/**
+ * Converts the file to PDF if its category asks for it, optionally keeping the original
+ */
+pub struct ConvertStage {}
+
+impl Stage for ConvertStage {
+    fn name(&self) -> &str {
+        "convert"
+    }
+
+    fn process(&self, file: &mut PipelineFile, context: &StageContext) -> std::io::Result<Outcome> {
+        let conversion = match context.path_matcher(file).and_then(|path_matcher| categorizer::conversion_for(context.repo, path_matcher)) {
+            Some(conversion) => conversion,
+            None => return Ok(Outcome::Passed),
+        };
+        let converted = match Converter::from_env().convert(&file.file, &conversion) {
+            Ok(Some(converted)) => converted,
+            Ok(None) => return Ok(Outcome::Passed),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(Outcome::Skipped(error.to_string())),
+            Err(error) => return Ok(Outcome::Skipped(format!("conversion failed, storing the original: {}", error))),
+        };
+        file.add_temporary(&converted);
+        let mut targets = vec![convert::converted_path(&file.target)];
+        if conversion.keep_original {
+            targets.push(targets[0].with_extension(file.file.extension().unwrap_or_default()));
+        }
+        // The converted file and the original must not replace files that are already stored
+        let mut targets = unused_paths(context.repo, &targets).into_iter();
+        let target = targets.next().unwrap_or_default();
+        if let Some(original) = targets.next() {
+            file.attachments.push(Attachment {
+                source: file.file.clone(),
+                target: original,
+                kind: AttachmentKind::Original,
+            });
+        }
+        file.file = converted;
+        file.target = target;
+        Ok(Outcome::Done(match conversion.format {
+            PdfFormat::Pdf => "converted to PDF".to_string(),
+            PdfFormat::PdfA => "converted to PDF/A".to_string(),
+        }))
+    }
+
+    fn preview(&self, target: PathBuf, category: &Categorized, context: &StageContext) -> PathBuf {
+        match categorizer::conversion_for(context.repo, &category.path_matcher) {
+            Some(_) if convert::is_convertible_name(&target) => convert::converted_path(&target),
+            _ => target,
+        }
+    }
+}
+
+/**
+ * Returns the paths unchanged if none of them exists in the repository, otherwise the paths
+ * with the first number appended to their names, e.g. `scan-2.pdf`, for which none exists
+ */
+fn unused_paths(repo: &Repository, paths: &[PathBuf]) -> Vec<PathBuf> {
+    let exists = |paths: &[PathBuf]| paths.iter().any(|path| Path::new(repo.path()).join(path).exists());
+    if !exists(paths) {
+        return paths.to_vec();
+    }
+    (2..)
+        .map(|number| paths.iter().map(|path| numbered_path(path, number)).collect::<Vec<PathBuf>>())
+        .find(|numbered| !exists(numbered))
+        .unwrap_or_default()
+}
+
+fn numbered_path(path: &Path, number: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, number, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, number),
+    };
+    path.with_file_name(name)
+}
+
+/**
+ * Encrypts the file and its attachments if its category asks for it
+ */
+pub struct EncryptStage {}
+
+impl Stage for EncryptStage {
+    fn name(&self) -> &str {
+        "encrypt"
+    }
+
+    // A file of an encrypted category must not be stored unencrypted
+    fn gatekeeping(&self) -> bool {
+        true
+    }
+
+    fn process(&self, file: &mut PipelineFile, context: &StageContext) -> std::io::Result<Outcome> {
+        let encryption = context.path_matcher(file)
+            .and_then(|path_matcher| categorizer::encryption_for(context.repo, path_matcher))
+            .or_else(|| categorizer::encryption_for_target(context.repo, &file.target));
+        let encryption = match encryption {
+            Some(encryption) => encryption,
+            None => return Ok(Outcome::Passed),
+        };
+        let encrypted = encrypt::encrypted_path(&file.file, encryption.format);
+        file.add_temporary(&encrypted);
+        encrypt::encrypt(&file.file, &encrypted, &encryption)?;
+        file.file = encrypted;
+        file.target = encrypt::encrypted_path(&file.target, encryption.format);
+        let mut attachments = std::mem::take(&mut file.attachments);
+        for attachment in attachments.iter_mut() {
+            let encrypted = encrypt::encrypted_path(&attachment.source, encryption.format);
+            file.add_temporary(&encrypted);
+            encrypt::encrypt(&attachment.source, &encrypted, &encryption)?;
+            attachment.source = encrypted;
+            attachment.target = encrypt::encrypted_path(&attachment.target, encryption.format);
+        }
+        file.attachments = attachments;
+        file.encrypted = true;
+        Ok(Outcome::Done(format!("encrypted for {} recipients", encryption.recipients.len())))
+    }
+
+    fn preview(&self, target: PathBuf, category: &Categorized, context: &StageContext) -> PathBuf {
+        let encryption = categorizer::encryption_for(context.repo, &category.path_matcher)
+            .or_else(|| categorizer::encryption_for_target(context.repo, &target));
+        match encryption {
+            Some(encryption) => encrypt::encrypted_path(&target, encryption.format),
+            None => target,
+        }
+    }
+}
+
+/**
+ * Stores the recognized text and the metadata of PDFs next to the file
+ */
+pub struct MetadataStage {}
+
+impl Stage for MetadataStage {
+    fn name(&self) -> &str {
+        "metadata"
+    }
+
+    fn process(&self, file: &mut PipelineFile, _context: &StageContext) -> std::io::Result<Outcome> {
+        // Recognized text and metadata would reveal the content of encrypted files
+        if file.encrypted {
+            return Ok(Outcome::Skipped("the file is encrypted".to_string()));
+        }
+        let mut stored = Vec::new();
+        if let Some(text) = file.text.clone() {
+            // The text must be next to a converted file to be found when extracting
+            let text_path = metadata::text_path(&file.file);
+            if text_path != text {
+                std::fs::copy(&text, &text_path)?;
+                file.add_temporary(&text_path);
+            }
+            file.attachments.push(Attachment {
+                source: text_path,
+                target: metadata::text_path(&file.target),
+                kind: AttachmentKind::Sidecar,
+            });
+            stored.push("recognized text");
+        }
+        if let Some(extracted) = metadata::extract_metadata(&file.file, file.caption.as_deref(), &file.tags()) {
+            let sidecar = metadata::write_sidecar(&file.file, &extracted)?;
+            file.add_temporary(&sidecar);
+            file.attachments.push(Attachment {
+                source: sidecar,
+                target: metadata::sidecar_path(&file.target),
+                kind: AttachmentKind::Sidecar,
+            });
+            stored.push("metadata");
+        }
+        if stored.is_empty() {
+            return Ok(Outcome::Passed);
+        }
+        Ok(Outcome::Done(format!("stored {}", stored.join(" and "))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RepositorySettings;
+
+    fn repository(dir: &Path) -> Repository {
+        let settings = RepositorySettings { path: dir.to_string_lossy().to_string(), ..Default::default() };
+        Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string())
+    }
+
+    /**
+     * Stage that fails with the name as error
+     */
+    struct FailingStage {
+        name: &'static str,
+        gatekeeping: bool
+    }
+
+    impl Stage for FailingStage {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn gatekeeping(&self) -> bool {
+            self.gatekeeping
+        }
+
+        fn process(&self, _file: &mut PipelineFile, _context: &StageContext) -> std::io::Result<Outcome> {
+            Err(std::io::Error::other(self.name))
+        }
+    }
+
+    #[test]
+    fn only_failing_gatekeeping_stages_reject_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repository(dir.path());
+        let context = StageContext::new(&repo, 1);
+        let mut file = PipelineFile::new(&dir.path().join("scan.pdf"), "scan.pdf", None);
+        let pipeline = Pipeline::new(vec![
+            Box::new(FailingStage { name: "enrich", gatekeeping: false }),
+            Box::new(FailingStage { name: "check", gatekeeping: true }),
+            Box::new(FailingStage { name: "never", gatekeeping: false }),
+        ]);
+
+        assert!(!pipeline.run(Phase::Resolved, &mut file, &context));
+        assert_eq!(file.report(), vec!["enrich: skipped, failed: enrich", "check: rejected, failed: check"]);
+        assert!(pipeline.run(Phase::Downloaded, &mut file, &context));
+    }
+
+    fn stage_names(pipeline: &Pipeline) -> Vec<&str> {
+        pipeline.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    fn stage_config(stage: &str, options: Option<serde_json::Value>, enabled: bool) -> StageConfig {
+        StageConfig { stage: stage.to_string(), options, enabled }
+    }
+
+    #[test]
+    fn merges_configured_stages_into_the_defaults() {
+        let mut registry = StageRegistry::with_defaults();
+        registry.register("enrich", |_| Ok(Box::new(FailingStage { name: "enrich", gatekeeping: false })));
+        registry.register("check", |_| Ok(Box::new(FailingStage { name: "check", gatekeeping: true })));
+        let defaults: Vec<&str> = DEFAULT_STAGES.iter().copied().filter(|name| cfg!(feature = "ocr") || *name != "ocr").collect();
+
+        assert_eq!(stage_names(&registry.pipeline(&[]).unwrap()), defaults);
+
+        let pipeline = registry.pipeline(&[
+            stage_config("sanitize", None, false),
+            stage_config("enrich", None, true),
+            stage_config("policy", None, true),
+            stage_config("check", None, true),
+            stage_config("metadata", None, false),
+        ]).unwrap();
+        let mut expected: Vec<&str> = defaults.iter().copied().filter(|name| *name != "sanitize" && *name != "metadata").collect();
+        let encrypt = expected.iter().position(|name| *name == "encrypt").unwrap();
+        expected.splice(encrypt..encrypt, ["enrich", "check"]);
+        assert_eq!(stage_names(&pipeline), expected);
+
+        assert!(matches!(registry.pipeline(&[stage_config("unknown", None, true)]), Err(PipelineError::UnknownStage(_))));
+        let options = Some(serde_json::json!({"keepCaptureDate": "yes"}));
+        assert!(matches!(registry.pipeline(&[stage_config("sanitize", options, true)]), Err(PipelineError::InvalidOptions(_, _))));
+    }
+
+    #[test]
+    fn sanitize_can_be_disabled() {
+        let registry = StageRegistry::with_defaults();
+        let stages: Vec<StageConfig> = serde_json::from_str(r#"[{"stage": "sanitize", "enabled": false}]"#).unwrap();
+        assert!(!stage_names(&registry.pipeline(&stages).unwrap()).contains(&"sanitize"));
+
+        let stages: Vec<StageConfig> = serde_json::from_str(r#"[{"stage": "sanitize", "options": {"keepCaptureDate": true}}]"#).unwrap();
+        assert!(stages[0].enabled);
+        assert!(stage_names(&registry.pipeline(&stages).unwrap()).contains(&"sanitize"));
+    }
+
+    #[test]
+    fn malformed_photos_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repository(dir.path());
+        let context = StageContext::new(&repo, 1);
+        let pipeline = StageRegistry::with_defaults().pipeline(&[]).unwrap();
+        // Start of image followed by an APP1 segment without a marker
+        let staged = dir.path().join("photo.jpg");
+        std::fs::write(&staged, [0xFF, 0xD8, 0xFF, 0xE1, 0x00, 0x08, b'E', b'x', b'i', b'f', 0x00, 0x00, 0x12, 0x34]).unwrap();
+        let mut file = PipelineFile::new(&staged, "photo.jpg", None);
+
+        assert!(!pipeline.run(Phase::Downloaded, &mut file, &context));
+        assert_eq!(file.report(), vec!["sanitize: rejected, failed: invalid JPEG file"]);
+    }
+
+    #[test]
+    fn conversion_is_skipped_without_libreoffice() {
+        std::env::set_var("SOFFICE", "/missing/soffice");
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("categories.json"), r#"{
+            "defaultCategory": "inbox/+",
+            "categories": [{"tags": ["letter"], "pathMatcher": "letters/+", "conversion": {"format": "pdfa"}}]
+        }"#).unwrap();
+        let repo = repository(dir.path());
+        let context = StageContext::new(&repo, 1);
+        let staged = dir.path().join("staged.docx");
+        std::fs::write(&staged, "PK").unwrap();
+        let mut file = PipelineFile::new(&staged, "letter.docx", None);
+        file.category = Some(Categorized::new("letters/+".to_string(), CategorySource::Default));
+        file.target = PathBuf::from("letters/1.docx");
+
+        let outcome = ConvertStage {}.process(&mut file, &context).unwrap();
+        assert_eq!(outcome, Outcome::Skipped("/missing/soffice not available".to_string()));
+        assert_eq!(file.file, staged);
+        assert_eq!(file.target, PathBuf::from("letters/1.docx"));
+    }
+
+    #[test]
+    fn files_stored_verbatim_in_an_encrypted_directory_are_encrypted() {
+        encrypt::tests::stand_in_age();
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("categories.json"), r#"{
+            "defaultCategory": "inbox/+",
+            "categories": [{"tags": ["tax"], "pathMatcher": "taxes/+", "encryption": {"recipients": ["age1tax"]}}]
+        }"#).unwrap();
+        let repo = repository(dir.path());
+        let context = StageContext::new(&repo, 1);
+        let staged = dir.path().join("staged.pdf");
+        std::fs::write(&staged, "tax return\n").unwrap();
+        let mut file = PipelineFile::new(&staged, "return.pdf", Some("taxes/return.pdf"));
+        let category = Categorized::new("taxes/return.pdf".to_string(), CategorySource::Verbatim);
+        file.category = Some(category.clone());
+        file.target = PathBuf::from("taxes/return.pdf");
+
+        let stage = EncryptStage {};
+        assert_eq!(stage.preview(file.target.clone(), &category, &context), PathBuf::from("taxes/return.pdf.age"));
+        assert!(matches!(stage.process(&mut file, &context).unwrap(), Outcome::Done(_)));
+        assert_eq!(file.target, PathBuf::from("taxes/return.pdf.age"));
+        assert!(file.encrypted);
+        assert_eq!(std::fs::read_to_string(&file.file).unwrap(), "age age1tax\ntax return\n");
+    }
+
+    #[test]
+    fn unused_paths_numbers_all_paths_on_collision() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("docs")).unwrap();
+        let repo = repository(dir.path());
+        let paths = vec![PathBuf::from("docs/scan.pdf"), PathBuf::from("docs/scan.jpg")];
+
+        assert_eq!(unused_paths(&repo, &paths), paths);
+        std::fs::write(dir.path().join("docs/scan.jpg"), "").unwrap();
+        std::fs::write(dir.path().join("docs/scan-2.pdf"), "").unwrap();
+        assert_eq!(unused_paths(&repo, &paths), vec![PathBuf::from("docs/scan-3.pdf"), PathBuf::from("docs/scan-3.jpg")]);
+        assert_eq!(numbered_path(Path::new("docs/README"), 2), PathBuf::from("docs/README-2"));
+    }
+}
@@ -0,0 +1,347 @@
+use serde::{Serialize, Deserialize};
+
+/**
+ * Number of bytes needed to detect the type of a file
+ */
+pub const HEADER_SIZE: usize = 4096;
+
+/**
+ * Rough kind of a file, can be used in the policies instead of single types
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileKind {
+    Document,
+    Image,
+    Text,
+    Archive,
+    Executable,
+    Unknown
+}
+
+impl FileKind {
+    fn name(&self) -> &'static str {
+        match self {
+            FileKind::Document => "document",
+            FileKind::Image => "image",
+            FileKind::Text => "text",
+            FileKind::Archive => "archive",
+            FileKind::Executable => "executable",
+            FileKind::Unknown => "unknown",
+        }
+    }
+}
+
+/**
+ * Type of a file as detected from its magic bytes
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileType {
+    pub mime: &'static str,
+    pub extension: &'static str,
+    pub kind: FileKind
+}
+
+impl FileType {
+    const fn new(mime: &'static str, extension: &'static str, kind: FileKind) -> FileType {
+        FileType { mime, extension, kind }
+    }
+
+    /**
+     * Matches a MIME type like `application/pdf`, all subtypes of a type with a trailing
+     * asterisk like `image/` followed by `*`, an extension like `pdf` or `.pdf`, or a kind like `archive`
+     */
+    fn matches(&self, entry: &str) -> bool {
+        let entry = entry.trim().to_lowercase();
+        if let Some(prefix) = entry.strip_suffix("/*") {
+            return self.mime.split('/').next() == Some(prefix);
+        }
+        entry == self.mime || entry.trim_start_matches('.') == self.extension || entry == self.kind.name()
+    }
+}
+
+impl std::fmt::Display for FileType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.extension, self.mime)
+    }
+}
+
+/**
+ * Detects the type of a file from its first bytes, the name only tells apart
+ * the office formats that are zip archives
+ */
+pub fn detect(header: &[u8], file_name: Option<&str>) -> FileType {
+    let starts = |magic: &[u8]| header.starts_with(magic);
+    let extension = file_name
+        .and_then(|name| name.rsplit_once('.'))
+        .map(|(_, extension)| extension.to_lowercase())
+        .unwrap_or_default();
+    if starts(b"%PDF-") {
+        FileType::new("application/pdf", "pdf", FileKind::Document)
+    } else if starts(&[0xFF, 0xD8, 0xFF]) {
+        FileType::new("image/jpeg", "jpg", FileKind::Image)
+    } else if starts(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        FileType::new("image/png", "png", FileKind::Image)
+    } else if starts(b"GIF87a") || starts(b"GIF89a") {
+        FileType::new("image/gif", "gif", FileKind::Image)
+    } else if starts(b"II*\0") || starts(b"MM\0*") {
+        FileType::new("image/tiff", "tiff", FileKind::Image)
+    } else if starts(b"RIFF") && header.get(8..12) == Some(b"WEBP") {
+        FileType::new("image/webp", "webp", FileKind::Image)
+    } else if header.get(4..8) == Some(b"ftyp") && matches!(header.get(8..12), Some(b"heic" | b"heix" | b"mif1" | b"msf1")) {
+        FileType::new("image/heic", "heic", FileKind::Image)
+    } else if starts(b"PK\x03\x04") {
+        detect_zip(header, &extension)
+    } else if starts(&[0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1]) {
+        match extension.as_str() {
+            "xls" => FileType::new("application/vnd.ms-excel", "xls", FileKind::Document),
+            "ppt" => FileType::new("application/vnd.ms-powerpoint", "ppt", FileKind::Document),
+            _ => FileType::new("application/msword", "doc", FileKind::Document),
+        }
+    } else if starts(b"{\\rtf") {
+        FileType::new("application/rtf", "rtf", FileKind::Document)
+    } else if starts(b"Rar!\x1A\x07") {
+        FileType::new("application/vnd.rar", "rar", FileKind::Archive)
+    } else if starts(&[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]) {
+        FileType::new("application/x-7z-compressed", "7z", FileKind::Archive)
+    } else if starts(&[0x1F, 0x8B]) {
+        FileType::new("application/gzip", "gz", FileKind::Archive)
+    } else if starts(b"BZh") {
+        FileType::new("application/x-bzip2", "bz2", FileKind::Archive)
+    } else if starts(&[0xFD, b'7', b'z', b'X', b'Z', 0x00]) {
+        FileType::new("application/x-xz", "xz", FileKind::Archive)
+    } else if header.get(257..262) == Some(b"ustar") {
+        FileType::new("application/x-tar", "tar", FileKind::Archive)
+    } else if starts(b"\x7FELF") {
+        FileType::new("application/x-elf", "elf", FileKind::Executable)
+    } else if starts(b"MZ") {
+        FileType::new("application/x-msdownload", "exe", FileKind::Executable)
+    } else if starts(&[0xFE, 0xED, 0xFA, 0xCE]) || starts(&[0xFE, 0xED, 0xFA, 0xCF])
+        || starts(&[0xCE, 0xFA, 0xED, 0xFE]) || starts(&[0xCF, 0xFA, 0xED, 0xFE]) || starts(&[0xCA, 0xFE, 0xBA, 0xBE]) {
+        FileType::new("application/x-mach-binary", "macho", FileKind::Executable)
+    } else if starts(b"#!") {
+        FileType::new("text/x-shellscript", "sh", FileKind::Executable)
+    } else if is_text(header) {
+        FileType::new("text/plain", "txt", FileKind::Text)
+    } else {
+        FileType::new("application/octet-stream", "bin", FileKind::Unknown)
+    }
+}
+
+/**
+ * OpenDocument files start with a `mimetype` entry, Office Open XML files with `[Content_Types].xml`
+ */
+fn detect_zip(header: &[u8], extension: &str) -> FileType {
+    let contains = |needle: &[u8]| header.windows(needle.len()).any(|window| window == needle);
+    if contains(b"mimetypeapplication/vnd.oasis.opendocument.text") {
+        FileType::new("application/vnd.oasis.opendocument.text", "odt", FileKind::Document)
+    } else if contains(b"mimetypeapplication/vnd.oasis.opendocument.spreadsheet") {
+        FileType::new("application/vnd.oasis.opendocument.spreadsheet", "ods", FileKind::Document)
+    } else if contains(b"mimetypeapplication/vnd.oasis.opendocument.presentation") {
+        FileType::new("application/vnd.oasis.opendocument.presentation", "odp", FileKind::Document)
+    } else if contains(b"[Content_Types].xml") || contains(b"word/") || contains(b"xl/") || contains(b"ppt/") {
+        match extension {
+            "xlsx" => FileType::new("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet", "xlsx", FileKind::Document),
+            "pptx" => FileType::new("application/vnd.openxmlformats-officedocument.presentationml.presentation", "pptx", FileKind::Document),
+            _ => FileType::new("application/vnd.openxmlformats-officedocument.wordprocessingml.document", "docx", FileKind::Document),
+        }
+    } else {
+        FileType::new("application/zip", "zip", FileKind::Archive)
+    }
+}
+
+fn is_text(header: &[u8]) -> bool {
+    if header.contains(&0) {
+        return false;
+    }
+    match std::str::from_utf8(header) {
+        Ok(_) => true,
+        // The header may end within a multi byte character
+        Err(error) => error.error_len().is_none(),
+    }
+}
+
+/**
+ * Reads the first bytes of a file and detects its type
+ */
+pub fn detect_file(path: &std::path::Path, file_name: Option<&str>) -> std::io::Result<FileType> {
+    let mut header = Vec::with_capacity(HEADER_SIZE);
+    std::io::Read::read_to_end(&mut std::io::Read::take(std::fs::File::open(path)?, HEADER_SIZE as u64), &mut header)?;
+    Ok(detect(&header, file_name))
+}
+
+fn default_denied_types() -> Vec<String> {
+    vec!["executable".to_string(), "archive".to_string()]
+}
+
+/**
+ * Files that may be stored, e.g. `{"maxSize": 10000000, "allowedTypes": ["application/pdf", "image"]}`
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct FilePolicy {
+    /**
+     * Largest file in bytes
+     */
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /**
+     * MIME types, extensions or kinds that are allowed, all if empty
+     */
+    #[serde(default)]
+    pub allowed_types: Vec<String>,
+    /**
+     * MIME types, extensions or kinds that are never allowed, executables and archives if missing
+     */
+    #[serde(default = "default_denied_types")]
+    pub denied_types: Vec<String>
+}
+
+impl Default for FilePolicy {
+    fn default() -> FilePolicy {
+        FilePolicy { max_size: None, allowed_types: Vec::new(), denied_types: default_denied_types() }
+    }
+}
+
+/**
+ * Reason a file is rejected
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PolicyViolation {
+    TooLarge { size: u64, max_size: u64 },
+    Denied(FileType),
+    NotAllowed(FileType)
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PolicyViolation::TooLarge { size, max_size } =>
+                write!(f, "The file has {}, only files up to {} are accepted", format_size(*size), format_size(*max_size)),
+            PolicyViolation::Denied(file_type) => write!(f, "Files of type {} are not accepted", file_type),
+            PolicyViolation::NotAllowed(file_type) => write!(f, "Files of type {} are not accepted here", file_type),
+        }
+    }
+}
+
+impl std::error::Error for PolicyViolation {}
+
+impl FilePolicy {
+    pub fn check_size(&self, size: u64) -> Result<(), PolicyViolation> {
+        match self.max_size {
+            Some(max_size) if size > max_size => Err(PolicyViolation::TooLarge { size, max_size }),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn check_type(&self, file_type: &FileType) -> Result<(), PolicyViolation> {
+        if self.denied_types.iter().any(|entry| file_type.matches(entry)) {
+            return Err(PolicyViolation::Denied(file_type.clone()));
+        }
+        if !self.allowed_types.is_empty() && !self.allowed_types.iter().any(|entry| file_type.matches(entry)) {
+            return Err(PolicyViolation::NotAllowed(file_type.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, size: u64, file_type: &FileType) -> Result<(), PolicyViolation> {
+        self.check_size(size)?;
+        self.check_type(file_type)
+    }
+}
+
+fn format_size(size: u64) -> String {
+    match size {
+        size if size >= 1024 * 1024 => format!("{:.1} MB", size as f64 / (1024.0 * 1024.0)),
+        size if size >= 1024 => format!("{:.1} KB", size as f64 / 1024.0),
+        size => format!("{} bytes", size),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extension(header: &[u8], file_name: Option<&str>) -> &'static str {
+        detect(header, file_name).extension
+    }
+
+    #[test]
+    fn detects_documents_and_images_from_magic_bytes() {
+        assert_eq!(detect(b"%PDF-1.7\n", Some("scan.jpg")), FileType::new("application/pdf", "pdf", FileKind::Document));
+        assert_eq!(extension(&[0xFF, 0xD8, 0xFF, 0xE0], None), "jpg");
+        assert_eq!(extension(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A, 0x00], None), "png");
+        assert_eq!(extension(b"GIF89a", None), "gif");
+        assert_eq!(extension(b"II*\0\x08\0\0\0", None), "tiff");
+        assert_eq!(extension(b"MM\0*\0\0\0\x08", None), "tiff");
+        assert_eq!(extension(b"RIFF\x24\0\0\0WEBPVP8 ", None), "webp");
+        assert_eq!(extension(b"\0\0\0\x18ftypheic\0\0\0\0", None), "heic");
+        // Other ISO media files are no HEIC images
+        assert_eq!(extension(b"\0\0\0\x18ftypisom\0\0\0\0", None), "bin");
+        assert_eq!(extension(b"{\\rtf1\\ansi", None), "rtf");
+    }
+
+    #[test]
+    fn tells_apart_office_formats_by_content_and_name() {
+        let mut odt = b"PK\x03\x04".to_vec();
+        odt.extend_from_slice(&[0; 26]);
+        odt.extend_from_slice(b"mimetypeapplication/vnd.oasis.opendocument.text");
+        assert_eq!(extension(&odt, Some("letter.zip")), "odt");
+
+        let mut ooxml = b"PK\x03\x04".to_vec();
+        ooxml.extend_from_slice(&[0; 26]);
+        ooxml.extend_from_slice(b"[Content_Types].xml");
+        assert_eq!(extension(&ooxml, Some("letter.docx")), "docx");
+        assert_eq!(extension(&ooxml, Some("Table.XLSX")), "xlsx");
+        assert_eq!(extension(&ooxml, Some("talk.pptx")), "pptx");
+        assert_eq!(extension(&ooxml, None), "docx");
+        assert_eq!(detect(b"PK\x03\x04\x14\0\0\0", Some("letter.docx")).kind, FileKind::Archive);
+
+        let ole = [0xD0, 0xCF, 0x11, 0xE0, 0xA1, 0xB1, 0x1A, 0xE1];
+        assert_eq!(extension(&ole, Some("table.xls")), "xls");
+        assert_eq!(extension(&ole, Some("talk.ppt")), "ppt");
+        assert_eq!(extension(&ole, Some("letter")), "doc");
+    }
+
+    #[test]
+    fn detects_archives_and_executables() {
+        assert_eq!(extension(b"Rar!\x1A\x07\x01\0", None), "rar");
+        assert_eq!(extension(&[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C], None), "7z");
+        assert_eq!(extension(&[0x1F, 0x8B, 0x08], None), "gz");
+        assert_eq!(extension(b"BZh91AY", None), "bz2");
+        assert_eq!(extension(&[0xFD, b'7', b'z', b'X', b'Z', 0x00], None), "xz");
+        let mut tar = vec![0u8; 512];
+        tar[257..262].copy_from_slice(b"ustar");
+        assert_eq!(detect(&tar, Some("backup.pdf")).kind, FileKind::Archive);
+
+        assert_eq!(detect(b"\x7FELF\x02\x01", Some("invoice.pdf")).kind, FileKind::Executable);
+        assert_eq!(extension(b"MZ\x90\0", None), "exe");
+        assert_eq!(extension(&[0xCF, 0xFA, 0xED, 0xFE], None), "macho");
+        assert_eq!(extension(&[0xCA, 0xFE, 0xBA, 0xBE], None), "macho");
+        assert_eq!(extension(b"#!/bin/sh\nrm -rf /\n", Some("notes.txt")), "sh");
+    }
+
+    #[test]
+    fn detects_text_and_unknown_files() {
+        assert_eq!(detect(b"Rechnung \xC3\xBCber 10 \xE2\x82\xAC\n", None).kind, FileKind::Text);
+        // Cut within the euro sign at the end of the header
+        assert_eq!(detect(b"10 \xE2\x82", None).kind, FileKind::Text);
+        assert_eq!(detect(b"\xE2\x28\xA1 invalid", None).kind, FileKind::Unknown);
+        assert_eq!(detect(b"text\0with nul", None).kind, FileKind::Unknown);
+        assert_eq!(detect(&[], None).kind, FileKind::Text);
+    }
+
+    #[test]
+    fn policy_matches_types_extensions_and_kinds() {
+        let pdf = detect(b"%PDF-1.4", None);
+        let zip = detect(b"PK\x03\x04\x14\0\0\0", None);
+        let policy = FilePolicy { max_size: Some(1024), allowed_types: vec!["application/pdf".to_string(), "image/*".to_string()], ..Default::default() };
+
+        assert_eq!(policy.check(1024, &pdf), Ok(()));
+        assert_eq!(policy.check(2048, &pdf), Err(PolicyViolation::TooLarge { size: 2048, max_size: 1024 }));
+        assert_eq!(policy.check(10, &detect(b"GIF87a", None)), Ok(()));
+        assert_eq!(policy.check(10, &zip), Err(PolicyViolation::Denied(zip.clone())));
+        assert!(matches!(policy.check(10, &detect(b"plain", None)), Err(PolicyViolation::NotAllowed(_))));
+        assert!(pdf.matches(".PDF"));
+        assert!(zip.matches("archive"));
+    }
+}
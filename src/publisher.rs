@@ -1,18 +1,63 @@
-use std::path::Path;
-use git2::{Oid, ObjectType, Commit, Direction, RemoteCallbacks, Tree};
+use std::{path::{Path, PathBuf}, process::{Command, Stdio}, io::Write};
+use git2::{Oid, ObjectType, Commit, Delta, Direction, RemoteCallbacks, Tree};
 
-use crate::config::Repository;
+use crate::{
+    commit_messages::{self, CHAT_TRAILER, REVERTS_TRAILER},
+    config::{Author, Repository, SigningConfig, SigningFormat},
+    lfs,
+    metadata,
+    path_matcher,
+};
 
 pub trait Publisher {
     /**
-     * Add a file to the repository, commits it and pushs it to the server
+     * Add files to the repository, commits them and pushs them to the server
      */
-    fn publish_file(&self, repo: &Repository, added_file: &Path, message: &String) -> Result<Oid, git2::Error>;
+    fn publish_files(&self, repo: &Repository, added_files: &[&Path], author: &Author, message: &str) -> Result<Oid, git2::Error>;
+
+    /**
+     * Moves a file and its sidecars within the repository, commits the rename and pushs it to the server
+     */
+    fn move_file(&self, repo: &Repository, from: &Path, to: &Path, author: &Author, message: &str) -> Result<Oid, git2::Error>;
+
+    /**
+     * Removes a file and its sidecars from the index and the working tree, commits it and pushs it to the server
+     */
+    fn remove_file(&self, repo: &Repository, removed_file: &Path, author: &Author, message: &str) -> Result<Oid, git2::Error>;
 
     /**
      * Updates the filesystem
      */
     fn update_files(&self, repo: &Repository) -> Result<(), git2::Error>;
+
+    /**
+     * Reverts the most recent commit created from the chat and pushs the revert.
+     * Fails if commits from other sources were made on top of it.
+     */
+    fn revert_last(&self, repo: &Repository, chat_id: i64, author: &Author) -> Result<Revert, git2::Error>;
+
+    /**
+     * Content of a file as it was committed, e.g. of a file a revert removed
+     */
+    fn read_file(&self, repo: &Repository, commit: Oid, path: &Path) -> Result<Vec<u8>, git2::Error>;
+}
+
+/**
+ * Result of reverting a commit
+ */
+pub struct Revert {
+    pub reverted: Oid,
+    pub summary: String,
+    pub files: Vec<String>,
+    /**
+     * Files the reverted commit added, the revert removed them
+     */
+    pub removed: Vec<String>,
+    /**
+     * Files the reverted commit removed, the revert restored them
+     */
+    pub restored: Vec<String>,
+    pub commit: Oid
 }
 
 
@@ -75,34 +120,204 @@ impl GitPublisher {
         callbacks
     }
 
-    fn add_to_index<'a>(&'a self, repo: &'a git2::Repository, added_file: &Path) -> Result<Tree, git2::Error>{
-        let mut index = repo.index()?;
-        
-        index.add_path(added_file)?;
-        index.write_tree()?;
-        let oid = index.write_tree()?;
+    /**
+     * Adds the files to the index in memory, it is written once the commit is created
+     */
+    fn add_to_index<'a>(&'a self, git_repo: &'a git2::Repository, repo: &Repository, index: &mut git2::Index, added_files: &[&Path]) -> Result<Tree<'a>, git2::Error>{
+        for added_file in added_files {
+            self.stage_file(git_repo, repo, index, added_file)?;
+            log::info!("[repo: {}] Added file {} to index", git_repo.path().display(), added_file.display());
+        }
+        let oid = index.write_tree_to(git_repo)?;
         
-        log::info!("[repo: {}] Added file {} to index", repo.path().display(), added_file.display());
-        repo.find_tree(oid)
+        git_repo.find_tree(oid)
+    }
+
+    /**
+     * Adds the file to the index, files tracked by LFS are added as pointer.
+     * Their content has to be uploaded with `lfs::upload_tracked` before.
+     */
+    fn stage_file(&self, git_repo: &git2::Repository, repo: &Repository, index: &mut git2::Index, file: &Path) -> Result<(), git2::Error> {
+        if !lfs::is_tracked(git_repo, file) {
+            return index.add_path(file);
+        }
+        let workdir_file = Path::new(repo.path()).join(file);
+        let pointer = lfs::Pointer::from_file(&workdir_file).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+        lfs::store_local(git_repo, &workdir_file, &pointer).map_err(|e| git2::Error::from_str(&e.to_string()))?;
+
+        let contents = pointer.contents();
+        let path = file.to_string_lossy().replace('\\', "/").into_bytes();
+        let entry = git2::IndexEntry {
+            ctime: git2::IndexTime::new(0, 0),
+            mtime: git2::IndexTime::new(0, 0),
+            dev: 0,
+            ino: 0,
+            mode: 0o100644,
+            uid: 0,
+            gid: 0,
+            file_size: contents.len() as u32,
+            id: Oid::zero(),
+            flags: path.len().min(0xFFF) as u16,
+            flags_extended: 0,
+            path,
+        };
+        index.add_frombuffer(&entry, contents.as_bytes())?;
+        log::info!("[repo: {}] Uploaded {} to LFS as {}", git_repo.path().display(), file.display(), pointer.oid);
+        Ok(())
     }
 
-    fn create_commit(&self, git_repo: &git2::Repository, sign: &git2::Signature, tree: &Tree, parent_commit: &Commit, message: &String)-> Result<Oid, git2::Error>{
-        let commit_id = git_repo.commit(Some("HEAD"), &sign, &sign, message, &tree, &[&parent_commit])?;
+    /**
+     * Walks the history from HEAD and returns the latest not yet reverted commit of the chat
+     */
+    fn find_last_chat_commit<'a>(&'a self, repo: &'a git2::Repository, chat_id: i64) -> Result<Commit<'a>, git2::Error> {
+        let chat = chat_id.to_string();
+        let mut reverted: Vec<Oid> = Vec::new();
+        let mut current = Some(self.find_last_commit(repo)?);
+        while let Some(commit) = current {
+            let message = commit.message().unwrap_or("");
+            if commit_messages::get_trailer(message, CHAT_TRAILER) != Some(chat.as_str()) {
+                return Err(git2::Error::from_str(&format!(
+                    "Commit {} was not made from this chat, refusing to undo",
+                    commit.id()
+                )));
+            }
+            if let Some(target) = commit_messages::get_trailer(message, REVERTS_TRAILER) {
+                reverted.push(Oid::from_str(target)?);
+            } else if !reverted.contains(&commit.id()) {
+                return Ok(commit);
+            }
+            current = commit.parents().next();
+        }
+        Err(git2::Error::from_str("Nothing to undo"))
+    }
+
+    fn changed_files(&self, repo: &git2::Repository, commit: &Commit) -> Result<Vec<(Delta, String)>, git2::Error> {
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        Ok(diff.deltas()
+            .filter_map(|delta| {
+                let path = delta.new_file().path().or(delta.old_file().path())?;
+                Some((delta.status(), path.display().to_string()))
+            })
+            .collect())
+    }
+
+    fn create_commit(&self, git_repo: &git2::Repository, repo: &Repository, author: &Author, tree: &Tree, parent_commit: &Commit, message: &str)-> Result<Oid, git2::Error>{
+        let author = author.signature()?;
+        let committer = repo.signature()?;
+        let commit_id = match &repo.settings().signing {
+            Some(signing) => {
+                let buffer = git_repo.commit_create_buffer(&author, &committer, message, tree, &[parent_commit])?;
+                let content = buffer.as_str().ok_or_else(|| git2::Error::from_str("Commit buffer is not valid utf-8"))?;
+                let signature = sign_buffer(signing, content)?;
+                let commit_id = git_repo.commit_signed(content, &signature, None)?;
+                git_repo.head()?.resolve()?.set_target(commit_id, "commit (signed)")?;
+                commit_id
+            }
+            None => git_repo.commit(Some("HEAD"), &author, &committer, message, tree, &[parent_commit])?,
+        };
         log::info!("[repo: {}] Created commit {}", git_repo.path().display(), commit_id);
         Ok(commit_id)
     }
 }
 
 impl Publisher for GitPublisher {
-    fn publish_file(&self, repo: &Repository, added_file: &Path, message: &String) -> Result<Oid, git2::Error> {
+    fn publish_files(&self, repo: &Repository, added_files: &[&Path], author: &Author, message: &str) -> Result<Oid, git2::Error> {
+        let git_repo = git2::Repository::open(repo.path())?;
+
+        self.pull(&git_repo)?;
+
+        let mut index = git_repo.index()?;
+        let tree = self.add_to_index(&git_repo, repo, &mut index, added_files)?;       
+
+        let parent_commit = self.find_last_commit(&git_repo)?;
+        let commit_id = self.create_commit(&git_repo, repo, author, &tree, &parent_commit, message)?;
+        index.write()?;
+
+        self.push(&git_repo)?;
+
+        Ok(commit_id)
+    }
+
+    fn move_file(&self, repo: &Repository, from: &Path, to: &Path, author: &Author, message: &str) -> Result<Oid, git2::Error> {
+        let from = checked_path(repo, from)?;
+        let to = checked_path(repo, to)?;
+        let git_repo = git2::Repository::open(repo.path())?;
+
+        self.pull(&git_repo)?;
+
+        // A stale sidecar at the target would describe the moved file, so it has to be free as well
+        let workdir = Path::new(repo.path());
+        let mut index = git_repo.index()?;
+        let mut targets = std::iter::once(to.clone()).chain(metadata::sidecars(&to));
+        if let Some(existing) = targets.find(|target| workdir.join(target).exists() || index.get_path(target, 0).is_some()) {
+            return Err(git2::Error::from_str(&format!("{} already exists", existing.display())));
+        }
+
+        // Move the index entries first, the blobs stay the same so LFS pointers are kept as they are
+        let mut moves = vec![(from.clone(), to.clone())];
+        moves.extend(metadata::sidecars(&from).into_iter()
+            .zip(metadata::sidecars(&to))
+            .filter(|(sidecar, _)| index.get_path(sidecar, 0).is_some()));
+        for (source, moved) in &moves {
+            let mut entry = index.get_path(source, 0)
+                .ok_or_else(|| git2::Error::from_str(&format!("{} is not tracked", source.display())))?;
+            entry.path = moved.to_string_lossy().replace('\\', "/").into_bytes();
+            entry.flags = (entry.flags & !0xFFF) | entry.path.len().min(0xFFF) as u16;
+            index.add(&entry)?;
+            index.remove_path(source)?;
+        }
+        let tree = git_repo.find_tree(index.write_tree()?)?;
+
+        // Rename last, so nothing has to be undone if the index could not be updated
+        rename_files(workdir, &moves)?;
+        let parent_commit = self.find_last_commit(&git_repo)?;
+        let commit_id = match self.create_commit(&git_repo, repo, author, &tree, &parent_commit, message) {
+            Ok(commit_id) => commit_id,
+            Err(error) => {
+                let reverse: Vec<_> = moves.iter().map(|(source, moved)| (moved.clone(), source.clone())).collect();
+                if let Err(rollback) = rename_files(workdir, &reverse) {
+                    log::error!("[repo: {}] Could not move back {}: {}", git_repo.path().display(), to.display(), rollback);
+                }
+                return Err(error);
+            }
+        };
+        index.write()?;
+        log::info!("[repo: {}] Moved {} to {}", git_repo.path().display(), from.display(), to.display());
+
+        self.push(&git_repo)?;
+
+        Ok(commit_id)
+    }
+
+    fn remove_file(&self, repo: &Repository, removed_file: &Path, author: &Author, message: &str) -> Result<Oid, git2::Error> {
         let git_repo = git2::Repository::open(repo.path())?;
 
         self.pull(&git_repo)?;
 
-        let tree = self.add_to_index(&git_repo, added_file)?;       
+        let mut index = git_repo.index()?;
+        index.remove_path(removed_file)?;
+        let sidecars: Vec<_> = metadata::sidecars(removed_file).into_iter()
+            .filter(|sidecar| index.get_path(sidecar, 0).is_some())
+            .collect();
+        for sidecar in &sidecars {
+            index.remove_path(sidecar)?;
+        }
+        let tree = git_repo.find_tree(index.write_tree_to(&git_repo)?)?;
 
+        // The index and the working tree are only changed once the removal is committed
         let parent_commit = self.find_last_commit(&git_repo)?;
-        let commit_id = self.create_commit(&git_repo, &repo.signature()?, &tree, &parent_commit, message)?;
+        let commit_id = self.create_commit(&git_repo, repo, author, &tree, &parent_commit, message)?;
+        index.write()?;
+        for file in std::iter::once(removed_file).chain(sidecars.iter().map(|sidecar| sidecar.as_path())) {
+            if let Err(error) = std::fs::remove_file(Path::new(repo.path()).join(file)) {
+                log::error!("[repo: {}] Could not delete {}: {}", git_repo.path().display(), file.display(), error);
+            }
+        }
+        log::info!("[repo: {}] Removed file {}", git_repo.path().display(), removed_file.display());
 
         self.push(&git_repo)?;
 
@@ -116,4 +331,412 @@ impl Publisher for GitPublisher {
 
         Ok(())
     }
+
+    fn revert_last(&self, repo: &Repository, chat_id: i64, author: &Author) -> Result<Revert, git2::Error> {
+        let git_repo = git2::Repository::open(repo.path())?;
+
+        self.pull(&git_repo)?;
+
+        let target = self.find_last_chat_commit(&git_repo, chat_id)?;
+        let head = self.find_last_commit(&git_repo)?;
+        let mut index = git_repo.revert_commit(&target, &head, 0, None)?;
+        if index.has_conflicts() {
+            return Err(git2::Error::from_str(&format!("Reverting {} causes conflicts", target.id())));
+        }
+        let tree = git_repo.find_tree(index.write_tree_to(&git_repo)?)?;
+
+        let summary = target.summary().unwrap_or("").to_string();
+        let message = format!(
+            "Revert \"{}\"\n\nThis reverts commit {}.\n\n{}: {}\n{}: {}",
+            summary, target.id(), CHAT_TRAILER, chat_id, REVERTS_TRAILER, target.id()
+        );
+        let commit_id = self.create_commit(&git_repo, repo, author, &tree, &head, &message)?;
+        // Only touch the working tree once the revert is committed, so a failed commit leaves it unchanged
+        git_repo.checkout_head(Some(git2::build::CheckoutBuilder::default().force()))?;
+        log::info!("[repo: {}] Checked out revert of {}", git_repo.path().display(), target.id());
+
+        self.push(&git_repo)?;
+
+        let changed = self.changed_files(&git_repo, &target)?;
+        let with_status = |status: Delta| changed.iter()
+            .filter(|(delta, _)| *delta == status)
+            .map(|(_, path)| path.clone())
+            .collect();
+        Ok(Revert {
+            reverted: target.id(),
+            summary,
+            removed: with_status(Delta::Added),
+            restored: with_status(Delta::Deleted),
+            files: changed.iter().map(|(_, path)| path.clone()).collect(),
+            commit: commit_id,
+        })
+    }
+
+    fn read_file(&self, repo: &Repository, commit: Oid, path: &Path) -> Result<Vec<u8>, git2::Error> {
+        let git_repo = git2::Repository::open(repo.path())?;
+        let entry = git_repo.find_commit(commit)?.tree()?.get_path(path)?;
+        let blob = entry.to_object(&git_repo)?.peel_to_blob()?;
+        Ok(blob.content().to_vec())
+    }
+}
+
+/**
+ * Validates a path given to the publisher, it has to stay inside the writable part of the repository
+ */
+fn checked_path(repo: &Repository, path: &Path) -> Result<PathBuf, git2::Error> {
+    path_matcher::validate_path(repo, &path.to_string_lossy())
+        .map(PathBuf::from)
+        .map_err(|error| git2::Error::from_str(&error.to_string()))
+}
+
+/**
+ * Renames the files in the working tree, the already renamed ones are moved back if one fails
+ */
+fn rename_files(workdir: &Path, moves: &[(PathBuf, PathBuf)]) -> Result<(), git2::Error> {
+    for (i, (source, moved)) in moves.iter().enumerate() {
+        let renamed = match workdir.join(moved).parent() {
+            Some(parent) => std::fs::create_dir_all(parent),
+            None => Ok(()),
+        }.and_then(|_| std::fs::rename(workdir.join(source), workdir.join(moved)));
+        if let Err(error) = renamed {
+            for (source, moved) in moves[..i].iter().rev() {
+                if let Err(error) = std::fs::rename(workdir.join(moved), workdir.join(source)) {
+                    log::error!("Could not move back {}: {}", moved.display(), error);
+                }
+            }
+            return Err(git2::Error::from_str(&format!("Could not move {}: {}", source.display(), error)));
+        }
+    }
+    Ok(())
+}
+
+/**
+ * Creates a detached signature of the commit content with ssh-keygen or gpg,
+ * the same way git does it. The binaries can be replaced with `SSH_KEYGEN` and `GPG`.
+ */
+fn sign_buffer(signing: &SigningConfig, content: &str) -> Result<String, git2::Error> {
+    let mut command = match signing.format {
+        SigningFormat::Ssh => {
+            let mut command = Command::new(std::env::var("SSH_KEYGEN").unwrap_or("ssh-keygen".to_string()));
+            command.args(["-Y", "sign", "-n", "git", "-f", &signing.key]);
+            command
+        }
+        SigningFormat::OpenPgp => {
+            let mut command = Command::new(std::env::var("GPG").unwrap_or("gpg".to_string()));
+            command.args(["--batch", "--armor", "--detach-sign", "--local-user", &signing.key]);
+            command
+        }
+    };
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| git2::Error::from_str(&format!("Signing failed: {}", e)))?;
+    // A signer that fails early closes its input, its error tells more than the broken pipe
+    let written = child.stdin.take()
+        .ok_or_else(|| git2::Error::from_str("Signing failed: no stdin"))?
+        .write_all(content.as_bytes());
+    let output = child.wait_with_output()
+        .map_err(|e| git2::Error::from_str(&format!("Signing failed: {}", e)))?;
+    if !output.status.success() {
+        return Err(git2::Error::from_str(&format!("Signing failed: {}", String::from_utf8_lossy(&output.stderr).trim())));
+    }
+    written.map_err(|e| git2::Error::from_str(&format!("Signing failed: {}", e)))?;
+    String::from_utf8(output.stdout)
+        .map_err(|_| git2::Error::from_str("Signing failed: signature is not valid utf-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::fs::PermissionsExt, sync::OnceLock};
+    use crate::config::RepositorySettings;
+
+    /**
+     * Clone of a bare origin with `docs/a.pdf` and its metadata sidecar committed on master
+     */
+    fn repository(dir: &Path, signing: Option<SigningConfig>) -> (Repository, git2::Repository) {
+        let origin = dir.join("origin.git");
+        git2::Repository::init_bare(&origin).unwrap();
+        let work = dir.join("work");
+        let git_repo = git2::Repository::init_opts(&work, git2::RepositoryInitOptions::new().initial_head("master")).unwrap();
+        git_repo.remote("origin", &origin.to_string_lossy()).unwrap();
+        std::fs::create_dir_all(work.join("docs")).unwrap();
+        std::fs::write(work.join("docs/a.pdf"), "%PDF-1.4").unwrap();
+        std::fs::write(metadata::sidecar_path(&work.join("docs/a.pdf")), "{}").unwrap();
+        let mut index = git_repo.index().unwrap();
+        index.add_path(Path::new("docs/a.pdf")).unwrap();
+        index.add_path(&metadata::sidecar_path(Path::new("docs/a.pdf"))).unwrap();
+        index.write().unwrap();
+        let tree = git_repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("archiver", "archiver@mail.com").unwrap();
+        git_repo.commit(Some("HEAD"), &signature, &signature, "Initial commit", &tree, &[]).unwrap();
+        drop(tree);
+        git_repo.find_remote("origin").unwrap().push(&["refs/heads/master:refs/heads/master"], None).unwrap();
+
+        let settings = RepositorySettings { path: work.to_string_lossy().to_string(), signing, ..Default::default() };
+        (Repository::new(settings, String::new(), "archiver".to_string(), "archiver@mail.com".to_string()), git_repo)
+    }
+
+    fn head(git_repo: &git2::Repository) -> Oid {
+        git_repo.head().unwrap().target().unwrap()
+    }
+
+    fn is_clean(git_repo: &git2::Repository) -> bool {
+        git_repo.statuses(None).unwrap().is_empty()
+    }
+
+    fn failing_signing() -> Option<SigningConfig> {
+        Some(SigningConfig { format: SigningFormat::Ssh, key: "/missing/signing-key".to_string() })
+    }
+
+    /**
+     * Stand-in for `ssh-keygen` and `gpg` that signs with the key `stand-in-key` only, so the
+     * failing signing key fails whether or not the stand-in is installed yet
+     */
+    fn stand_in_signer() -> Option<SigningConfig> {
+        static SIGNER: OnceLock<PathBuf> = OnceLock::new();
+        SIGNER.get_or_init(|| {
+            let path = tempfile::tempdir().unwrap().keep().join("sign");
+            std::fs::write(&path, r#"#!/bin/sh
+prev=""
+for arg in "$@"; do
+    case $prev in -f|--local-user) key=$arg ;; esac
+    prev=$arg
+done
+if [ "$key" != stand-in-key ]; then
+    echo "no such key: $key" >&2
+    exit 1
+fi
+echo "-----BEGIN SIGNATURE-----"
+echo "signed $(wc -c) bytes"
+echo "-----END SIGNATURE-----"
+"#).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            std::env::set_var("SSH_KEYGEN", &path);
+            std::env::set_var("GPG", &path);
+            path
+        });
+        Some(SigningConfig { format: SigningFormat::Ssh, key: "stand-in-key".to_string() })
+    }
+
+    #[test]
+    fn signs_commits_with_the_configured_key() {
+        let signing = stand_in_signer();
+        for format in [SigningFormat::Ssh, SigningFormat::OpenPgp] {
+            let dir = tempfile::tempdir().unwrap();
+            let signing = signing.clone().map(|signing| SigningConfig { format, ..signing });
+            let (repo, git_repo) = repository(dir.path(), signing);
+            std::fs::write(Path::new(repo.path()).join("docs/b.pdf"), "%PDF-1.4").unwrap();
+
+            let commit = GitPublisher::new(String::new())
+                .publish_files(&repo, &[Path::new("docs/b.pdf")], &repo.author(), "Add")
+                .unwrap();
+
+            assert_eq!(head(&git_repo), commit);
+            let (signature, signed) = git_repo.extract_signature(&commit, None).unwrap();
+            assert_eq!(
+                signature.as_str().unwrap(),
+                format!("-----BEGIN SIGNATURE-----\nsigned {} bytes\n-----END SIGNATURE-----\n", signed.len())
+            );
+            assert!(signed.as_str().unwrap().ends_with("\nAdd"));
+            assert!(is_clean(&git_repo));
+        }
+    }
+
+    #[test]
+    fn commits_unsigned_without_signing_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), None);
+        std::fs::write(Path::new(repo.path()).join("docs/b.pdf"), "%PDF-1.4").unwrap();
+
+        let commit = GitPublisher::new(String::new())
+            .publish_files(&repo, &[Path::new("docs/b.pdf")], &repo.author(), "Add")
+            .unwrap();
+
+        assert!(git_repo.extract_signature(&commit, None).is_err());
+        let commit = git_repo.find_commit(commit).unwrap();
+        assert_eq!(commit.author().name(), Some("archiver"));
+        assert_eq!(commit.message(), Some("Add"));
+    }
+
+    #[test]
+    fn reports_signing_failures() {
+        stand_in_signer();
+        let content = "tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\nAdd";
+        let error = sign_buffer(&failing_signing().unwrap(), content).unwrap_err();
+        assert_eq!(error.message(), "Signing failed: no such key: /missing/signing-key");
+        let signing = SigningConfig { format: SigningFormat::OpenPgp, key: "unknown@mail.com".to_string() };
+        let error = sign_buffer(&signing, content).unwrap_err();
+        assert_eq!(error.message(), "Signing failed: no such key: unknown@mail.com");
+    }
+
+    #[test]
+    fn publishes_files_only_if_committed() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), failing_signing());
+        let work = Path::new(repo.path());
+        std::fs::write(work.join("docs/b.pdf"), "%PDF-1.4").unwrap();
+        let before = head(&git_repo);
+
+        let published = GitPublisher::new(String::new())
+            .publish_files(&repo, &[Path::new("docs/b.pdf")], &repo.author(), "Add");
+        assert!(published.is_err());
+        assert_eq!(head(&git_repo), before);
+        assert!(git2::Repository::open(work).unwrap().index().unwrap().get_path(Path::new("docs/b.pdf"), 0).is_none());
+
+        let (repo, git_repo) = repository(&dir.path().join("unsigned"), None);
+        let work = Path::new(repo.path());
+        std::fs::write(work.join("docs/b.pdf"), "%PDF-1.4").unwrap();
+        let commit = GitPublisher::new(String::new())
+            .publish_files(&repo, &[Path::new("docs/b.pdf")], &repo.author(), "Add")
+            .unwrap();
+        assert_eq!(head(&git_repo), commit);
+        assert!(is_clean(&git_repo));
+    }
+
+    #[test]
+    fn removes_file_and_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), None);
+        let work = Path::new(repo.path());
+
+        let commit = GitPublisher::new(String::new())
+            .remove_file(&repo, Path::new("docs/a.pdf"), &repo.author(), "Remove")
+            .unwrap();
+
+        assert_eq!(head(&git_repo), commit);
+        assert!(!work.join("docs/a.pdf").exists());
+        assert!(!metadata::sidecar_path(&work.join("docs/a.pdf")).exists());
+        assert!(git_repo.find_commit(commit).unwrap().tree().unwrap().get_path(Path::new("docs")).is_err());
+        assert!(is_clean(&git_repo));
+        let origin = git2::Repository::open_bare(dir.path().join("origin.git")).unwrap();
+        assert_eq!(origin.refname_to_id("refs/heads/master").unwrap(), commit);
+    }
+
+    #[test]
+    fn failed_removal_leaves_working_tree_and_index_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), failing_signing());
+        let work = Path::new(repo.path());
+        let before = head(&git_repo);
+
+        let removed = GitPublisher::new(String::new()).remove_file(&repo, Path::new("docs/a.pdf"), &repo.author(), "Remove");
+
+        assert!(removed.is_err());
+        assert_eq!(head(&git_repo), before);
+        assert!(work.join("docs/a.pdf").exists());
+        assert!(metadata::sidecar_path(&work.join("docs/a.pdf")).exists());
+        assert!(is_clean(&git_repo));
+    }
+
+    #[test]
+    fn revert_lists_the_removed_and_restored_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), None);
+        let work = Path::new(repo.path());
+        let publisher = GitPublisher::new(String::new());
+        std::fs::write(work.join("docs/b.pdf"), "%PDF-1.4 b").unwrap();
+        let message = commit_messages::with_chat_trailer("Add", 1);
+        publisher.publish_files(&repo, &[Path::new("docs/b.pdf")], &repo.author(), &message).unwrap();
+        publisher.remove_file(&repo, Path::new("docs/a.pdf"), &repo.author(), &commit_messages::with_chat_trailer("Remove", 1)).unwrap();
+
+        let revert = publisher.revert_last(&repo, 1, &repo.author()).unwrap();
+        assert!(revert.removed.is_empty());
+        let sidecar = metadata::sidecar_path(Path::new("docs/a.pdf")).to_string_lossy().to_string();
+        assert_eq!(revert.restored, vec!["docs/a.pdf".to_string(), sidecar]);
+        assert!(work.join("docs/a.pdf").exists());
+
+        let revert = publisher.revert_last(&repo, 1, &repo.author()).unwrap();
+        assert_eq!(revert.removed, vec!["docs/b.pdf".to_string()]);
+        assert!(revert.restored.is_empty());
+        assert!(!work.join("docs/b.pdf").exists());
+        // The content of the removed file is still available
+        assert_eq!(publisher.read_file(&repo, revert.reverted, Path::new("docs/b.pdf")).unwrap(), b"%PDF-1.4 b");
+        assert!(publisher.read_file(&repo, head(&git_repo), Path::new("docs/b.pdf")).is_err());
+        assert!(is_clean(&git_repo));
+    }
+
+    #[test]
+    fn finds_the_last_commit_of_the_chat() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), None);
+        let work = Path::new(repo.path());
+        let publisher = GitPublisher::new(String::new());
+        let mut commits = Vec::new();
+        for (name, chat) in [("b", 1), ("c", 2), ("d", 1)] {
+            let path = format!("docs/{}.pdf", name);
+            std::fs::write(work.join(&path), "%PDF-1.4").unwrap();
+            let message = commit_messages::with_chat_trailer("Add", chat);
+            commits.push(publisher.publish_files(&repo, &[Path::new(&path)], &repo.author(), &message).unwrap());
+        }
+
+        // Commits of other chats below the latest one do not matter
+        assert_eq!(publisher.find_last_chat_commit(&git_repo, 1).unwrap().id(), commits[2]);
+        let error = publisher.find_last_chat_commit(&git_repo, 2).unwrap_err();
+        assert_eq!(error.message(), format!("Commit {} was not made from this chat, refusing to undo", commits[2]));
+
+        // Once the latest commit is reverted the commit of the other chat is on top
+        let revert = publisher.revert_last(&repo, 1, &repo.author()).unwrap();
+        assert_eq!(revert.reverted, commits[2]);
+        let error = publisher.find_last_chat_commit(&git_repo, 1).unwrap_err();
+        assert_eq!(error.message(), format!("Commit {} was not made from this chat, refusing to undo", commits[1]));
+    }
+
+    #[test]
+    fn moves_file_and_sidecars() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), None);
+        let work = Path::new(repo.path());
+
+        let commit = GitPublisher::new(String::new())
+            .move_file(&repo, Path::new("docs/a.pdf"), Path::new("archive/a.pdf"), &repo.author(), "Move")
+            .unwrap();
+
+        assert_eq!(head(&git_repo), commit);
+        assert!(!work.join("docs/a.pdf").exists());
+        assert!(work.join("archive/a.pdf").exists());
+        assert!(metadata::sidecar_path(&work.join("archive/a.pdf")).exists());
+        assert!(is_clean(&git_repo));
+    }
+
+    #[test]
+    fn move_refuses_existing_sidecar_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), None);
+        let work = Path::new(repo.path());
+        std::fs::create_dir_all(work.join("archive")).unwrap();
+        std::fs::write(metadata::text_path(&work.join("archive/a.pdf")), "stale text").unwrap();
+        let before = head(&git_repo);
+
+        let error = GitPublisher::new(String::new())
+            .move_file(&repo, Path::new("docs/a.pdf"), Path::new("archive/a.pdf"), &repo.author(), "Move")
+            .unwrap_err();
+
+        assert_eq!(error.message(), "archive/a.pdf.ocr.txt already exists");
+        assert_eq!(head(&git_repo), before);
+        assert!(work.join("docs/a.pdf").exists());
+        assert!(metadata::sidecar_path(&work.join("docs/a.pdf")).exists());
+        assert!(!work.join("archive/a.pdf").exists());
+    }
+
+    #[test]
+    fn failed_move_is_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let (repo, git_repo) = repository(dir.path(), failing_signing());
+        let work = Path::new(repo.path());
+        let before = head(&git_repo);
+
+        let moved = GitPublisher::new(String::new())
+            .move_file(&repo, Path::new("docs/a.pdf"), Path::new("archive/a.pdf"), &repo.author(), "Move");
+
+        assert!(moved.is_err());
+        assert_eq!(head(&git_repo), before);
+        assert!(work.join("docs/a.pdf").exists());
+        assert!(metadata::sidecar_path(&work.join("docs/a.pdf")).exists());
+        assert!(!work.join("archive/a.pdf").exists());
+        assert!(!metadata::sidecar_path(&work.join("archive/a.pdf")).exists());
+        assert!(is_clean(&git_repo));
+    }
 }
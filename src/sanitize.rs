@@ -0,0 +1,804 @@
+use std::{collections::HashMap, path::Path};
+use chrono::NaiveDateTime;
+use serde::{Serialize, Deserialize};
+
+use crate::policy;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+
+const TAG_ORIENTATION: u16 = 0x0112;
+const TAG_DATE_TIME: u16 = 0x0132;
+const TAG_EXIF_IFD: u16 = 0x8769;
+const TAG_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const TAG_DATE_TIME_DIGITIZED: u16 = 0x9004;
+
+const EXIF_DATE_FORMAT: &str = "%Y:%m:%d %H:%M:%S";
+
+/**
+ * Removal of the metadata of photos, e.g. `{"keepCaptureDate": true}`
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct SanitizeConfig {
+    /**
+     * Keeps the date the photo was taken, date placeholders of the path resolve to it
+     */
+    #[serde(default)]
+    pub keep_capture_date: bool
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Sanitized {
+    /**
+     * Whether any metadata was removed
+     */
+    pub removed: bool,
+    /**
+     * Date the photo was taken if it is kept
+     */
+    pub captured: Option<NaiveDateTime>
+}
+
+/**
+ * Metadata that survives the sanitizing
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ExifInfo {
+    orientation: Option<u16>,
+    captured: Option<NaiveDateTime>
+}
+
+impl ExifInfo {
+    fn kept(&self, config: &SanitizeConfig) -> ExifInfo {
+        ExifInfo {
+            orientation: self.orientation.filter(|orientation| *orientation != 1),
+            captured: self.captured.filter(|_| config.keep_capture_date),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.orientation.is_none() && self.captured.is_none()
+    }
+}
+
+fn invalid(format: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, format!("invalid {} file", format))
+}
+
+/**
+ * Removes EXIF and XMP metadata like the location and the camera from JPEG, PNG and HEIC
+ * images in place. The orientation is kept, so photos are not displayed rotated.
+ */
+pub fn sanitize(path: &Path, config: &SanitizeConfig) -> std::io::Result<Sanitized> {
+    let file_type = policy::detect_file(path, None)?;
+    let mut data = std::fs::read(path)?;
+    let (info, removed) = match file_type.mime {
+        "image/jpeg" => {
+            let (sanitized, info, removed) = sanitize_jpeg(&data, config)?;
+            data = sanitized;
+            (info, removed)
+        }
+        "image/png" => {
+            let (sanitized, info, removed) = sanitize_png(&data, config)?;
+            data = sanitized;
+            (info, removed)
+        }
+        "image/heic" => sanitize_heic(&mut data, config)?,
+        _ => return Ok(Sanitized::default()),
+    };
+    if removed {
+        std::fs::write(path, &data)?;
+    }
+    Ok(Sanitized { removed, captured: info.kept(config).captured })
+}
+
+/**
+ * Drops the EXIF, XMP, IPTC and vendor segments, the comments and the data after the image.
+ * JFIF, ICC profiles and the Adobe color transform are kept.
+ */
+fn sanitize_jpeg(data: &[u8], config: &SanitizeConfig) -> std::io::Result<(Vec<u8>, ExifInfo, bool)> {
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return Err(invalid("JPEG"));
+    }
+    let mut segments: Vec<&[u8]> = Vec::new();
+    let mut info = ExifInfo::default();
+    let mut removed = false;
+    let mut pos = 2;
+    while pos < data.len() {
+        if data[pos] != 0xFF {
+            return Err(invalid("JPEG"));
+        }
+        let start = pos;
+        // Markers may be preceded by fill bytes
+        while pos < data.len() && data[pos] == 0xFF {
+            pos += 1;
+        }
+        let marker = *data.get(pos).ok_or_else(|| invalid("JPEG"))?;
+        pos += 1;
+        match marker {
+            0xD9 => {
+                segments.push(&data[start..pos]);
+                // Anything after the end of the image, e.g. vendor trailers, is dropped
+                removed |= pos < data.len();
+                break;
+            }
+            0x01 | 0xD0..=0xD7 => {
+                segments.push(&data[start..pos]);
+                continue;
+            }
+            _ => {}
+        }
+        let length = data.get(pos..pos + 2)
+            .map(|length| u16::from_be_bytes([length[0], length[1]]) as usize)
+            .ok_or_else(|| invalid("JPEG"))?;
+        let payload = data.get(pos + 2..pos + length).ok_or_else(|| invalid("JPEG"))?;
+        pos += length;
+        if marker == 0xDA {
+            // The entropy coded data ends at the first marker that is no restart marker
+            while pos < data.len() && !(data[pos] == 0xFF && pos + 1 < data.len() && data[pos + 1] != 0 && !(0xD0..=0xD7).contains(&data[pos + 1])) {
+                pos += 1;
+            }
+        }
+        let keep = match marker {
+            0xE1 => {
+                if let Some(tiff) = payload.strip_prefix(b"Exif\0\0") {
+                    info = read_exif(tiff);
+                }
+                false
+            }
+            0xE0 | 0xEE => true,
+            0xE2 => payload.starts_with(b"ICC_PROFILE\0"),
+            0xE3..=0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            segments.push(&data[start..pos]);
+        } else {
+            removed = true;
+        }
+    }
+    if !removed {
+        return Ok((data.to_vec(), info, false));
+    }
+
+    let mut sanitized = vec![0xFF, 0xD8];
+    let mut segments = segments.into_iter().peekable();
+    // The JFIF segment must stay first
+    if let Some(segment) = segments.next_if(|segment| segment.get(1) == Some(&0xE0)) {
+        sanitized.extend_from_slice(segment);
+    }
+    let kept = info.kept(config);
+    if !kept.is_empty() {
+        let mut payload = b"Exif\0\0".to_vec();
+        payload.extend(write_exif(&kept));
+        sanitized.extend_from_slice(&[0xFF, 0xE1]);
+        sanitized.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        sanitized.extend(payload);
+    }
+    for segment in segments {
+        sanitized.extend_from_slice(segment);
+    }
+    Ok((sanitized, info, true))
+}
+
+/**
+ * Drops the `eXIf` chunk, the text chunks that hold XMP and comments, and the data after the image
+ */
+fn sanitize_png(data: &[u8], config: &SanitizeConfig) -> std::io::Result<(Vec<u8>, ExifInfo, bool)> {
+    if !data.starts_with(&PNG_SIGNATURE) {
+        return Err(invalid("PNG"));
+    }
+    let mut chunks: Vec<(&[u8], &[u8])> = Vec::new();
+    let mut pos = PNG_SIGNATURE.len();
+    while pos < data.len() {
+        let length = data.get(pos..pos + 4)
+            .map(|length| u32::from_be_bytes([length[0], length[1], length[2], length[3]]) as usize)
+            .ok_or_else(|| invalid("PNG"))?;
+        let chunk = data.get(pos..pos + 12 + length).ok_or_else(|| invalid("PNG"))?;
+        chunks.push((&chunk[4..8], chunk));
+        pos += 12 + length;
+        if &chunk[4..8] == b"IEND" {
+            break;
+        }
+    }
+
+    let mut info = ExifInfo::default();
+    let mut removed = pos < data.len();
+    let mut kept_chunks = Vec::new();
+    for (kind, chunk) in chunks {
+        match kind {
+            b"eXIf" => {
+                info = read_exif(&chunk[8..chunk.len() - 4]);
+                removed = true;
+            }
+            b"tEXt" | b"zTXt" | b"iTXt" => removed = true,
+            _ => kept_chunks.push((kind, chunk)),
+        }
+    }
+    if !removed {
+        return Ok((data.to_vec(), info, false));
+    }
+
+    let kept = info.kept(config);
+    let mut sanitized = PNG_SIGNATURE.to_vec();
+    let mut exif_written = kept.is_empty();
+    for (kind, chunk) in kept_chunks {
+        // eXIf must precede the image data
+        if kind == b"IDAT" && !exif_written {
+            sanitized.extend(png_chunk(b"eXIf", &write_exif(&kept)));
+            exif_written = true;
+        }
+        sanitized.extend_from_slice(chunk);
+    }
+    Ok((sanitized, info, true))
+}
+
+fn png_chunk(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut chunk = (content.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(content);
+    let crc = crc32(&chunk[4..]);
+    chunk.extend_from_slice(&crc.to_be_bytes());
+    chunk
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/**
+ * Overwrites the Exif and XMP items of a HEIC in place, so the offsets of all other items stay valid.
+ * The orientation of HEIC images is not stored in EXIF and needs not be kept.
+ */
+fn sanitize_heic(data: &mut [u8], config: &SanitizeConfig) -> std::io::Result<(ExifInfo, bool)> {
+    let (meta_start, meta_end) = find_box(data, 0, data.len(), b"meta").ok_or_else(|| invalid("HEIC"))?;
+    // meta is a full box with version and flags before its children
+    let children = meta_start + 4;
+    let items = match find_box(data, children, meta_end, b"iinf") {
+        Some((start, end)) => parse_iinf(data, start, end).ok_or_else(|| invalid("HEIC"))?,
+        None => return Ok((ExifInfo::default(), false)),
+    };
+    let idat = find_box(data, children, meta_end, b"idat").map(|(start, _)| start);
+    let (iloc_start, iloc_end) = find_box(data, children, meta_end, b"iloc").ok_or_else(|| invalid("HEIC"))?;
+    let locations = parse_iloc(data, iloc_start, iloc_end, idat).ok_or_else(|| invalid("HEIC"))?;
+
+    let mut info = ExifInfo::default();
+    let mut removed = false;
+    for (id, kind) in items {
+        let extents = match locations.get(&id) {
+            Some(extents) => extents,
+            None => continue,
+        };
+        let mut ranges = Vec::new();
+        let mut content = Vec::new();
+        for (offset, length) in extents {
+            let range = offset.checked_add(*length).map(|end| *offset..end).ok_or_else(|| invalid("HEIC"))?;
+            content.extend_from_slice(data.get(range.clone()).ok_or_else(|| invalid("HEIC"))?);
+            ranges.push(range);
+        }
+        let replacement = match kind {
+            ItemKind::Exif => {
+                // The TIFF header follows an offset, usually to skip `Exif\0\0`
+                let header = content.get(0..4)
+                    .and_then(|offset| (u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]) as usize).checked_add(4))
+                    .filter(|header| *header <= content.len())
+                    .ok_or_else(|| invalid("HEIC"))?;
+                info = read_exif(&content[header..]);
+                let kept = ExifInfo { orientation: None, ..info.kept(config) };
+                let mut replacement = content[..header].to_vec();
+                replacement.extend(write_exif(&kept));
+                replacement
+            }
+            ItemKind::Xmp => {
+                let mut replacement = b"<?xpacket begin=\"\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?><x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec();
+                let end = b"<?xpacket end=\"w\"?>";
+                replacement.resize(content.len().saturating_sub(end.len()), b' ');
+                replacement.extend_from_slice(end);
+                replacement
+            }
+        };
+        // Items too small for a valid replacement are cleared
+        let replacement = if replacement.len() <= content.len() { replacement } else { Vec::new() };
+        let mut written = 0;
+        for range in ranges {
+            let length = range.len();
+            for (i, byte) in data[range].iter_mut().enumerate() {
+                *byte = replacement.get(written + i).copied().unwrap_or(0);
+            }
+            written += length;
+        }
+        removed = true;
+    }
+    Ok((info, removed))
+}
+
+enum ItemKind {
+    Exif,
+    Xmp
+}
+
+/**
+ * Start of the content and end of the first box of the type between `start` and `end`
+ */
+fn find_box(data: &[u8], start: usize, end: usize, kind: &[u8; 4]) -> Option<(usize, usize)> {
+    let mut pos = start;
+    while pos + 8 <= end {
+        let size = read_u32(data, pos)? as usize;
+        let (header, size) = match size {
+            0 => (8, end - pos),
+            1 => (16, read_u64(data, pos + 8)? as usize),
+            size => (8, size),
+        };
+        // Sizes are read from the file and may overflow
+        let box_end = pos.checked_add(size)?;
+        if size < header || box_end > end {
+            return None;
+        }
+        if &data[pos + 4..pos + 8] == kind {
+            return Some((pos + header, box_end));
+        }
+        pos = box_end;
+    }
+    None
+}
+
+/**
+ * Ids of the Exif and XMP items of the item info box
+ */
+fn parse_iinf(data: &[u8], start: usize, end: usize) -> Option<Vec<(u32, ItemKind)>> {
+    let version = *data.get(start)?;
+    let mut pos = if version == 0 { start + 6 } else { start + 8 };
+    let mut items = Vec::new();
+    while let Some((entry, entry_end)) = find_box(data, pos, end, b"infe") {
+        let version = *data.get(entry)?;
+        pos = entry_end;
+        if version < 2 {
+            continue;
+        }
+        let (id, mut field) = match version {
+            2 => (read_u16(data, entry + 4)? as u32, entry + 6),
+            _ => (read_u32(data, entry + 4)?, entry + 8),
+        };
+        // Skip the protection index
+        field += 2;
+        let kind = data.get(field..field + 4)?;
+        if kind == b"Exif" {
+            items.push((id, ItemKind::Exif));
+        } else if kind == b"mime" {
+            let strings: Vec<&[u8]> = data.get(field + 4..entry_end)?.split(|byte| *byte == 0).collect();
+            // The item name is followed by the content type
+            if strings.get(1).is_some_and(|content_type| content_type.ends_with(b"rdf+xml")) {
+                items.push((id, ItemKind::Xmp));
+            }
+        }
+    }
+    Some(items)
+}
+
+/**
+ * Extents of the items in the file from the item location box, only items located in the file
+ * itself or in the `idat` box are supported
+ */
+fn parse_iloc(data: &[u8], start: usize, end: usize, idat: Option<usize>) -> Option<HashMap<u32, Vec<(usize, usize)>>> {
+    let version = *data.get(start)?;
+    let sizes = *data.get(start + 4)?;
+    let (offset_size, length_size) = ((sizes >> 4) as usize, (sizes & 0x0F) as usize);
+    let sizes = *data.get(start + 5)?;
+    let (base_offset_size, index_size) = ((sizes >> 4) as usize, if version > 0 { (sizes & 0x0F) as usize } else { 0 });
+    let mut pos = start + 6;
+    let count = if version < 2 { pos += 2; read_u16(data, pos - 2)? as u32 } else { pos += 4; read_u32(data, pos - 4)? };
+
+    let mut locations = HashMap::new();
+    for _ in 0..count {
+        let id = if version < 2 { pos += 2; read_u16(data, pos - 2)? as u32 } else { pos += 4; read_u32(data, pos - 4)? };
+        let construction = if version > 0 { pos += 2; read_u16(data, pos - 2)? & 0x0F } else { 0 };
+        // Skip the data reference index
+        pos += 2;
+        let base = read_sized(data, pos, base_offset_size)?;
+        pos += base_offset_size;
+        let extent_count = read_u16(data, pos)?;
+        pos += 2;
+        let mut extents = Vec::new();
+        for _ in 0..extent_count {
+            pos += index_size;
+            let offset = read_sized(data, pos, offset_size)?;
+            pos += offset_size;
+            let length = read_sized(data, pos, length_size)?;
+            pos += length_size;
+            extents.push((base.checked_add(offset)?, length));
+        }
+        if pos > end {
+            return None;
+        }
+        let origin = match (construction, idat) {
+            (0, _) => 0,
+            (1, Some(idat)) => idat,
+            _ => continue,
+        };
+        let extents = extents.into_iter()
+            .map(|(offset, length)| {
+                let offset = usize::try_from(offset).ok().and_then(|offset| origin.checked_add(offset))?;
+                // A length of zero extends to the end of the file
+                let length = if length == 0 { data.len().saturating_sub(offset) } else { usize::try_from(length).ok()? };
+                Some((offset, length))
+            })
+            .collect::<Option<Vec<(usize, usize)>>>()?;
+        locations.insert(id, extents);
+    }
+    Some(locations)
+}
+
+fn read_u16(data: &[u8], pos: usize) -> Option<u16> {
+    data.get(pos..pos + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], pos: usize) -> Option<u32> {
+    data.get(pos..pos + 4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn read_u64(data: &[u8], pos: usize) -> Option<u64> {
+    data.get(pos..pos + 8).map(|bytes| u64::from_be_bytes(bytes.try_into().unwrap()))
+}
+
+fn read_sized(data: &[u8], pos: usize, size: usize) -> Option<u64> {
+    match size {
+        0 => Some(0),
+        4 => read_u32(data, pos).map(|value| value as u64),
+        8 => read_u64(data, pos),
+        _ => None,
+    }
+}
+
+/**
+ * Reads the orientation and the capture date of the TIFF structure of EXIF data
+ */
+fn read_exif(tiff: &[u8]) -> ExifInfo {
+    let little_endian = match tiff.get(0..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return ExifInfo::default(),
+    };
+    let u16_at = |pos: usize| tiff.get(pos..pos + 2).map(|bytes| {
+        let bytes = [bytes[0], bytes[1]];
+        if little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) }
+    });
+    let u32_at = |pos: usize| tiff.get(pos..pos + 4).map(|bytes| {
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        if little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) }
+    });
+    // Position of the value of the tag in the IFD
+    let find = |ifd: usize, tag: u16| -> Option<usize> {
+        let count = u16_at(ifd)? as usize;
+        (0..count)
+            .map(|i| ifd + 2 + i * 12)
+            .find(|entry| u16_at(*entry) == Some(tag))
+            .map(|entry| entry + 8)
+    };
+    let date = |ifd: usize, tag: u16| -> Option<NaiveDateTime> {
+        let value = find(ifd, tag)?;
+        let offset = u32_at(value)? as usize;
+        let text = tiff.get(offset..offset + 19)?;
+        NaiveDateTime::parse_from_str(std::str::from_utf8(text).ok()?, EXIF_DATE_FORMAT).ok()
+    };
+
+    let ifd0 = match u32_at(4) {
+        Some(ifd0) => ifd0 as usize,
+        None => return ExifInfo::default(),
+    };
+    let exif_ifd = find(ifd0, TAG_EXIF_IFD).and_then(u32_at).map(|exif_ifd| exif_ifd as usize);
+    ExifInfo {
+        orientation: find(ifd0, TAG_ORIENTATION).and_then(u16_at),
+        captured: exif_ifd.and_then(|exif_ifd| date(exif_ifd, TAG_DATE_TIME_ORIGINAL).or_else(|| date(exif_ifd, TAG_DATE_TIME_DIGITIZED)))
+            .or_else(|| date(ifd0, TAG_DATE_TIME)),
+    }
+}
+
+/**
+ * Big endian TIFF structure with only the orientation and the capture date
+ */
+fn write_exif(info: &ExifInfo) -> Vec<u8> {
+    let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: [u8; 4]| {
+        tiff.extend_from_slice(&tag.to_be_bytes());
+        tiff.extend_from_slice(&kind.to_be_bytes());
+        tiff.extend_from_slice(&count.to_be_bytes());
+        tiff.extend_from_slice(&value);
+    };
+    let mut tiff = b"MM\0\x2A".to_vec();
+    tiff.extend_from_slice(&8u32.to_be_bytes());
+
+    let ifd0_entries = info.orientation.is_some() as u16 + info.captured.is_some() as u16;
+    let exif_ifd = 8 + 2 + 12 * ifd0_entries as u32 + 4;
+    tiff.extend_from_slice(&ifd0_entries.to_be_bytes());
+    if let Some(orientation) = info.orientation {
+        let value = orientation.to_be_bytes();
+        entry(&mut tiff, TAG_ORIENTATION, 3, 1, [value[0], value[1], 0, 0]);
+    }
+    if info.captured.is_some() {
+        entry(&mut tiff, TAG_EXIF_IFD, 4, 1, exif_ifd.to_be_bytes());
+    }
+    tiff.extend_from_slice(&0u32.to_be_bytes());
+
+    if let Some(captured) = info.captured {
+        let date = format!("{}\0", captured.format(EXIF_DATE_FORMAT));
+        tiff.extend_from_slice(&1u16.to_be_bytes());
+        entry(&mut tiff, TAG_DATE_TIME_ORIGINAL, 2, date.len() as u32, (exif_ifd + 2 + 12 + 4).to_be_bytes());
+        tiff.extend_from_slice(&0u32.to_be_bytes());
+        tiff.extend_from_slice(date.as_bytes());
+    }
+    tiff
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GPS_SECRET: &[u8] = b"SECRET-LOCATION\0";
+
+    fn captured() -> NaiveDateTime {
+        NaiveDateTime::parse_from_str("2023:06:01 12:30:00", EXIF_DATE_FORMAT).unwrap()
+    }
+
+    fn keep_date() -> SanitizeConfig {
+        SanitizeConfig { keep_capture_date: true }
+    }
+
+    /**
+     * Little endian EXIF with the orientation, the capture date and a GPS IFD with a map datum
+     */
+    fn gps_exif(orientation: u16) -> Vec<u8> {
+        let entry = |tiff: &mut Vec<u8>, tag: u16, kind: u16, count: u32, value: u32| {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&kind.to_le_bytes());
+            tiff.extend_from_slice(&count.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        };
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8u32.to_le_bytes());
+        // IFD0 from 8 to 50, the Exif IFD from 50 to 68, the GPS IFD from 68 to 86
+        tiff.extend_from_slice(&3u16.to_le_bytes());
+        entry(&mut tiff, TAG_ORIENTATION, 3, 1, orientation as u32);
+        entry(&mut tiff, TAG_EXIF_IFD, 4, 1, 50);
+        entry(&mut tiff, 0x8825, 4, 1, 68);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        entry(&mut tiff, TAG_DATE_TIME_ORIGINAL, 2, 20, 86);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(&1u16.to_le_bytes());
+        entry(&mut tiff, 0x0012, 2, GPS_SECRET.len() as u32, 106);
+        tiff.extend_from_slice(&0u32.to_le_bytes());
+        tiff.extend_from_slice(b"2023:06:01 12:30:00\0");
+        tiff.extend_from_slice(GPS_SECRET);
+        tiff
+    }
+
+    fn contains(data: &[u8], needle: &[u8]) -> bool {
+        data.windows(needle.len()).any(|window| window == needle)
+    }
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let mut segment = vec![0xFF, marker];
+        segment.extend_from_slice(&((payload.len() + 2) as u16).to_be_bytes());
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    /**
+     * JPEG with a JFIF header, EXIF with GPS, a comment, a frame, scan data and a trailer
+     */
+    fn jpeg(orientation: u16) -> Vec<u8> {
+        [
+            vec![0xFF, 0xD8],
+            segment(0xE0, b"JFIF\0\x01\x01\0\0\x01\0\x01\0\0"),
+            segment(0xE1, &[b"Exif\0\0".as_slice(), &gps_exif(orientation)].concat()),
+            segment(0xFE, b"taken at home"),
+            segment(0xC0, &[0x08, 0x00, 0x10, 0x00, 0x10, 0x01, 0x01, 0x11, 0x00]),
+            segment(0xDA, &[0x01, 0x01, 0x00, 0x00, 0x3F, 0x00]),
+            vec![0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56],
+            vec![0xFF, 0xD9],
+            b"TRAILER".to_vec(),
+        ].concat()
+    }
+
+    /**
+     * Markers of the segments up to the scan
+     */
+    fn markers(data: &[u8]) -> Vec<u8> {
+        let mut markers = Vec::new();
+        let mut pos = 2;
+        while data[pos + 1] != 0xDA {
+            markers.push(data[pos + 1]);
+            pos += 2 + u16::from_be_bytes([data[pos + 2], data[pos + 3]]) as usize;
+        }
+        markers
+    }
+
+    #[test]
+    fn removes_gps_exif_from_jpeg() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, jpeg(1)).unwrap();
+
+        let sanitized = sanitize(&path, &SanitizeConfig::default()).unwrap();
+        assert_eq!(sanitized, Sanitized { removed: true, captured: None });
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(markers(&data), vec![0xE0, 0xC0]);
+        assert!(!contains(&data, GPS_SECRET));
+        assert!(!contains(&data, b"taken at home"));
+        assert!(data.ends_with(&[0x12, 0xFF, 0x00, 0x34, 0xFF, 0xD0, 0x56, 0xFF, 0xD9]));
+
+        // Nothing is left to remove
+        assert_eq!(sanitize(&path, &SanitizeConfig::default()).unwrap(), Sanitized::default());
+        assert_eq!(std::fs::read(&path).unwrap(), data);
+    }
+
+    #[test]
+    fn keeps_orientation_and_capture_date_of_jpeg() {
+        let (data, info, removed) = sanitize_jpeg(&jpeg(6), &keep_date()).unwrap();
+        assert!(removed);
+        assert_eq!(info, ExifInfo { orientation: Some(6), captured: Some(captured()) });
+        assert_eq!(markers(&data), vec![0xE0, 0xE1, 0xC0]);
+        assert!(!contains(&data, GPS_SECRET));
+
+        let exif = &data[data.iter().position(|byte| *byte == 0xE1).unwrap() + 3..];
+        let tiff = exif.strip_prefix(b"Exif\0\0").unwrap();
+        assert_eq!(read_exif(tiff), info);
+
+        // Without the date only the orientation is kept
+        let (data, _, _) = sanitize_jpeg(&jpeg(6), &SanitizeConfig::default()).unwrap();
+        let exif = &data[data.iter().position(|byte| *byte == 0xE1).unwrap() + 3..];
+        assert_eq!(read_exif(exif.strip_prefix(b"Exif\0\0").unwrap()), ExifInfo { orientation: Some(6), captured: None });
+        assert!(sanitize_jpeg(b"\xFF\xD8\x00", &SanitizeConfig::default()).is_err());
+    }
+
+    fn png_kinds(data: &[u8]) -> Vec<String> {
+        let mut kinds = Vec::new();
+        let mut pos = PNG_SIGNATURE.len();
+        while pos < data.len() {
+            let length = u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]]) as usize;
+            kinds.push(String::from_utf8_lossy(&data[pos + 4..pos + 8]).to_string());
+            pos += 12 + length;
+        }
+        kinds
+    }
+
+    #[test]
+    fn removes_exif_and_text_chunks_from_png() {
+        let png = [
+            PNG_SIGNATURE.to_vec(),
+            png_chunk(b"IHDR", &[0, 0, 0, 1, 0, 0, 0, 1, 8, 0, 0, 0, 0]),
+            png_chunk(b"tEXt", b"Comment\0taken at home"),
+            png_chunk(b"eXIf", &gps_exif(1)),
+            png_chunk(b"IDAT", b"pixels"),
+            png_chunk(b"IEND", b""),
+            b"TRAILER".to_vec(),
+        ].concat();
+
+        let (data, info, removed) = sanitize_png(&png, &SanitizeConfig::default()).unwrap();
+        assert!(removed);
+        assert_eq!(info, ExifInfo { orientation: Some(1), captured: Some(captured()) });
+        assert_eq!(png_kinds(&data), vec!["IHDR", "IDAT", "IEND"]);
+        assert!(!contains(&data, GPS_SECRET));
+
+        let (data, _, _) = sanitize_png(&png, &keep_date()).unwrap();
+        assert_eq!(png_kinds(&data), vec!["IHDR", "eXIf", "IDAT", "IEND"]);
+        assert!(!contains(&data, GPS_SECRET));
+        assert!(!contains(&data, b"TRAILER"));
+        let exif = png_chunk(b"eXIf", &write_exif(&ExifInfo { orientation: None, captured: Some(captured()) }));
+        assert!(contains(&data, &exif));
+
+        // The kept date is removed when sanitized again without it
+        let (data, _, removed) = sanitize_png(&data, &SanitizeConfig::default()).unwrap();
+        assert!(removed);
+        assert_eq!(png_kinds(&data), vec!["IHDR", "IDAT", "IEND"]);
+    }
+
+    fn boxed(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+        let mut boxed = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        boxed.extend_from_slice(kind);
+        boxed.extend_from_slice(content);
+        boxed
+    }
+
+    fn full_box(kind: &[u8; 4], version: u8, content: &[u8]) -> Vec<u8> {
+        let mut full = vec![version, 0, 0, 0];
+        full.extend_from_slice(content);
+        boxed(kind, &full)
+    }
+
+    /**
+     * HEIC with an image, an Exif and an XMP item in `mdat`. Returns the file and the position
+     * of the 64 bit base offset of the Exif item in `iloc`.
+     */
+    fn heic(exif: &[u8], xmp: &[u8]) -> (Vec<u8>, usize) {
+        let mut exif_item = vec![0, 0, 0, 6];
+        exif_item.extend_from_slice(b"Exif\0\0");
+        exif_item.extend_from_slice(exif);
+        let items: [&[u8]; 3] = [b"IMAGE-DATA", &exif_item, xmp];
+
+        let infe = |id: u16, kind: &[u8; 4], names: &[u8]| {
+            let mut content = id.to_be_bytes().to_vec();
+            content.extend_from_slice(&[0, 0]);
+            content.extend_from_slice(kind);
+            content.extend_from_slice(names);
+            full_box(b"infe", 2, &content)
+        };
+        let mut iinf = 3u16.to_be_bytes().to_vec();
+        iinf.extend(infe(1, b"hvc1", b"\0"));
+        iinf.extend(infe(2, b"Exif", b"\0"));
+        iinf.extend(infe(3, b"mime", b"XMP\0application/rdf+xml\0"));
+        let iinf = full_box(b"iinf", 0, &iinf);
+        let iloc = |start: usize| {
+            // 32 bit offsets and lengths, 64 bit base offsets
+            let mut content = vec![0x44, 0x80];
+            content.extend_from_slice(&3u16.to_be_bytes());
+            let mut offset = start;
+            for (id, item) in items.iter().enumerate() {
+                content.extend_from_slice(&(id as u16 + 1).to_be_bytes());
+                content.extend_from_slice(&[0, 0]);
+                content.extend_from_slice(&0u64.to_be_bytes());
+                content.extend_from_slice(&1u16.to_be_bytes());
+                content.extend_from_slice(&(offset as u32).to_be_bytes());
+                content.extend_from_slice(&(item.len() as u32).to_be_bytes());
+                offset += item.len();
+            }
+            full_box(b"iloc", 0, &content)
+        };
+        let meta = |start: usize| full_box(b"meta", 0, &[iinf.clone(), iloc(start)].concat());
+
+        let ftyp = boxed(b"ftyp", b"heic\0\0\0\0mif1heic");
+        let start = ftyp.len() + meta(0).len() + 8;
+        let exif_base = ftyp.len() + 12 + iinf.len() + 12 + 4 + 22 + 4;
+        ([ftyp, meta(start), boxed(b"mdat", &items.concat())].concat(), exif_base)
+    }
+
+    #[test]
+    fn overwrites_exif_and_xmp_items_of_heic() {
+        let mut xmp = b"<x:xmpmeta><rdf:Description exif:GPSMapDatum=\"SECRET-LOCATION\"/></x:xmpmeta>".to_vec();
+        xmp.resize(200, b' ');
+        let (original, _) = heic(&gps_exif(6), &xmp);
+        let mut data = original.clone();
+
+        let (info, removed) = sanitize_heic(&mut data, &keep_date()).unwrap();
+        assert!(removed);
+        assert_eq!(info, ExifInfo { orientation: Some(6), captured: Some(captured()) });
+        // Items are overwritten in place, so all offsets stay valid
+        assert_eq!(data.len(), original.len());
+        assert!(contains(&data, b"IMAGE-DATA"));
+        assert!(!contains(&data, b"SECRET-LOCATION"));
+        assert!(contains(&data, b"<?xpacket end=\"w\"?>"));
+
+        let exif = &data[data.windows(6).rposition(|window| window == b"Exif\0\0").unwrap() + 6..];
+        assert_eq!(read_exif(exif), ExifInfo { orientation: None, captured: Some(captured()) });
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.heic");
+        std::fs::write(&path, &original).unwrap();
+        assert_eq!(sanitize(&path, &SanitizeConfig::default()).unwrap(), Sanitized { removed: true, captured: None });
+        assert!(!contains(&std::fs::read(&path).unwrap(), b"SECRET-LOCATION"));
+    }
+
+    #[test]
+    fn rejects_heic_offsets_that_overflow() {
+        let (valid, exif_base) = heic(&gps_exif(1), &[b' '; 200]);
+        assert!(sanitize_heic(&mut valid.clone(), &SanitizeConfig::default()).is_ok());
+
+        let mut data = valid.clone();
+        data[exif_base..exif_base + 8].copy_from_slice(&u64::MAX.to_be_bytes());
+        let error = sanitize_heic(&mut data, &SanitizeConfig::default()).unwrap_err();
+        assert_eq!(error.to_string(), "invalid HEIC file");
+
+        // A box with a 64 bit size that reaches beyond the address space
+        let mut large = 1u32.to_be_bytes().to_vec();
+        large.extend_from_slice(b"free");
+        large.extend_from_slice(&u64::MAX.to_be_bytes());
+        let mut data = [&valid[..24], &large, &valid[24..]].concat();
+        assert_eq!(sanitize_heic(&mut data, &SanitizeConfig::default()).unwrap_err().to_string(), "invalid HEIC file");
+    }
+}
@@ -0,0 +1,183 @@
+use std::{io::{Read, Write}, os::unix::net::UnixStream, path::{Path, PathBuf}, time::Duration};
+
+/**
+ * Size of the chunks streamed to clamd, must be below its `StreamMaxLength`
+ */
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScanResult {
+    Clean,
+    /**
+     * Name of the signature that matched
+     */
+    Infected(String)
+}
+
+/**
+ * Scans files with a ClamAV daemon using the INSTREAM command of the clamd protocol.
+ * Any program listening on the socket and speaking the protocol can stand in for clamd.
+ */
+pub struct ClamdScanner {
+    socket: PathBuf,
+    timeout: Duration
+}
+
+impl ClamdScanner {
+    pub fn new(socket: &Path) -> ClamdScanner {
+        ClamdScanner { socket: socket.to_path_buf(), timeout: Duration::from_secs(60) }
+    }
+
+    /**
+     * Scanner for the socket in `CLAMD_SOCKET`, None if scanning is not configured
+     */
+    pub fn from_env() -> Option<ClamdScanner> {
+        std::env::var("CLAMD_SOCKET").ok()
+            .filter(|socket| !socket.is_empty())
+            .map(|socket| ClamdScanner::new(Path::new(&socket)))
+    }
+
+    pub fn scan(&self, file: &Path) -> std::io::Result<ScanResult> {
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.write_all(b"zINSTREAM\0")?;
+
+        let mut input = std::fs::File::open(file)?;
+        let mut buffer = vec![0u8; CHUNK_SIZE];
+        loop {
+            let read = input.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&(read as u32).to_be_bytes())?;
+            stream.write_all(&buffer[..read])?;
+        }
+        stream.write_all(&0u32.to_be_bytes())?;
+        stream.flush()?;
+
+        // Replies of z-prefixed commands end with a NUL byte
+        let mut reply = Vec::new();
+        for byte in std::io::BufReader::new(stream).bytes() {
+            match byte? {
+                0 => break,
+                byte => reply.push(byte),
+            }
+        }
+        parse_reply(String::from_utf8_lossy(&reply).trim_end())
+    }
+}
+
+/**
+ * Parses replies like `stream: OK` or `stream: Eicar-Test-Signature FOUND`
+ */
+fn parse_reply(reply: &str) -> std::io::Result<ScanResult> {
+    let result = reply.strip_prefix("stream:").map(|result| result.trim()).unwrap_or(reply);
+    if result == "OK" {
+        Ok(ScanResult::Clean)
+    } else if let Some(signature) = result.strip_suffix("FOUND") {
+        Ok(ScanResult::Infected(signature.trim().to_string()))
+    } else {
+        Err(std::io::Error::other(format!("clamd replied: {}", reply)))
+    }
+}
+
+/**
+ * Moves an infected file to the quarantine directory in `QUARANTINE_DIR` and returns its new path
+ */
+pub fn quarantine(file: &Path, name: &str) -> std::io::Result<PathBuf> {
+    let dir = PathBuf::from(std::env::var("QUARANTINE_DIR").unwrap_or("quarantine".to_string()));
+    std::fs::create_dir_all(&dir)?;
+    let target = dir.join(format!("{}-{}", chrono::Local::now().format("%Y%m%d%H%M%S"), name));
+    // The staging directory may be on another file system
+    if std::fs::rename(file, &target).is_err() {
+        std::fs::copy(file, &target)?;
+        std::fs::remove_file(file)?;
+    }
+    Ok(target)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::unix::net::UnixListener, thread::JoinHandle};
+
+    /**
+     * Stand-in for clamd that answers one INSTREAM command with the reply and returns the streamed content
+     */
+    fn fake_clamd(socket: &Path, reply: &'static str) -> JoinHandle<Vec<u8>> {
+        let listener = UnixListener::bind(socket).unwrap();
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut command = [0u8; 10];
+            stream.read_exact(&mut command).unwrap();
+            assert_eq!(&command, b"zINSTREAM\0");
+            let mut content = Vec::new();
+            loop {
+                let mut length = [0u8; 4];
+                stream.read_exact(&mut length).unwrap();
+                let length = u32::from_be_bytes(length) as usize;
+                if length == 0 {
+                    break;
+                }
+                assert!(length <= CHUNK_SIZE);
+                let mut chunk = vec![0u8; length];
+                stream.read_exact(&mut chunk).unwrap();
+                content.extend(chunk);
+            }
+            stream.write_all(reply.as_bytes()).unwrap();
+            stream.write_all(b"\0").unwrap();
+            content
+        })
+    }
+
+    #[test]
+    fn streams_the_file_in_chunks() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("clamd.sock");
+        let clamd = fake_clamd(&socket, "stream: OK");
+        let file = dir.path().join("scan.pdf");
+        let content: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        std::fs::write(&file, &content).unwrap();
+
+        assert_eq!(ClamdScanner::new(&socket).scan(&file).unwrap(), ScanResult::Clean);
+        assert_eq!(clamd.join().unwrap(), content);
+    }
+
+    #[test]
+    fn reports_the_signature_of_infected_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("clamd.sock");
+        let clamd = fake_clamd(&socket, "stream: Eicar FOUND");
+        let file = dir.path().join("eicar.txt");
+        std::fs::write(&file, "X5O!P%@AP").unwrap();
+
+        assert_eq!(ClamdScanner::new(&socket).scan(&file).unwrap(), ScanResult::Infected("Eicar".to_string()));
+        assert_eq!(clamd.join().unwrap(), b"X5O!P%@AP");
+    }
+
+    #[test]
+    fn fails_on_errors_and_missing_daemon() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("clamd.sock");
+        let clamd = fake_clamd(&socket, "INSTREAM size limit exceeded. ERROR");
+        let file = dir.path().join("scan.pdf");
+        std::fs::write(&file, "%PDF-1.4").unwrap();
+
+        let error = ClamdScanner::new(&socket).scan(&file).unwrap_err();
+        assert_eq!(error.to_string(), "clamd replied: INSTREAM size limit exceeded. ERROR");
+        clamd.join().unwrap();
+        assert!(ClamdScanner::new(&dir.path().join("missing.sock")).scan(&file).is_err());
+    }
+
+    #[test]
+    fn parses_replies() {
+        assert_eq!(parse_reply("stream: OK").unwrap(), ScanResult::Clean);
+        assert_eq!(parse_reply("OK").unwrap(), ScanResult::Clean);
+        assert_eq!(
+            parse_reply("stream: Win.Test.EICAR_HDB-1 FOUND").unwrap(),
+            ScanResult::Infected("Win.Test.EICAR_HDB-1".to_string())
+        );
+        assert!(parse_reply("").is_err());
+    }
+}